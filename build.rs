@@ -0,0 +1,27 @@
+use std::{
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Let's bake some information about the build into the worker so we can tell which build is deployed.
+// None of it is required, so builds outside of a git checkout still work, they just can't say which
+// commit they came from.
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok());
+    if let Some(commit) = commit {
+        println!("cargo:rustc-env=GIT_COMMIT_HASH={}", commit.trim());
+    }
+
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        println!("cargo:rustc-env=BUILD_TIMESTAMP={}", now.as_secs());
+    }
+
+    // Only rebuild when the checked out commit changes, rather than whenever any file does.
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use worker::*;
+
+use crate::{counter_value, next_counter_value, read_counter, write_counter, Store};
+
+// How long a counter waits after being incremented before copying its value to KV, so a burst of
+// increments only costs a single write.
+const CHECKPOINT_DELAY: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IncrementRequest {
+    // The key the counter is read from with `GET /:key`, which its value is copied to.
+    key: String,
+    by: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IncrementResponse {
+    value: i64,
+}
+
+/// Holds the authoritative value of a single counter. Every increment of the counter goes through
+/// the same object, one at a time, so none of them can be lost like they can when racing each other
+/// in KV. The value is copied to KV shortly after it changes, so reads that go straight to KV can
+/// be a few seconds behind.
+#[durable_object]
+pub struct CounterObject {
+    state: State,
+    env: Env,
+}
+
+#[durable_object]
+impl DurableObject for CounterObject {
+    fn new(state: State, env: Env) -> Self {
+        Self { state, env }
+    }
+
+    async fn fetch(&mut self, mut req: Request) -> Result<Response> {
+        let IncrementRequest { key, by } = req.json().await?;
+        let mut storage = self.state.storage();
+
+        // The first increment carries on from whatever KV already holds, such as increments made
+        // before the object was bound.
+        let value = match storage.get::<i64>("value").await {
+            Ok(value) => value,
+            Err(_) => {
+                let store = Store::from_env(&self.env)?;
+                let (stored, _) = read_counter(&self.env, &store, &key).await?;
+                counter_value(stored.as_deref())?
+            }
+        };
+        let value = next_counter_value(value, by)?;
+        storage.put("value", value).await?;
+        storage.put("key", &key).await?;
+
+        if storage.get_alarm().await?.is_none() {
+            storage.set_alarm(CHECKPOINT_DELAY).await?;
+        }
+
+        Response::from_json(&IncrementResponse { value })
+    }
+
+    async fn alarm(&mut self) -> Result<Response> {
+        let storage = self.state.storage();
+        let key: String = storage.get("key").await?;
+        let value: i64 = storage.get("value").await?;
+
+        // The counter keeps the metadata and expiration its key has in KV, whatever that holds now.
+        let store = Store::from_env(&self.env)?;
+        let (_, metadata) = read_counter(&self.env, &store, &key).await?;
+        write_counter(&self.env, &store, &key, value, metadata).await?;
+
+        Response::ok("checkpointed")
+    }
+}
+
+/// Increments the counter for `key` through the object named `name`, returning its new value.
+pub async fn increment(namespace: &ObjectNamespace, name: &str, key: &str, by: i64) -> Result<i64> {
+    let stub = namespace.id_from_name(name)?.get_stub()?;
+    let body = serde_json::to_string(&IncrementRequest {
+        key: key.into(),
+        by,
+    })?;
+
+    // The URL only matters to the object, which doesn't look at it.
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post).with_body(Some(body.into()));
+    let req = Request::new_with_init("https://counter/increment", &init)?;
+
+    let IncrementResponse { value } = stub.fetch_with_request(req).await?.json().await?;
+    Ok(value)
+}
@@ -0,0 +1,312 @@
+use std::fmt;
+
+use worker::{kv::KvError, Error, Response, Result};
+
+use crate::retry;
+
+// How many seconds clients are told to wait before trying again when the store is unavailable.
+const UNAVAILABLE_RETRY_AFTER: u64 = 5;
+
+/// The ways a request can fail. Each one is sent with its own status and a stable code clients can
+/// match on, rather than having to parse the message, which is free to change.
+///
+/// Handlers have to return a [worker::Error], so an `ApiError` is turned into one with `?` or
+/// `.into()` and turned back with [ApiError::from_status] once it reaches `main`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    /// The request body couldn't be parsed, with the reason if we know it.
+    InvalidBody(Option<String>),
+    /// A parameter or header of the request doesn't make sense.
+    InvalidRequest(String),
+    Unauthorized,
+    Forbidden(String),
+    KeyNotFound,
+    /// Something other than a key, such as a lock or a previous version, doesn't exist.
+    NotFound(String),
+    NotAcceptable(String),
+    Conflict(String),
+    PreconditionFailed,
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    RangeNotSatisfiable,
+    /// An `Idempotency-Key` was sent again with a different request.
+    IdempotencyKeyReused,
+    Internal(String),
+    /// The KV store failed in a way that won't go away by trying again.
+    Store(String),
+    NotImplemented(String),
+    /// What the store handed back was changed behind the worker's back.
+    Integrity(String),
+    /// The KV store is rate limiting us or having trouble of its own, which should pass.
+    Unavailable(String),
+    /// The store took too long to answer.
+    Timeout(String),
+    /// Any other status, for errors that were never an `ApiError` to begin with.
+    Other(u16, String),
+}
+
+impl ApiError {
+    /// The status the error is sent with.
+    pub fn status(&self) -> u16 {
+        match self {
+            ApiError::InvalidBody(_) | ApiError::InvalidRequest(_) => 400,
+            ApiError::Unauthorized => 401,
+            ApiError::Forbidden(_) => 403,
+            ApiError::KeyNotFound | ApiError::NotFound(_) => 404,
+            ApiError::NotAcceptable(_) => 406,
+            ApiError::Conflict(_) => 409,
+            ApiError::PreconditionFailed => 412,
+            ApiError::PayloadTooLarge(_) => 413,
+            ApiError::UnsupportedMediaType(_) => 415,
+            ApiError::RangeNotSatisfiable => 416,
+            ApiError::IdempotencyKeyReused => 422,
+            ApiError::Internal(_) | ApiError::Store(_) => 500,
+            ApiError::NotImplemented(_) => 501,
+            ApiError::Integrity(_) => 502,
+            ApiError::Unavailable(_) => 503,
+            ApiError::Timeout(_) => 504,
+            ApiError::Other(status, _) => *status,
+        }
+    }
+
+    /// The code clients can tell errors apart by.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::KeyNotFound => "key_not_found",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::NotAcceptable(_) => "not_acceptable",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::PreconditionFailed => "precondition_failed",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::RangeNotSatisfiable => "range_not_satisfiable",
+            ApiError::IdempotencyKeyReused => "idempotency_key_reused",
+            ApiError::Internal(_) => "internal_error",
+            ApiError::Store(_) => "store_error",
+            ApiError::NotImplemented(_) => "not_implemented",
+            ApiError::Integrity(_) => "integrity_error",
+            ApiError::Unavailable(_) => "store_unavailable",
+            ApiError::Timeout(_) => "timeout",
+            ApiError::Other(..) => "error",
+        }
+    }
+
+    /// Rebuilds an error from the status and message it was sent with, which is all a
+    /// [worker::Error] or a plain error response has room for.
+    pub fn from_status(status: u16, message: &str) -> Self {
+        let error = match status {
+            400 => match message.strip_prefix("invalid body") {
+                Some("") => ApiError::InvalidBody(None),
+                Some(reason) => match reason.strip_prefix(": ") {
+                    Some(reason) => ApiError::InvalidBody(Some(reason.into())),
+                    None => ApiError::InvalidRequest(message.into()),
+                },
+                None => ApiError::InvalidRequest(message.into()),
+            },
+            401 => ApiError::Unauthorized,
+            403 => ApiError::Forbidden(message.into()),
+            404 if message == ApiError::KeyNotFound.to_string() => ApiError::KeyNotFound,
+            404 => ApiError::NotFound(message.into()),
+            406 => ApiError::NotAcceptable(message.into()),
+            409 => ApiError::Conflict(message.into()),
+            412 => ApiError::PreconditionFailed,
+            413 => ApiError::PayloadTooLarge(message.into()),
+            415 => ApiError::UnsupportedMediaType(message.into()),
+            416 => ApiError::RangeNotSatisfiable,
+            422 => ApiError::IdempotencyKeyReused,
+            500 => match message.strip_prefix("store error: ") {
+                Some(reason) => ApiError::Store(reason.into()),
+                None => ApiError::Internal(message.into()),
+            },
+            501 => ApiError::NotImplemented(message.into()),
+            502 => ApiError::Integrity(message.into()),
+            503 => match message.strip_prefix("store temporarily unavailable: ") {
+                Some(reason) => ApiError::Unavailable(reason.into()),
+                None => ApiError::Other(status, message.into()),
+            },
+            504 => ApiError::Timeout(message.into()),
+            _ => ApiError::Other(status, message.into()),
+        };
+
+        // Errors with a fixed message only match when the message is theirs.
+        if error.to_string() == message {
+            error
+        } else {
+            ApiError::Other(status, message.into())
+        }
+    }
+
+    /// How many seconds the client should wait before trying again, for errors that should pass.
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            ApiError::Unavailable(_) => Some(UNAVAILABLE_RETRY_AFTER),
+            _ => None,
+        }
+    }
+
+    /// Builds the plain text response for the error, which `main` renders in the format the client
+    /// prefers.
+    pub fn response(&self) -> Result<Response> {
+        Response::error(self.to_string(), self.status())
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidBody(None) => write!(f, "invalid body"),
+            ApiError::InvalidBody(Some(reason)) => write!(f, "invalid body: {}", reason),
+            ApiError::Unauthorized => write!(f, "unauthorized"),
+            ApiError::KeyNotFound => write!(f, "key not found"),
+            ApiError::PreconditionFailed => write!(f, "precondition failed"),
+            ApiError::RangeNotSatisfiable => write!(f, "range not satisfiable"),
+            ApiError::IdempotencyKeyReused => write!(
+                f,
+                "idempotency key was already used for a different request"
+            ),
+            ApiError::Store(reason) => write!(f, "store error: {}", reason),
+            ApiError::Unavailable(reason) => {
+                write!(f, "store temporarily unavailable: {}", reason)
+            }
+            ApiError::InvalidRequest(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::NotAcceptable(message)
+            | ApiError::Conflict(message)
+            | ApiError::PayloadTooLarge(message)
+            | ApiError::UnsupportedMediaType(message)
+            | ApiError::Internal(message)
+            | ApiError::NotImplemented(message)
+            | ApiError::Integrity(message)
+            | ApiError::Timeout(message)
+            | ApiError::Other(_, message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl From<KvError> for ApiError {
+    fn from(err: KvError) -> Self {
+        let retryable = retry::is_retryable(&err);
+        ApiError::from_store(retryable, Error::from(err).to_string())
+    }
+}
+
+impl ApiError {
+    /// Picks the error a failed store operation is sent as. Errors that might go away by trying again
+    /// are the store's own trouble, which is still there once we've run out of retries.
+    fn from_store(retryable: bool, message: String) -> Self {
+        if retryable {
+            ApiError::Unavailable(message)
+        } else {
+            ApiError::Store(message)
+        }
+    }
+}
+
+impl From<ApiError> for Error {
+    fn from(err: ApiError) -> Self {
+        Error::Json((err.to_string(), err.status()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every_error() -> Vec<ApiError> {
+        vec![
+            ApiError::InvalidBody(None),
+            ApiError::InvalidBody(Some("expected an object".into())),
+            ApiError::InvalidRequest("invalid ttl".into()),
+            ApiError::Unauthorized,
+            ApiError::Forbidden("key is reserved for internal use".into()),
+            ApiError::KeyNotFound,
+            ApiError::NotFound("no deleted key found".into()),
+            ApiError::NotAcceptable("can't send that".into()),
+            ApiError::Conflict("not a counter".into()),
+            ApiError::PreconditionFailed,
+            ApiError::PayloadTooLarge("body is too large".into()),
+            ApiError::UnsupportedMediaType("expected JSON".into()),
+            ApiError::RangeNotSatisfiable,
+            ApiError::IdempotencyKeyReused,
+            ApiError::Internal("checksum mismatch".into()),
+            ApiError::Store("no such namespace".into()),
+            ApiError::NotImplemented("not configured".into()),
+            ApiError::Integrity("metadata signature mismatch".into()),
+            ApiError::Unavailable("too many requests".into()),
+            ApiError::Timeout("the store took too long".into()),
+            ApiError::Other(418, "I'm a teapot".into()),
+        ]
+    }
+
+    #[test]
+    fn errors_round_trip_through_their_status_and_message() {
+        for error in every_error() {
+            let rebuilt = ApiError::from_status(error.status(), &error.to_string());
+            assert_eq!(rebuilt, error);
+            assert_eq!(rebuilt.code(), error.code());
+        }
+    }
+
+    #[test]
+    fn errors_round_trip_through_a_worker_error() {
+        for error in every_error() {
+            let (message, status) = match Error::from(error.clone()) {
+                Error::Json(json) => json,
+                err => panic!("unexpected error {:?}", err),
+            };
+            assert_eq!(ApiError::from_status(status, &message), error);
+        }
+    }
+
+    #[test]
+    fn errors_with_a_fixed_message_only_match_their_own() {
+        assert_eq!(
+            ApiError::from_status(404, "no such lock"),
+            ApiError::NotFound("no such lock".into())
+        );
+        assert_eq!(
+            ApiError::from_status(401, "go away"),
+            ApiError::Other(401, "go away".into())
+        );
+        assert_eq!(
+            ApiError::from_status(503, "down for maintenance"),
+            ApiError::Other(503, "down for maintenance".into())
+        );
+    }
+
+    #[test]
+    fn only_unavailable_stores_are_worth_retrying() {
+        assert_eq!(
+            ApiError::Unavailable("too many requests".into()).retry_after(),
+            Some(UNAVAILABLE_RETRY_AFTER)
+        );
+        for error in every_error()
+            .into_iter()
+            .filter(|error| !matches!(error, ApiError::Unavailable(_)))
+        {
+            assert_eq!(error.retry_after(), None, "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn store_errors_that_might_pass_are_unavailable() {
+        let error = ApiError::from_store(true, "too many requests".into());
+        assert_eq!(error.status(), 503);
+        assert_eq!(error.retry_after(), Some(UNAVAILABLE_RETRY_AFTER));
+    }
+
+    #[test]
+    fn store_errors_that_wont_pass_are_internal() {
+        let error = ApiError::from_store(false, "no such namespace".into());
+        assert_eq!(error.status(), 500);
+        assert_eq!(error.retry_after(), None);
+
+        // These never reach the runtime, so trying again won't help.
+        assert!(!retry::is_retryable(&KvError::InvalidKvStore("KV".into())));
+    }
+}
@@ -1,11 +1,291 @@
+// The OpenAPI document in `spec` is one big `json!` invocation, which needs more room to expand.
+#![recursion_limit = "256"]
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    future::Future,
+    str::FromStr,
+    time::Duration,
+};
+
+use error::ApiError;
+use futures::{future::join_all, pin_mut, select, stream, FutureExt, Stream};
+use jsonpath_rust::{path::config::JsonPathConfig, JsonPathInst};
+use retry::Retries;
 use serde::{Deserialize, Serialize};
-use worker::{*, kv::KvError};
+use worker::{
+    kv::KvError,
+    wasm_bindgen::{JsCast, JsValue},
+    wasm_bindgen_futures::JsFuture,
+    *,
+};
 
+mod counter;
+mod error;
+mod retry;
+mod spec;
 mod utils;
 
 // This is the name of the KV store binding that we specified in our wrangler.toml file.
 const KV_BINDING_NAME: &str = "KV_STORE";
 
+// Counters incremented with `?consistent=1` go through the Durable Object bound under this name, when
+// there is one.
+const COUNTER_BINDING_NAME: &str = "COUNTER";
+
+// Every request that changes the store is recorded in the KV namespace bound under this name, when
+// there is one, with keys starting with the prefix below followed by the time and a UUID.
+const AUDIT_BINDING_NAME: &str = "AUDIT_LOG";
+const AUDIT_KEY_PREFIX: &str = "audit:";
+
+// How many audit entries `GET /admin/audit` returns by default, and the most it will return.
+const DEFAULT_AUDIT_LIMIT: u64 = 100;
+const MAX_AUDIT_LIMIT: u64 = 1000;
+
+// Every key is stored under this variable's value when it's set, so several deployments can share a
+// single namespace without seeing each other's keys.
+const KEY_PREFIX_VAR: &str = "KEY_PREFIX";
+
+// How many milliseconds a single read from or write to the store is given before the request fails
+// with a 504, rather than hanging until the runtime gives up on it. Listing counts as reading and
+// deleting as writing. They can be overridden with the `KV_GET_TIMEOUT_MS` and `KV_PUT_TIMEOUT_MS`
+// variables.
+const KV_GET_TIMEOUT_VAR: &str = "KV_GET_TIMEOUT_MS";
+const DEFAULT_KV_GET_TIMEOUT: u64 = 2000;
+const KV_PUT_TIMEOUT_VAR: &str = "KV_PUT_TIMEOUT_MS";
+const DEFAULT_KV_PUT_TIMEOUT: u64 = 5000;
+
+// The name of the secret holding the base64 encoded AES-256 key used to encrypt values at rest.
+// When it isn't set values are stored as plaintext.
+const ENCRYPTION_KEY_SECRET: &str = "ENCRYPTION_KEY";
+
+// The name of the secret holding the bearer token required by endpoints that can modify or dump
+// large parts of the store. When it isn't set those endpoints are disabled.
+const WRITE_TOKEN_SECRET: &str = "WRITE_TOKEN";
+
+// The name of the secret holding the bearer token required by endpoints that can wipe the whole
+// store. When it isn't set those endpoints are disabled.
+const ADMIN_TOKEN_SECRET: &str = "ADMIN_TOKEN";
+
+// The name of the secret holding the key used to sign URLs that grant temporary read access to a
+// single key. When it isn't set URLs can't be signed.
+const SIGNING_KEY_SECRET: &str = "SIGNING_KEY";
+
+// The name of the secret holding the key used to sign metadata when values are written, so values
+// changed in the store by anything other than the worker can be caught when they're read. When it
+// isn't set metadata isn't signed or checked.
+const INTEGRITY_KEY_SECRET: &str = "INTEGRITY_KEY";
+
+// Setting this variable to `true` makes reading a key require the write token or a signed URL.
+const PRIVATE_READS_VAR: &str = "PRIVATE_READS";
+
+// Setting this variable to `true` makes deletes soft by default, leaving a tombstone behind that can
+// be undeleted until it expires.
+const SOFT_DELETE_VAR: &str = "SOFT_DELETE";
+
+// How many seconds a tombstone left by a soft delete is kept before it's removed for good. It can be
+// overridden with the `TOMBSTONE_TTL` variable.
+const TOMBSTONE_TTL_VAR: &str = "TOMBSTONE_TTL";
+const DEFAULT_TOMBSTONE_TTL: u64 = 60 * 60 * 24 * 7;
+
+// KV won't accept an expiration ttl shorter than this many seconds.
+const MIN_TTL: u64 = 60;
+
+// Setting this variable to a number of seconds makes keys written without a `?ttl=` of their own
+// expire after that long, which keeps ephemeral deployments from piling up keys.
+const DEFAULT_TTL_VAR: &str = "DEFAULT_TTL";
+
+// Keys starting with this prefix are reserved for the worker's own bookkeeping.
+const SYSTEM_KEY_PREFIX: &str = "__";
+
+// When a key is overwritten its old value is kept under this prefix so it can be restored.
+const PREVIOUS_KEY_PREFIX: &str = "__prev__/";
+
+// Setting this variable to a number keeps that many older versions of every key written with
+// `PUT /:key` under this prefix, on top of the previous version.
+const HISTORY_DEPTH_VAR: &str = "HISTORY_DEPTH";
+const HISTORY_KEY_PREFIX: &str = "__history__/";
+
+// How often requests failed because KV was rate limiting us or having trouble of its own is counted
+// in this key.
+const STORE_STATS_KEY: &str = "__stats__/store";
+
+// The prefixes the worker's own features store their keys under. Clients can't read or write these
+// keys, and they're hidden from listings.
+const SYSTEM_PREFIXES: &[&str] = &[
+    "__stats__/",
+    "__ratelimit__/",
+    "__schema__/",
+    PREVIOUS_KEY_PREFIX,
+    HISTORY_KEY_PREFIX,
+    LOCK_KEY_PREFIX,
+    CONFIRM_KEY_PREFIX,
+    IDEMPOTENCY_KEY_PREFIX,
+    BLOB_KEY_PREFIX,
+    "__idx__/",
+    "__health__",
+];
+
+// Setting this variable to `true` stores values that are written more than once only once, with
+// every key holding them pointing at a single shared copy.
+const DEDUPLICATE_VAR: &str = "DEDUPLICATE";
+
+// The shared copies of deduplicated values are kept under this prefix, named by their checksum.
+const BLOB_KEY_PREFIX: &str = "__blobs__/";
+
+// Every key is also listed under this prefix followed by its content-type, so keys with a given
+// content-type can be listed without walking every key.
+const CONTENT_TYPE_INDEX_PREFIX: &str = "__idx__/content_type/";
+
+// The top-level fields of structured documents are indexed under this prefix, followed by the
+// field's name and value, so documents with a field set to a value can be found without a scan.
+const STRUCTURED_INDEX_PREFIX: &str = "__idx__/structured/";
+
+// Locks are kept under this prefix, holding the token of whoever acquired them.
+const LOCK_KEY_PREFIX: &str = "__locks__/";
+
+// The tokens confirming a prefix delete are kept under this prefix, for this many seconds.
+const CONFIRM_KEY_PREFIX: &str = "__confirm__/";
+const CONFIRM_TTL: u64 = 60;
+
+// The responses to writes sent with an `Idempotency-Key` header are kept under this prefix, for this
+// many seconds, so retries get the same response without the write happening twice.
+const IDEMPOTENCY_KEY_PREFIX: &str = "__idem__/";
+const IDEMPOTENCY_TTL: u64 = 60 * 60 * 24;
+
+// KV won't store values bigger than this many bytes. Deployments can set a lower limit with the
+// `MAX_VALUE_SIZE` variable.
+const MAX_VALUE_SIZE: usize = 25 * 1024 * 1024;
+const MAX_VALUE_SIZE_VAR: &str = "MAX_VALUE_SIZE";
+
+// KV won't store keys longer than this many bytes.
+const MAX_KEY_LENGTH: usize = 512;
+
+// KV won't store metadata that takes more than this many bytes once serialized as JSON.
+const MAX_METADATA_SIZE: usize = 1024;
+
+// Custom metadata is sent as headers starting with this, such as `X-Meta-Owner`.
+const CUSTOM_METADATA_HEADER_PREFIX: &str = "x-meta-";
+
+// How many custom metadata headers a single write can have, and how long each name and value can be,
+// which keeps the custom metadata from crowding out the fields we manage ourselves.
+const MAX_CUSTOM_METADATA_FIELDS: usize = 16;
+const MAX_CUSTOM_METADATA_LENGTH: usize = 256;
+
+// Keys with these names would be routed to one of our other endpoints instead of `/:key`.
+const RESERVED_KEYS: &[&str] = &[
+    "admin",
+    "batch",
+    "bulk",
+    "by-content-type",
+    "content-types",
+    "counter",
+    "export",
+    "import",
+    "keys",
+    "list",
+    "locks",
+    "meta",
+    "multi",
+    "openapi.json",
+    "prefix",
+    "prefix-delete",
+    "query",
+    "search",
+    "stats",
+    "structured",
+    "tags",
+    "undelete",
+    "version",
+];
+
+// How many seconds previous versions are kept around for.
+const PREVIOUS_TTL: u64 = 60 * 60 * 24 * 7;
+
+// Operators can optionally provide a JSON Schema in this environment variable to describe the
+// documents accepted by the structured endpoints.
+const STRUCTURED_SCHEMA_VAR: &str = "STRUCTURED_SCHEMA";
+
+// How many bytes of a value `/:key/inspect` shows by default, and the most it will show.
+const INSPECT_DEFAULT_BYTES: usize = 256;
+const INSPECT_MAX_BYTES: usize = 4096;
+
+// Values at least this many bytes are streamed to the client as they're read from the store rather
+// than read into memory first.
+const STREAM_THRESHOLD: u64 = 1024 * 1024;
+
+// Values smaller than this many bytes aren't worth compressing, as they could end up even bigger.
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+// How many keys a single export request will read before handing back a cursor, so we stay within
+// the CPU and subrequest limits of a single request.
+const EXPORT_KEY_LIMIT: u64 = 500;
+
+// How many keys we ask for in each list call while exporting.
+const EXPORT_PAGE_SIZE: u64 = 100;
+
+// The most list calls a single export request will make, since the worker's own keys and tombstones
+// are listed but not exported, so the key limit alone doesn't bound them.
+const EXPORT_PAGE_LIMIT: usize = 20;
+
+// The most keys that can be fetched in a single multi-get request.
+const MULTI_GET_LIMIT: usize = 50;
+
+// The most keys that can be checked in a single batch existence request.
+const BATCH_EXISTS_LIMIT: usize = 100;
+
+// The most entries that can be written in a single bulk put request.
+const BULK_PUT_LIMIT: usize = 100;
+
+// The most pages a delimited listing will read to fill a single page of keys and common prefixes.
+const DELIMITED_LIST_PAGE_LIMIT: usize = 10;
+
+// The most keys and pages `/list?all=1` will read before giving up and returning a cursor.
+const LIST_ALL_KEY_LIMIT: u64 = 10_000;
+const LIST_ALL_PAGE_LIMIT: usize = 20;
+
+// KV won't list more than this many keys in a single page.
+const MAX_LIST_PAGE_SIZE: u64 = 1000;
+
+// The most keys a single request can change the expiration of, since each one has to be read and
+// written again.
+const PREFIX_TTL_KEY_LIMIT: u64 = 100;
+
+// The most keys a single migration request will look at, since each one without metadata has to be
+// read and written again.
+const MIGRATE_KEY_LIMIT: u64 = 100;
+
+// The most keys `/content-types` will look at in a single request.
+const CONTENT_TYPES_SCAN_LIMIT: u64 = 10_000;
+
+// The most keys `/stats/usage` will look at in a single request.
+const USAGE_SCAN_LIMIT: u64 = 10_000;
+
+// How many keys a single request deleting a tag looks at before handing back a cursor, in pages of
+// `DELETE_TAG_PAGE_SIZE`. It also stops at the end of the page where it has deleted at least
+// `DELETE_TAG_KEY_LIMIT` keys, since each one costs several reads and writes.
+const DELETE_TAG_SCAN_LIMIT: u64 = 1000;
+const DELETE_TAG_PAGE_SIZE: u64 = 100;
+const DELETE_TAG_KEY_LIMIT: u64 = 100;
+
+// The most keys a single prefix delete request will delete, since each one costs several reads and
+// writes.
+const PREFIX_DELETE_KEY_LIMIT: u64 = 100;
+
+// The most keys a single flush request will delete, so we stay within the subrequest limits of a
+// single request.
+const FLUSH_KEY_LIMIT: u64 = 100;
+
+// The most keys a single search or query will look at before giving up, which can be changed with
+// the `SCAN_LIMIT` variable.
+const SCAN_LIMIT_VAR: &str = "SCAN_LIMIT";
+const DEFAULT_SCAN_LIMIT: u64 = 500;
+
+// The content-type values are stored with when none was sent and we can't work out what they are,
+// which can be changed with the `DEFAULT_CONTENT_TYPE` variable.
+const DEFAULT_CONTENT_TYPE_VAR: &str = "DEFAULT_CONTENT_TYPE";
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
 /// Let's pretend we have some important metadata we want to store along side our keys, so we'll
 /// just use the amazing [serde](https://docs.rs/serde) library add serialization support for
 /// our metadata struct.
@@ -13,146 +293,6199 @@ const KV_BINDING_NAME: &str = "KV_STORE";
 struct ExampleMetadata {
     // For our metadata, let's store the content-type the user specified when putting a key.
     content_type: String,
+    // When the key was first written, as a unix timestamp in seconds. Keys written before we started
+    // tracking this, or overwritten without reading them first, won't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    created_at: Option<u64>,
+    // When the key was last written, as a unix timestamp in seconds. Keys written before we started
+    // tracking this won't have it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    updated_at: Option<u64>,
+    // The hex encoded SHA-256 digest of the value, so we can make sure it hasn't been corrupted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    // The base64 encoded nonce the value was encrypted with, if it was encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
+    // When the key was soft deleted, which means it should be treated as if it doesn't exist.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deleted_at: Option<u64>,
+    // When the key will expire from the store, as a unix timestamp in seconds, if we know it. A
+    // tombstone keeps the expiration of the key it hides, so it can be brought back with it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expiration: Option<u64>,
+    // The checksum of the blob holding the value if it was deduplicated, in which case the key itself
+    // holds nothing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    blob: Option<String>,
+    // The name of the file the value was uploaded from, if it was uploaded with a form.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filename: Option<String>,
+    // How many bytes the value is, before it's encrypted, so clients can tell how big it is without
+    // reading it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    // Labels the key was written with using `?tag=`, which let keys be deleted as a group.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    // The hex encoded HMAC of the fields describing the value, see [metadata_signature].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    // Whatever else the client wants to attach to the value, sent as `X-Meta-*` headers.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    custom: HashMap<String, String>,
+    // Arbitrary JSON the client stored alongside the value using `PUT /:key/with-meta`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extra: Option<serde_json::Value>,
 }
 
-async fn list(req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    // Load the KV store binding by the name we specified above.
-    let store = ctx.kv(KV_BINDING_NAME)?;
+impl ExampleMetadata {
+    /// Creates the metadata for a value that is being written right now.
+    fn new(content_type: impl Into<String>) -> Self {
+        Self {
+            content_type: content_type.into(),
+            created_at: None,
+            updated_at: Some(utils::now()),
+            checksum: None,
+            nonce: None,
+            deleted_at: None,
+            expiration: None,
+            blob: None,
+            filename: None,
+            size: None,
+            tags: Vec::new(),
+            signature: None,
+            custom: HashMap::new(),
+            extra: None,
+        }
+    }
 
-    // Read any options we'd like to do to configure our list.
-    let url = req.url()?;
-    let limit = utils::param_from(&url, "limit")
-        .and_then(|limit_str| limit_str.parse().ok())
-        .unwrap_or(100);
-    let prefix = utils::param_from(&url, "prefix")
-        .map(String::from)
-        .unwrap_or_default();
+    /// Makes sure the metadata will fit in KV, failing with a 400 if it won't.
+    fn check_size(&self) -> Result<()> {
+        let size = serde_json::to_vec(self)?.len();
+        if size > MAX_METADATA_SIZE {
+            return Err(ApiError::InvalidRequest(format!(
+                "metadata is {} bytes, which is more than the {} bytes KV allows",
+                size, MAX_METADATA_SIZE
+            ))
+            .into());
+        }
 
-    let list = store.list().limit(limit).prefix(prefix).execute().await?;
-    Response::from_json(&list)
+        Ok(())
+    }
 }
 
-async fn put(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let store = ctx.kv(KV_BINDING_NAME)?;
-    let key = ctx.param("key").unwrap();
-    let body = req.bytes().await?;
+/// Reads the custom metadata sent as `X-Meta-*` headers, keyed by the lowercased name after the
+/// prefix.
+fn custom_metadata(headers: &Headers) -> Result<HashMap<String, String>> {
+    let mut custom = HashMap::new();
+    for (name, value) in headers.entries() {
+        let name = name.to_ascii_lowercase();
+        let name = match name.strip_prefix(CUSTOM_METADATA_HEADER_PREFIX) {
+            Some(name) if !name.is_empty() => name,
+            _ => continue,
+        };
+        if name.len() > MAX_CUSTOM_METADATA_LENGTH || value.len() > MAX_CUSTOM_METADATA_LENGTH {
+            return Err(ApiError::InvalidRequest(format!(
+                "custom metadata names and values can't be longer than {} bytes",
+                MAX_CUSTOM_METADATA_LENGTH
+            ))
+            .into());
+        }
+        custom.insert(name.to_string(), value);
+    }
+    if custom.len() > MAX_CUSTOM_METADATA_FIELDS {
+        return Err(ApiError::InvalidRequest(format!(
+            "there can't be more than {} custom metadata headers",
+            MAX_CUSTOM_METADATA_FIELDS
+        ))
+        .into());
+    }
 
-    // Let's store the content-type in our metadata, defaulting to data/binary if none was sent.
-    let content_type = req
-        .headers()
-        .get("content-type")?
-        .unwrap_or_else(|| "data/binary".into());
+    Ok(custom)
+}
 
-    store
-        .put_bytes(key, &body)?
-        .metadata(ExampleMetadata { content_type })?
-        .execute()
+/// The key the previous version of `key` is kept under.
+fn previous_key(key: &str) -> String {
+    format!("{}{}", PREVIOUS_KEY_PREFIX, key)
+}
+
+/// The prefix the history of `key` is kept under.
+fn history_prefix(key: &str) -> String {
+    format!("{}{}/", HISTORY_KEY_PREFIX, key)
+}
+
+/// The key a version of `key` is kept under. Versions are padded so they list in order.
+fn history_key(key: &str, version: u64) -> String {
+    format!("{}{:020}", history_prefix(key), version)
+}
+
+/// Lists the versions of `key` in its history, oldest first, along with their metadata. Keys nested
+/// under `key` have their history under the same prefix, so only names ending in a version count.
+async fn history_versions(store: &Store, key: &str) -> Result<Vec<(u64, Option<ExampleMetadata>)>> {
+    let prefix = history_prefix(key);
+    let listing = list_all(store, &prefix, None, &|_| true).await?;
+
+    Ok(listing
+        .keys
+        .into_iter()
+        .filter_map(|listed| {
+            let version = listed.name.strip_prefix(&prefix)?;
+            if !version.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
+            }
+            let metadata = listed
+                .metadata
+                .and_then(|metadata| serde_json::from_value(metadata).ok());
+            Some((version.parse().ok()?, metadata))
+        })
+        .collect())
+}
+
+/// Adds a value that is about to be overwritten to the history of `key`, dropping the oldest
+/// versions beyond `HISTORY_DEPTH`. Nothing is kept unless `HISTORY_DEPTH` is set.
+async fn record_history(
+    ctx: &RouteContext<Retries>,
+    store: &Store,
+    key: &str,
+    value: &[u8],
+    metadata: Option<&ExampleMetadata>,
+) -> Result<()> {
+    let depth = history_depth(ctx)?;
+    if depth == 0 {
+        return Ok(());
+    }
+
+    // The blob of a deduplicated value can be released long before the history is, so let's keep a
+    // copy of the value itself. It's still encrypted with the blob's nonce, which is in its metadata.
+    let (value, metadata) = follow_blob(store, Some(value.to_vec()), metadata.cloned()).await?;
+    let metadata = metadata.map(|metadata| ExampleMetadata {
+        blob: None,
+        ..metadata
+    });
+
+    let versions = history_versions(store, key).await?;
+    let version = versions.last().map_or(1, |(version, _)| version + 1);
+    write_value(
+        store,
+        &history_key(key, version),
+        &value.unwrap_or_default(),
+        metadata.as_ref(),
+        None,
+    )
+    .await?;
+
+    let excess = (versions.len() as u64 + 1).saturating_sub(depth);
+    for (version, _) in versions.iter().take(excess as usize) {
+        store.delete(&history_key(key, *version)).await?;
+    }
+
+    Ok(())
+}
+
+/// Removes every version in the history of `key`.
+async fn clear_history(store: &Store, key: &str) -> Result<()> {
+    for (version, _) in history_versions(store, key).await? {
+        store.delete(&history_key(key, version)).await?;
+    }
+
+    Ok(())
+}
+
+/// How long the previous version of a key is kept for. A deduplicated value's reference to its blob
+/// moves to its previous version, and nothing would release it if the previous version expired, so
+/// those are kept until they're replaced or the key is purged.
+fn previous_ttl(metadata: Option<&ExampleMetadata>) -> Option<u64> {
+    match metadata.and_then(|metadata| metadata.blob.as_ref()) {
+        Some(_) => None,
+        None => Some(PREVIOUS_TTL),
+    }
+}
+
+/// Keeps a value that is about to be overwritten as the previous version of `key` and in its
+/// history, releasing the blob of the previous version it replaces.
+async fn keep_previous(
+    ctx: &RouteContext<Retries>,
+    store: &Store,
+    key: &str,
+    value: &[u8],
+    metadata: Option<&ExampleMetadata>,
+) -> Result<()> {
+    let previous = previous_key(key);
+    let (_, replaced) = store
+        .get(&previous)
+        .bytes_with_metadata::<ExampleMetadata>()
         .await?;
 
-    Response::ok("inserted")
+    write_value(store, &previous, value, metadata, previous_ttl(metadata)).await?;
+    if let Some(blob) = replaced.and_then(|replaced| replaced.blob) {
+        release_blob(store, &blob).await?;
+    }
+    record_history(ctx, store, key, value, metadata).await
 }
 
-async fn get(_: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let store = ctx.kv(KV_BINDING_NAME)?;
-    let key = ctx.param("key").unwrap();
+/// Removes the previous version of `key`, along with its share of a deduplicated blob.
+async fn drop_previous(store: &Store, key: &str) -> Result<()> {
+    let previous = previous_key(key);
+    let (_, metadata) = store
+        .get(&previous)
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
 
-    // In our store we might have the key and that key might have metadata, so we need to check.
-    let (maybe_value, maybe_metadata) = store
+    store.delete(&previous).await?;
+    if let Some(blob) = metadata.and_then(|metadata| metadata.blob) {
+        release_blob(store, &blob).await?;
+    }
+
+    Ok(())
+}
+
+/// What a write found in the key it's overwriting.
+struct Replaced {
+    // Whether the key held a value, rather than nothing or a tombstone.
+    existed: bool,
+    // The metadata of the value, if it had any.
+    metadata: Option<ExampleMetadata>,
+}
+
+/// Makes way for a new value of `key` the way `PUT /:key` does. The value being replaced is kept as
+/// the previous version and in the history, while a tombstone is dropped along with its share of a
+/// blob, since nothing points at it anymore.
+async fn replace_current(
+    ctx: &RouteContext<Retries>,
+    store: &Store,
+    key: &str,
+) -> Result<Replaced> {
+    let (value, metadata) = store
         .get(key)
         .bytes_with_metadata::<ExampleMetadata>()
         .await?;
+    let deleted = metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.deleted_at.is_some());
 
-    let (value, metadata) = match (maybe_value, maybe_metadata) {
-        (Some(value), Some(metadata)) => (value, metadata),
-        // Our KV store might have that key, but no metadata associated. So we'll just return a 500
-        // as we should never get into this state unless the store is manipulated manually.
-        (Some(_), None) => return Response::error("no metadata found", 500),
-        _ => return Response::error("key not found", 404),
-    };
+    match value {
+        Some(value) if !deleted => {
+            keep_previous(ctx, store, key, &value, metadata.as_ref()).await?;
+            Ok(Replaced {
+                existed: true,
+                metadata,
+            })
+        }
+        _ => {
+            if let Some(blob) = metadata.and_then(|metadata| metadata.blob) {
+                release_blob(store, &blob).await?;
+            }
+            Ok(Replaced {
+                existed: false,
+                metadata: None,
+            })
+        }
+    }
+}
 
-    // Let's return a body containing the bytes in the KV store with a content-type header from our
-    // metadata.
-    Ok(Response::from_bytes(value)?.with_headers({
-        let mut headers = Headers::default();
-        headers.append("content-type", &metadata.content_type)?;
-        headers
-    }))
+/// Writes a value to `key` along with its metadata, if it has any.
+async fn write_value(
+    store: &Store,
+    key: &str,
+    value: &[u8],
+    metadata: Option<&ExampleMetadata>,
+    ttl: Option<u64>,
+) -> Result<()> {
+    let mut put = store.put_bytes(key, value)?;
+    if let Some(metadata) = metadata {
+        put = put.metadata(metadata)?;
+    }
+    if let Some(ttl) = ttl {
+        put = put.expiration_ttl(ttl);
+    }
+
+    put.execute().await.map_err(Error::from)
 }
 
-async fn delete(_: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let store = ctx.kv(KV_BINDING_NAME)?;
-    let key = ctx.param("key").unwrap();
+/// Writes a value to `key` the way `PUT /:key` does, which every endpoint that writes values goes
+/// through: the value is deduplicated or encrypted as configured, its metadata is signed and the key
+/// is listed under its content-type. The checksum in `metadata` is worked out from the value if it
+/// doesn't have one, and its size, expiration, blob, nonce and signature are replaced. Returns the
+/// metadata the value was written with.
+async fn store_value(
+    env: &Env,
+    store: &Store,
+    key: &str,
+    value: Vec<u8>,
+    metadata: ExampleMetadata,
+    ttl: Option<u64>,
+) -> Result<ExampleMetadata> {
+    let checksum = match metadata.checksum.clone() {
+        Some(checksum) => checksum,
+        None => utils::sha256_hex(&value),
+    };
+    let size = value.len() as u64;
 
-    store.delete(key).await?;
+    // Identical values can be stored once and shared between keys, in which case the key only points
+    // at the shared blob. Otherwise sensitive values can be encrypted before they ever reach the store
+    // if we have a key.
+    let deduplicate = env
+        .var(DEDUPLICATE_VAR)
+        .is_ok_and(|deduplicate| deduplicate.to_string() == "true");
+    let (value, nonce, blob) = if deduplicate {
+        let nonce = store_blob(env, store, &checksum, value).await?;
+        (Vec::new(), nonce, Some(checksum.clone()))
+    } else {
+        match encryption_key(env)? {
+            Some(key) => {
+                let (ciphertext, nonce) = utils::encrypt(&key, &value)?;
+                (ciphertext, Some(nonce), None)
+            }
+            None => (value, None, None),
+        }
+    };
 
-    Response::ok("deleted")
+    let mut metadata = ExampleMetadata {
+        checksum: Some(checksum),
+        size: Some(size),
+        expiration: ttl.map(|ttl| utils::now() + ttl),
+        nonce,
+        blob,
+        ..metadata
+    };
+    metadata.signature = metadata_signature(env, &metadata);
+    metadata.check_size()?;
+    write_value(store, key, &value, Some(&metadata), ttl).await?;
+    index_content_type(store, key, &metadata.content_type, ttl).await;
+
+    Ok(metadata)
 }
 
+/// The metadata of a blob holding a deduplicated value.
 #[derive(Debug, Serialize, Deserialize)]
-struct StructuredValue {
-    foo: String,
-    bar: i32,
+struct BlobMetadata {
+    // How many keys point at the blob.
+    refs: u64,
+    // The base64 encoded nonce the blob was encrypted with, if it was encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nonce: Option<String>,
 }
 
-/// We might not always want to deal with having vague data types in our KV store we can use serde
-/// to write any serializable type to a key. So let's just only put the request body in the store
-/// if it matches the schema for [StructuredValue].
-async fn structured_put(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let store = ctx.kv(KV_BINDING_NAME)?;
-    let key = ctx.param("key").unwrap();
+impl BlobMetadata {
+    /// The metadata of a blob that was just stored for the first key pointing at it.
+    fn new(nonce: Option<String>) -> Self {
+        Self { refs: 1, nonce }
+    }
 
-    let url = req.url()?;
-    let body: StructuredValue = match req.json().await {
-        Ok(body) => body,
-        // Reject all requests that don't follow our body schema.
-        Err(_) => return Response::error("invalid body", 400),
+    /// The metadata of a blob once one more key points at it.
+    fn referenced(self) -> Self {
+        Self {
+            refs: self.refs + 1,
+            ..self
+        }
+    }
+
+    /// The metadata of a blob once one of the keys pointing at it lets go, or `None` if that was the
+    /// last one and the blob can be removed.
+    fn released(self) -> Option<Self> {
+        match self.refs {
+            0 | 1 => None,
+            refs => Some(Self {
+                refs: refs - 1,
+                ..self
+            }),
+        }
+    }
+}
+
+/// The key the blob of values with the given checksum is kept under.
+fn blob_key(checksum: &str) -> String {
+    format!("{}{}", BLOB_KEY_PREFIX, checksum)
+}
+
+/// Reads a key, following it to the blob holding its value if the value was deduplicated.
+async fn read_value(
+    store: &Store,
+    key: &str,
+) -> Result<(Option<Vec<u8>>, Option<ExampleMetadata>)> {
+    let (value, metadata) = store
+        .get(key)
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
+
+    follow_blob(store, value, metadata).await
+}
+
+/// Swaps the value of a deduplicated key, which is empty, for the value in its blob.
+async fn follow_blob(
+    store: &Store,
+    value: Option<Vec<u8>>,
+    metadata: Option<ExampleMetadata>,
+) -> Result<(Option<Vec<u8>>, Option<ExampleMetadata>)> {
+    let blob = match metadata
+        .as_ref()
+        .and_then(|metadata| metadata.blob.as_ref())
+    {
+        Some(blob) if value.is_some() => blob,
+        _ => return Ok((value, metadata)),
     };
 
-    // Let's add a expiration ttl if the user specifies one.
-    if let Some(ttl_str) = utils::param_from(&url, "ttl") {
-        let ttl = match ttl_str.parse() {
-            Ok(ttl) => ttl,
-            Err(_) => return Response::error("invalid ttl", 400),
-        };
+    match store.get(&blob_key(blob)).bytes().await? {
+        Some(value) => Ok((Some(value), metadata)),
+        None => Err(Error::RustError(format!("blob {} is missing", blob))),
+    }
+}
 
-        store.put(key, &body)?.expiration_ttl(ttl).execute().await?
-    } else {
-        store.put(key, &body)?.execute().await?
+/// Stores a value in the blob for its checksum, or adds a reference to the blob if an identical
+/// value is already stored. Returns the nonce the blob was encrypted with, if it was encrypted.
+///
+/// KV can't update a key atomically, so two writes racing to change the same blob's references can
+/// lose one of them.
+async fn store_blob(
+    env: &Env,
+    store: &Store,
+    checksum: &str,
+    value: Vec<u8>,
+) -> Result<Option<String>> {
+    let key = blob_key(checksum);
+    let (existing, metadata) = store
+        .get(&key)
+        .bytes_with_metadata::<BlobMetadata>()
+        .await?;
+
+    let (value, metadata) = match (existing, metadata) {
+        (Some(existing), Some(metadata)) => (existing, metadata.referenced()),
+        _ => match encryption_key(env)? {
+            Some(encryption_key) => {
+                let (ciphertext, nonce) = utils::encrypt(&encryption_key, &value)?;
+                (ciphertext, BlobMetadata::new(Some(nonce)))
+            }
+            None => (value, BlobMetadata::new(None)),
+        },
+    };
+
+    store
+        .put_bytes(&key, &value)?
+        .metadata(&metadata)?
+        .execute()
+        .await?;
+
+    Ok(metadata.nonce)
+}
+
+/// Drops a reference to a blob, removing it once no keys point at it anymore. Keys that expire can't
+/// release their references, so a blob can outlive the keys pointing at it, but never the other way
+/// around.
+async fn release_blob(store: &Store, checksum: &str) -> Result<()> {
+    let key = blob_key(checksum);
+    let (value, metadata) = store
+        .get(&key)
+        .bytes_with_metadata::<BlobMetadata>()
+        .await?;
+
+    match (value, metadata.and_then(BlobMetadata::released)) {
+        (Some(value), Some(metadata)) => {
+            store
+                .put_bytes(&key, &value)?
+                .metadata(metadata)?
+                .execute()
+                .await?
+        }
+        (Some(_), None) => store.delete(&key).await?,
+        (None, _) => {}
     }
 
-    Response::ok("inserted")
+    Ok(())
 }
 
-async fn structured_get(_: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let store = ctx.kv(KV_BINDING_NAME)?;
-    let key = ctx.param("key").unwrap();
+/// The prefix of the index entries for keys with the given content-type.
+fn content_type_index_prefix(content_type: &str) -> String {
+    format!(
+        "{}{}/",
+        CONTENT_TYPE_INDEX_PREFIX,
+        utils::media_type_essence(content_type)
+    )
+}
 
-    match store.get(key).json::<StructuredValue>().await {
-        Ok(Some(value)) => Response::from_json(&value),
-        Ok(None) => Response::error("key not found", 404),
-        // The key might have already been inserted with out non-structured put endpoint, so let's
-        // pretend it doesn't exist if it's invalid.
-        Err(KvError::Serialization(_)) => Response::error("key not found", 404),
-        Err(_) => Response::error("internal server error", 500),
+/// Lists a key in the content-type index. The index is only a convenience, so failing to update it
+/// shouldn't fail the write that changed the key, it's only logged instead.
+async fn index_content_type(store: &Store, key: &str, content_type: &str, ttl: Option<u64>) {
+    let index_key = format!("{}{}", content_type_index_prefix(content_type), key);
+    if let Err(err) = write_value(store, &index_key, &[], None, ttl).await {
+        console_error!("failed to add {} to the content-type index: {}", key, err);
     }
 }
 
-#[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: worker::Context) -> Result<Response> {
-    utils::log_request(&req);
+/// Removes a key from the content-type index, which like adding it is only best-effort.
+async fn unindex_content_type(store: &Store, key: &str, content_type: &str) {
+    let index_key = format!("{}{}", content_type_index_prefix(content_type), key);
+    if let Err(err) = store.delete(&index_key).await {
+        console_error!(
+            "failed to remove {} from the content-type index: {}",
+            key,
+            err
+        );
+    }
+}
 
-    // Optionally, get more helpful error messages written to the console in the case of a panic.
-    utils::set_panic_hook();
+/// The prefix of the index entries for structured documents with `field` set to `value`. Both are
+/// encoded so a slash in either can't be mistaken for the end of it.
+fn structured_index_prefix(field: &str, value: &str) -> String {
+    format!(
+        "{}{}/{}/",
+        STRUCTURED_INDEX_PREFIX,
+        utils::percent_encode(field),
+        utils::percent_encode(value)
+    )
+}
 
-    // We can use a Router to route our incoming requests to our handlers, using `:param` syntax to
-    // add URL patterns or `*name` for catch-alls.
-    Router::new()
-        .get_async("/list", list)
-        .put_async("/:key", put)
-        .get_async("/:key", get)
-        .delete_async("/:key", delete)
-        .put_async("/structured/:key", structured_put)
-        .get_async("/structured/:key", structured_get)
-        .run(req, env)
+/// The index entries for a structured document, one for each of its top-level fields holding a
+/// string, number or boolean.
+fn structured_index_keys(key: &str, document: &serde_json::Value) -> Vec<String> {
+    let fields = match document.as_object() {
+        Some(fields) => fields,
+        None => return Vec::new(),
+    };
+
+    fields
+        .iter()
+        .filter_map(|(field, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value.clone(),
+                serde_json::Value::Number(value) => value.to_string(),
+                serde_json::Value::Bool(value) => value.to_string(),
+                _ => return None,
+            };
+            Some(format!("{}{}", structured_index_prefix(field, &value), key))
+        })
+        .collect()
+}
+
+/// Moves a structured document's index entries from its old fields to its new ones. Like the
+/// content-type index this is only best-effort, so failures are logged rather than failing the
+/// write.
+async fn reindex_structured(
+    store: &Store,
+    key: &str,
+    old: Option<&serde_json::Value>,
+    new: Option<&serde_json::Value>,
+    ttl: Option<u64>,
+) {
+    let new_keys = new.map_or_else(Vec::new, |new| structured_index_keys(key, new));
+    let stale_keys = old
+        .map_or_else(Vec::new, |old| structured_index_keys(key, old))
+        .into_iter()
+        .filter(|old_key| !new_keys.contains(old_key));
+
+    for stale_key in stale_keys {
+        if let Err(err) = store.delete(&stale_key).await {
+            console_error!(
+                "failed to remove {} from the structured index: {}",
+                key,
+                err
+            );
+        }
+    }
+    for new_key in &new_keys {
+        if let Err(err) = write_value(store, new_key, &[], None, ttl).await {
+            console_error!("failed to add {} to the structured index: {}", key, err);
+        }
+    }
+}
+
+/// Reads a value as a structured document if it looks like one, so its index entries can be found.
+/// The value has to be decrypted and read from its blob already.
+fn as_structured(value: &[u8], metadata: &ExampleMetadata) -> Option<serde_json::Value> {
+    if utils::media_type_essence(&metadata.content_type) != "application/json" {
+        return None;
+    }
+
+    serde_json::from_slice::<serde_json::Value>(value)
+        .ok()
+        .filter(|document| document.is_object())
+}
+
+/// Reads a value as it's stored as a structured document, following it to its blob and decrypting
+/// it. The index is only best-effort, so a value that can't be read just doesn't have any entries.
+async fn stored_document(
+    env: &Env,
+    store: &Store,
+    value: Option<Vec<u8>>,
+    metadata: &ExampleMetadata,
+) -> Option<serde_json::Value> {
+    if utils::media_type_essence(&metadata.content_type) != "application/json" {
+        return None;
+    }
+
+    let (value, _) = follow_blob(store, value, Some(metadata.clone()))
         .await
+        .ok()?;
+    let value = decrypt_value(env, value?, metadata).ok()?;
+    as_structured(&value, metadata)
+}
+
+/// Writes a key's index entries again with the expiration the key was just given, so they expire
+/// along with it.
+async fn refresh_indexes(
+    env: &Env,
+    store: &Store,
+    key: &str,
+    value: Vec<u8>,
+    metadata: &ExampleMetadata,
+    ttl: Option<u64>,
+) {
+    index_content_type(store, key, &metadata.content_type, ttl).await;
+    let document = stored_document(env, store, Some(value), metadata).await;
+    reindex_structured(store, key, None, document.as_ref(), ttl).await;
+}
+
+/// Gets the key a request is for. Keys are always stored un-encoded, so the key from the URL is
+/// percent-decoded, which also lets keys containing a `/` be addressed as `%2F`. The catch-all routes
+/// under `/keys/` hand us the key with a leading `/` which we don't want either.
+fn key_param(ctx: &RouteContext<Retries>) -> Result<String> {
+    decode_key(ctx.param("key").unwrap())
+}
+
+/// Decodes the key parameter of a route into the key it's for.
+fn decode_key(param: &str) -> Result<String> {
+    utils::percent_decode(param.strip_prefix('/').unwrap_or(param))
+}
+
+/// Gets the key a request wants to write to, failing with a 400 explaining why if it isn't allowed.
+fn write_key_param(ctx: &RouteContext<Retries>) -> Result<String> {
+    writable_key(user_key_param(ctx)?)
+}
+
+/// Makes sure a key can be written, failing with a 400 explaining why if it can't.
+fn writable_key(key: String) -> Result<String> {
+    match key_violation(&key) {
+        Some(violation) => Err(ApiError::InvalidRequest(violation).into()),
+        None => Ok(key),
+    }
+}
+
+/// Gets the key a request wants to read or delete. Keys that can't be written can't exist either, so
+/// they're simply not found rather than telling the client which rule they broke.
+fn read_key_param(ctx: &RouteContext<Retries>) -> Result<String> {
+    readable_key(user_key_param(ctx)?)
+}
+
+/// Makes sure a key could exist, failing with a 404 if it couldn't.
+fn readable_key(key: String) -> Result<String> {
+    match key_violation(&key) {
+        Some(_) => Err(ApiError::KeyNotFound.into()),
+        None => Ok(key),
+    }
+}
+
+/// Gets the key a request is for, refusing to let clients touch the worker's own keys.
+fn user_key_param(ctx: &RouteContext<Retries>) -> Result<String> {
+    user_key(key_param(ctx)?)
+}
+
+/// Makes sure a key isn't one of the worker's own, failing with a 403 if it is.
+fn user_key(key: String) -> Result<String> {
+    if is_system_key(&key) {
+        return Err(ApiError::Forbidden("key is reserved for internal use".into()).into());
+    }
+
+    Ok(key)
+}
+
+/// Checks if a key belongs to one of the worker's own features.
+fn is_system_key(key: &str) -> bool {
+    SYSTEM_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+/// Checks if a key can be written, returning a description of the rule it breaks if it can't.
+fn key_violation(key: &str) -> Option<String> {
+    if key.is_empty() {
+        Some("key must not be empty".into())
+    } else if key.len() > MAX_KEY_LENGTH {
+        Some(format!("key must be at most {} bytes", MAX_KEY_LENGTH))
+    } else if RESERVED_KEYS.contains(&key) {
+        Some(format!("{} is a reserved key", key))
+    } else if key.starts_with(SYSTEM_KEY_PREFIX) {
+        Some(format!(
+            "keys starting with {} are reserved",
+            SYSTEM_KEY_PREFIX
+        ))
+    } else {
+        None
+    }
+}
+
+/// The KV store, scoped to the namespace in the `KEY_PREFIX` variable. Handlers only ever see keys
+/// without the namespace, it's added when a key is read or written and stripped from listed keys.
+/// Without a namespace keys are stored exactly as they're named.
+struct Store {
+    kv: kv::KvStore,
+    // The binding itself, for the few things `kv::KvStore` can't do.
+    binding: JsValue,
+    prefix: String,
+    get_timeout: Duration,
+    put_timeout: Duration,
+    // How retrying is going for the request the store was opened for.
+    retries: Retries,
+}
+
+impl Store {
+    fn new(ctx: &RouteContext<Retries>) -> Result<Self> {
+        Ok(Self {
+            retries: ctx.data.clone(),
+            ..Self::from_env(&ctx.env)?
+        })
+    }
+
+    /// Opens the store outside of a route, where there's only the environment to go by, so retrying
+    /// is kept track of for this store alone.
+    fn from_env(env: &Env) -> Result<Self> {
+        Ok(Self {
+            kv: env.kv(KV_BINDING_NAME)?,
+            binding: js_sys::Reflect::get(env, &KV_BINDING_NAME.into())?,
+            prefix: env
+                .var(KEY_PREFIX_VAR)
+                .map(|prefix| prefix.to_string())
+                .unwrap_or_default(),
+            get_timeout: kv_timeout(env, KV_GET_TIMEOUT_VAR, DEFAULT_KV_GET_TIMEOUT)?,
+            put_timeout: kv_timeout(env, KV_PUT_TIMEOUT_VAR, DEFAULT_KV_PUT_TIMEOUT)?,
+            retries: Retries::default(),
+        })
+    }
+
+    fn get(&self, key: &str) -> Get {
+        Get {
+            builder: self.kv.get(&utils::scope_key(&self.prefix, key)),
+            key: key.into(),
+            timeout: self.get_timeout,
+            retries: self.retries.clone(),
+        }
+    }
+
+    /// Reads a key as a stream along with its metadata, so the value can be passed on without
+    /// holding all of it in memory.
+    async fn get_stream(
+        &self,
+        key: &str,
+    ) -> Result<(Option<web_sys::ReadableStream>, Option<ExampleMetadata>)> {
+        let get_with_metadata: js_sys::Function =
+            js_sys::Reflect::get(&self.binding, &"getWithMetadata".into())?.dyn_into()?;
+        let options = js_sys::Object::new();
+        js_sys::Reflect::set(&options, &"type".into(), &"stream".into())?;
+        let promise: js_sys::Promise = get_with_metadata
+            .call2(
+                &self.binding,
+                &utils::scope_key(&self.prefix, key).into(),
+                &options,
+            )?
+            .dyn_into()?;
+
+        let pair = JsFuture::from(promise).await?;
+        let value = js_sys::Reflect::get(&pair, &"value".into())?;
+        let metadata = js_sys::Reflect::get(&pair, &"metadata".into())?;
+        let metadata = if metadata.is_null() || metadata.is_undefined() {
+            None
+        } else {
+            let metadata: String = js_sys::JSON::stringify(&metadata)?.into();
+            Some(serde_json::from_str(&metadata)?)
+        };
+
+        Ok((value.dyn_into().ok(), metadata))
+    }
+
+    fn put_bytes(&self, key: &str, value: &[u8]) -> std::result::Result<Put, ApiError> {
+        let builder = self
+            .kv
+            .put_bytes(&utils::scope_key(&self.prefix, key), value)?;
+        Ok(Put {
+            builder,
+            key: key.into(),
+            timeout: self.put_timeout,
+            retries: self.retries.clone(),
+        })
+    }
+
+    async fn delete(&self, key: &str) -> std::result::Result<(), ApiError> {
+        let scoped = utils::scope_key(&self.prefix, key);
+        let delete = retry::with_retries(&self.retries, || self.kv.delete(&scoped));
+        with_timeout("delete", key, self.put_timeout, delete).await
+    }
+}
+
+fn kv_timeout(env: &Env, name: &str, default: u64) -> Result<Duration> {
+    match env.var(name) {
+        Ok(timeout) => timeout
+            .to_string()
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| Error::RustError(format!("invalid {}", name))),
+        Err(_) => Ok(Duration::from_millis(default)),
+    }
+}
+
+/// Races an operation on the store against its timeout, failing with a 504 naming the operation if
+/// the store doesn't answer in time. The operation is only given up on, not cancelled, so a write
+/// that timed out might still land.
+async fn with_timeout<T>(
+    operation: &str,
+    key: &str,
+    timeout: Duration,
+    future: impl Future<Output = std::result::Result<T, KvError>>,
+) -> std::result::Result<T, ApiError> {
+    let raced = utils::timed(async {
+        let future = future.fuse();
+        let delay = Delay::from(timeout).fuse();
+        pin_mut!(future, delay);
+        select! {
+            result = future => Some(result),
+            () = delay => None,
+        }
+    });
+    match raced.await {
+        (Some(result), _) => result.map_err(ApiError::from),
+        (None, elapsed) => {
+            console_error!("kv {} of {} timed out after {} ms", operation, key, elapsed);
+            Err(ApiError::Timeout(format!(
+                "kv {} timed out after {} ms",
+                operation,
+                timeout.as_millis()
+            )))
+        }
+    }
+}
+
+/// A read from the store, which is retried if it fails in a way that might not happen again and
+/// fails with a 504 if the store takes too long.
+struct Get {
+    builder: kv::GetOptionsBuilder,
+    key: String,
+    timeout: Duration,
+    retries: Retries,
+}
+
+impl Get {
+    async fn text(self) -> std::result::Result<Option<String>, ApiError> {
+        let get = retry::with_retries(&self.retries, || self.builder.clone().text());
+        with_timeout("get", &self.key, self.timeout, get).await
+    }
+
+    async fn json<T: serde::de::DeserializeOwned>(
+        self,
+    ) -> std::result::Result<Option<T>, ApiError> {
+        let get = retry::with_retries(&self.retries, || self.builder.clone().json());
+        with_timeout("get", &self.key, self.timeout, get).await
+    }
+
+    async fn bytes(self) -> std::result::Result<Option<Vec<u8>>, ApiError> {
+        let get = retry::with_retries(&self.retries, || self.builder.clone().bytes());
+        with_timeout("get", &self.key, self.timeout, get).await
+    }
+
+    async fn bytes_with_metadata<M: serde::de::DeserializeOwned>(
+        self,
+    ) -> std::result::Result<(Option<Vec<u8>>, Option<M>), ApiError> {
+        let get = retry::with_retries(&self.retries, || self.builder.clone().bytes_with_metadata());
+        with_timeout("get", &self.key, self.timeout, get).await
+    }
+}
+
+/// A write to the store, which is only retried when the request is guarded by an idempotency key
+/// and fails with a 504 if the store takes too long.
+struct Put {
+    builder: kv::PutOptionsBuilder,
+    key: String,
+    timeout: Duration,
+    retries: Retries,
+}
+
+impl Put {
+    fn metadata<T: Serialize>(self, metadata: T) -> std::result::Result<Self, ApiError> {
+        Ok(Put {
+            builder: self.builder.metadata(metadata)?,
+            ..self
+        })
+    }
+
+    fn expiration(self, expiration: u64) -> Self {
+        Put {
+            builder: self.builder.expiration(expiration),
+            ..self
+        }
+    }
+
+    fn expiration_ttl(self, expiration_ttl: u64) -> Self {
+        Put {
+            builder: self.builder.expiration_ttl(expiration_ttl),
+            ..self
+        }
+    }
+
+    async fn execute(self) -> std::result::Result<(), ApiError> {
+        let put = retry::put_with_retries(&self.retries, || self.builder.clone().execute());
+        with_timeout("put", &self.key, self.timeout, put).await
+    }
+}
+
+/// Lists a single page of keys under `prefix`, carrying on from `cursor` if there is one.
+async fn list_page(
+    store: &Store,
+    prefix: &str,
+    cursor: Option<String>,
+    limit: Option<u64>,
+) -> Result<kv::ListResponse> {
+    let mut options = store
+        .kv
+        .list()
+        .prefix(utils::scope_key(&store.prefix, prefix));
+    if let Some(cursor) = cursor {
+        options = options.cursor(cursor);
+    }
+    if let Some(limit) = limit {
+        options = options.limit(limit);
+    }
+
+    let list = retry::with_retries(&store.retries, || options.clone().execute());
+    let mut page = with_timeout("list", prefix, store.get_timeout, list).await?;
+    unscope_keys(&store.prefix, &mut page.keys);
+
+    Ok(page)
+}
+
+/// Takes listed keys back out of the namespace given by `prefix`, so clients never see it.
+fn unscope_keys(prefix: &str, keys: &mut [kv::Key]) {
+    for key in keys {
+        key.name = utils::unscope_key(prefix, &key.name).to_string();
+    }
+}
+
+/// Finds a key without reading its value, by listing the keys starting with it. If the key exists
+/// it's always listed first, since no other key with it as a prefix can sort before it.
+async fn listed_key(store: &Store, key: &str) -> Result<Option<kv::Key>> {
+    let page = list_page(store, key, None, Some(1)).await?;
+    Ok(page
+        .keys
+        .into_iter()
+        .next()
+        .filter(|listed| listed.name == key))
+}
+
+/// Checks if a key exists without reading its value.
+async fn key_exists(store: &Store, key: &str) -> Result<bool> {
+    Ok(listed_key(store, key)
+        .await?
+        .is_some_and(|listed| !is_tombstone(&listed)))
+}
+
+/// Gets the content-type stored in a listed key's metadata.
+fn key_content_type(key: &kv::Key) -> Option<&str> {
+    key.metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get("content_type"))
+        .and_then(|content_type| content_type.as_str())
+}
+
+/// Gets a numeric metadata field of a listed key.
+fn key_metadata_u64(key: &kv::Key, field: &str) -> Option<u64> {
+    key.metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get(field))
+        .and_then(|value| value.as_u64())
+}
+
+/// Checks if a listed key is a tombstone left behind by a soft delete.
+fn is_tombstone(key: &kv::Key) -> bool {
+    key.metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get("deleted_at"))
+        .is_some_and(|deleted_at| !deleted_at.is_null())
+}
+
+/// Loads the key used to encrypt values at rest, if one is configured.
+fn encryption_key(env: &Env) -> Result<Option<Vec<u8>>> {
+    match env.secret(ENCRYPTION_KEY_SECRET) {
+        Ok(secret) => utils::parse_encryption_key(&secret.to_string()).map(Some),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Checks that the request carries the bearer token from the `WRITE_TOKEN` secret. If no token is
+/// configured nobody is authorized.
+fn write_authorized(req: &Request, ctx: &RouteContext<Retries>) -> Result<bool> {
+    let token = match ctx.secret(WRITE_TOKEN_SECRET) {
+        Ok(token) => token.to_string(),
+        Err(_) => return Ok(false),
+    };
+
+    Ok(utils::bearer_token(req)?.is_some_and(|bearer| bearer == token))
+}
+
+/// Checks if a request has the admin token, which is never the case when one isn't configured.
+fn admin_authorized(req: &Request, ctx: &RouteContext<Retries>) -> Result<bool> {
+    let token = match ctx.secret(ADMIN_TOKEN_SECRET) {
+        Ok(token) => token.to_string(),
+        Err(_) => return Ok(false),
+    };
+
+    Ok(utils::bearer_token(req)?.is_some_and(|bearer| bearer == token))
+}
+
+/// Loads the key used to sign URLs, if one is configured.
+fn signing_key(ctx: &RouteContext<Retries>) -> Option<Vec<u8>> {
+    ctx.secret(SIGNING_KEY_SECRET)
+        .ok()
+        .map(|secret| secret.to_string().into_bytes())
+}
+
+/// Loads the key used to sign metadata, if one is configured.
+fn integrity_key(env: &Env) -> Option<Vec<u8>> {
+    env.secret(INTEGRITY_KEY_SECRET)
+        .ok()
+        .map(|secret| secret.to_string().into_bytes())
+}
+
+/// The message signed to vouch for a value's metadata. It covers the fields that describe the value:
+/// its checksum, which covers the value itself, its content-type and its size. The other fields are
+/// rewritten by the worker all the time, like the expiration when a key is touched, so they're left
+/// out. Values without a checksum can't be signed.
+fn signed_metadata_message(metadata: &ExampleMetadata) -> Option<String> {
+    let checksum = metadata.checksum.as_ref()?;
+    Some(format!(
+        "{}\n{}\n{}",
+        checksum,
+        metadata.content_type,
+        metadata.size.unwrap_or_default()
+    ))
+}
+
+/// Signs a value's metadata, when the integrity key is configured.
+fn metadata_signature(env: &Env, metadata: &ExampleMetadata) -> Option<String> {
+    let key = integrity_key(env)?;
+    let message = signed_metadata_message(metadata)?;
+    Some(utils::hmac_sha256_hex(&key, &message))
+}
+
+/// Checks that the metadata of a value was signed by us when the integrity key is configured,
+/// failing with a 502 if it wasn't.
+fn verify_metadata_signature(env: &Env, metadata: Option<&ExampleMetadata>) -> Result<()> {
+    match integrity_key(env) {
+        Some(key) => check_metadata_signature(&key, metadata),
+        None => Ok(()),
+    }
+}
+
+/// Checks the signature of a value's metadata with `key`. Values without a signature, or without
+/// any metadata at all, fail as well, otherwise removing them would be enough to get past this, so
+/// values written before the integrity key was set have to be written again.
+fn check_metadata_signature(key: &[u8], metadata: Option<&ExampleMetadata>) -> Result<()> {
+    let verified = match metadata {
+        Some(metadata) => match (signed_metadata_message(metadata), &metadata.signature) {
+            (Some(message), Some(signature)) => utils::verify_hmac_sha256(key, &message, signature),
+            _ => false,
+        },
+        None => false,
+    };
+    if !verified {
+        return Err(ApiError::Integrity(
+            "metadata signature doesn't match, the value was changed outside of the worker".into(),
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// The message signed to grant read access to `key` until `expires`.
+fn signed_read_message(key: &str, expires: u64) -> String {
+    format!("GET\n{}\n{}", key, expires)
+}
+
+/// Checks the signature and expiry of a signed URL for reading `key`, failing with a 403 if the URL
+/// has expired or doesn't match its signature.
+fn verify_signed_read(
+    ctx: &RouteContext<Retries>,
+    url: &Url,
+    key: &str,
+    signature: &str,
+) -> Result<()> {
+    let forbidden = |message: &str| Err(ApiError::Forbidden(message.into()).into());
+
+    let signing_key = match signing_key(ctx) {
+        Some(signing_key) => signing_key,
+        None => return forbidden("invalid signature"),
+    };
+    let expires = match utils::param_from(url, "expires").and_then(|expires| expires.parse().ok()) {
+        Some(expires) => expires,
+        None => return forbidden("invalid signature"),
+    };
+
+    if !utils::verify_hmac_sha256(&signing_key, &signed_read_message(key, expires), signature) {
+        return forbidden("invalid signature");
+    }
+    if expires <= utils::now() {
+        return forbidden("signature expired");
+    }
+
+    Ok(())
+}
+
+/// Decrypts a value read from the store if it was encrypted when it was written.
+fn decrypt_value(env: &Env, value: Vec<u8>, metadata: &ExampleMetadata) -> Result<Vec<u8>> {
+    let nonce = match &metadata.nonce {
+        Some(nonce) => nonce,
+        None => return Ok(value),
+    };
+
+    match encryption_key(env)? {
+        Some(key) => utils::decrypt(&key, nonce, &value),
+        None => Err(Error::RustError(
+            "value is encrypted but no encryption key is configured".into(),
+        )),
+    }
+}
+
+/// A page of keys, which every endpoint that lists keys responds with. KV's own list response is
+/// named after its JavaScript API and has changed between versions of the worker crate, so clients
+/// get this instead.
+#[derive(Debug, Serialize)]
+struct ListResult {
+    keys: Vec<ListedKey>,
+    /// Only listings grouped by a delimiter have these.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    common_prefixes: Option<Vec<String>>,
+    /// Where to carry on from, which is only set when there are more keys to list.
+    cursor: Option<String>,
+    complete: bool,
+    /// Set when fetching every key stopped short because there were too many of them.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    /// How the keys were sorted, if they were sorted by something other than their name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sort: Option<PageSort>,
+}
+
+impl ListResult {
+    fn new(keys: Vec<ListedKey>, cursor: Option<String>, complete: bool) -> Self {
+        Self {
+            keys,
+            common_prefixes: None,
+            cursor: cursor.filter(|_| !complete),
+            complete,
+            truncated: false,
+            sort: None,
+        }
+    }
+
+    /// Leaves the metadata out of every key, for clients that only want names and expirations.
+    fn without_metadata(mut self) -> Self {
+        for key in &mut self.keys {
+            key.metadata = None;
+        }
+        self
+    }
+}
+
+/// A single key in a `ListResult`.
+#[derive(Debug, Serialize)]
+struct ListedKey {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Value>,
+}
+
+impl From<kv::Key> for ListedKey {
+    fn from(key: kv::Key) -> Self {
+        Self {
+            name: key.name,
+            expiration: key.expiration,
+            metadata: key.metadata,
+        }
+    }
+}
+
+impl From<kv::ListResponse> for ListResult {
+    fn from(page: kv::ListResponse) -> Self {
+        let keys = page.keys.into_iter().map(ListedKey::from).collect();
+        Self::new(keys, page.cursor, page.list_complete)
+    }
+}
+
+/// Lists every key under a prefix in one go by following cursors, which saves clients of small
+/// stores from writing the loop themselves. It stops at `LIST_ALL_KEY_LIMIT` keys or
+/// `LIST_ALL_PAGE_LIMIT` pages, leaving the listing incomplete with a cursor to carry on from.
+async fn list_all(
+    store: &Store,
+    prefix: &str,
+    cursor: Option<String>,
+    matches: &dyn Fn(&kv::Key) -> bool,
+) -> Result<kv::ListResponse> {
+    let mut listing = AllKeys::new(cursor);
+    while let Some(limit) = listing.next_limit() {
+        let page = list_page(store, prefix, listing.cursor.clone(), Some(limit)).await?;
+        listing.add(page, matches);
+    }
+
+    Ok(listing.into())
+}
+
+/// The keys [list_all] has gathered so far, which knows when to stop.
+#[derive(Debug, Default)]
+struct AllKeys {
+    keys: Vec<kv::Key>,
+    cursor: Option<String>,
+    list_complete: bool,
+    pages: usize,
+}
+
+impl AllKeys {
+    fn new(cursor: Option<String>) -> Self {
+        Self {
+            cursor,
+            ..Self::default()
+        }
+    }
+
+    /// How many keys the next page should list, or nothing once the listing is complete or has hit
+    /// one of the limits.
+    fn next_limit(&self) -> Option<u64> {
+        let remaining = LIST_ALL_KEY_LIMIT.saturating_sub(self.keys.len() as u64);
+        if self.list_complete || self.pages >= LIST_ALL_PAGE_LIMIT || remaining == 0 {
+            return None;
+        }
+
+        Some(remaining.min(MAX_LIST_PAGE_SIZE))
+    }
+
+    fn add(&mut self, page: kv::ListResponse, matches: &dyn Fn(&kv::Key) -> bool) {
+        self.keys
+            .extend(page.keys.into_iter().filter(|key| matches(key)));
+        self.list_complete = page.list_complete;
+        self.cursor = page.cursor;
+        self.pages += 1;
+    }
+}
+
+impl From<AllKeys> for kv::ListResponse {
+    fn from(listing: AllKeys) -> Self {
+        Self {
+            keys: listing.keys,
+            list_complete: listing.list_complete,
+            cursor: listing.cursor,
+        }
+    }
+}
+
+/// The metadata a page of keys can be sorted by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SortField {
+    Key,
+    UpdatedAt,
+    Size,
+}
+
+impl FromStr for SortField {
+    type Err = Error;
+
+    fn from_str(field: &str) -> Result<Self> {
+        match field {
+            "key" => Ok(SortField::Key),
+            "updated_at" => Ok(SortField::UpdatedAt),
+            "size" => Ok(SortField::Size),
+            _ => Err(ApiError::InvalidRequest(
+                "unknown sort field, supported fields are: key, updated_at, size".into(),
+            )
+            .into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum SortOrder {
+    Asc,
+    Desc,
+}
+
+impl FromStr for SortOrder {
+    type Err = Error;
+
+    fn from_str(order: &str) -> Result<Self> {
+        match order {
+            "asc" => Ok(SortOrder::Asc),
+            "desc" => Ok(SortOrder::Desc),
+            _ => Err(ApiError::InvalidRequest(
+                "unknown order, supported orders are: asc, desc".into(),
+            )
+            .into()),
+        }
+    }
+}
+
+/// How a page of keys was sorted. KV only lists keys by name, so sorting by anything else can only
+/// sort the keys in the page we've listed, which `scope` spells out for clients.
+#[derive(Debug, Serialize)]
+struct PageSort {
+    field: SortField,
+    order: SortOrder,
+    scope: &'static str,
+}
+
+impl PageSort {
+    fn new(field: SortField, order: SortOrder) -> Self {
+        Self {
+            field,
+            order,
+            scope: "page",
+        }
+    }
+
+    /// Sorts a page of listed keys. Keys missing the field are sorted before the ones that have it,
+    /// and keys with the same value are sorted by name.
+    fn sort(&self, keys: &mut [kv::Key]) {
+        let field = |key: &kv::Key| match self.field {
+            SortField::Key => None,
+            SortField::UpdatedAt => key_metadata_u64(key, "updated_at"),
+            SortField::Size => key_metadata_u64(key, "size"),
+        };
+
+        keys.sort_by(|a, b| {
+            let ordering = field(a).cmp(&field(b)).then_with(|| a.name.cmp(&b.name));
+            match self.order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        });
+    }
+}
+
+/// Turns a walk over a whole prefix into its last `limit` keys in descending order, or all of them
+/// when there's no limit. There's no cursor to carry on from, so this returns whether the listing
+/// was truncated: either the walk stopped before the end of the prefix, in which case these aren't
+/// really the last keys, or there were more keys than fit in the page.
+fn reverse_listing(list: &mut kv::ListResponse, limit: Option<u64>) -> bool {
+    list.keys.reverse();
+    let truncated =
+        !list.list_complete || limit.is_some_and(|limit| list.keys.len() as u64 > limit);
+    if let Some(limit) = limit {
+        list.keys.truncate(limit as usize);
+    }
+    list.list_complete = !truncated;
+    list.cursor = None;
+    truncated
+}
+
+/// Lists the keys under a prefix the way S3 does with a delimiter, where keys containing the
+/// delimiter after the prefix are collapsed into the common prefix up to and including it. That way
+/// `photos/2024/01/a.jpg` under `photos/` shows up as the `photos/2024/` prefix, like a directory.
+///
+/// A single page of keys can collapse into a single prefix, so we keep following cursors until we
+/// have `limit` keys and prefixes, the listing is complete, or we've read
+/// `DELIMITED_LIST_PAGE_LIMIT` pages. KV can't start listing after a given key, so a prefix whose
+/// keys span two pages can show up at the end of one page and the start of the next.
+async fn delimited_list(
+    store: &Store,
+    prefix: &str,
+    mut cursor: Option<String>,
+    limit: u64,
+    delimiter: &str,
+    matches: &dyn Fn(&kv::Key) -> bool,
+) -> Result<ListResult> {
+    let mut keys = Vec::new();
+    let mut common_prefixes: Vec<String> = Vec::new();
+    let mut complete = false;
+
+    for _ in 0..DELIMITED_LIST_PAGE_LIMIT {
+        let page = list_page(store, prefix, cursor, Some(limit)).await?;
+        for key in page.keys.into_iter().filter(|key| matches(key)) {
+            let rest = key.name.get(prefix.len()..).unwrap_or_default();
+            match rest.find(delimiter) {
+                Some(index) => {
+                    let common = &key.name[..prefix.len() + index + delimiter.len()];
+                    // Keys are listed in order, so keys sharing a prefix are always next to each other.
+                    if common_prefixes.last().map(String::as_str) != Some(common) {
+                        common_prefixes.push(common.to_string());
+                    }
+                }
+                None => keys.push(ListedKey::from(key)),
+            }
+        }
+
+        complete = page.list_complete;
+        cursor = page.cursor;
+        if complete || (keys.len() + common_prefixes.len()) as u64 >= limit {
+            break;
+        }
+    }
+
+    Ok(ListResult {
+        common_prefixes: Some(common_prefixes),
+        ..ListResult::new(keys, cursor, complete)
+    })
+}
+
+/// Which of the listed keys a client is shown.
+#[derive(Debug, Default)]
+struct ListFilter {
+    // The worker's own keys are only shown when asked for.
+    include_system: bool,
+    // Only keys in `[start, end)` are shown.
+    start: Option<String>,
+    end: Option<String>,
+    // Only keys with any of these content-types are shown, unless there are none.
+    content_types: Vec<String>,
+}
+
+impl ListFilter {
+    fn matches(&self, key: &kv::Key) -> bool {
+        // Soft deleted keys are still in the store, but as far as clients are concerned they're gone.
+        !is_tombstone(key)
+            && (self.include_system || !is_system_key(&key.name))
+            && self.start.as_ref().is_none_or(|start| key.name >= *start)
+            && self.end.as_ref().is_none_or(|end| key.name < *end)
+            && (self.content_types.is_empty()
+                || key_content_type(key).is_some_and(|content_type| {
+                    self.content_types.iter().any(|c| c == content_type)
+                }))
+    }
+}
+
+async fn list(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    authorize_private_read(&req, &ctx)?;
+
+    // Load the KV store binding by the name we specified above.
+    let store = Store::new(&ctx)?;
+
+    // Read any options we'd like to do to configure our list.
+    let url = req.url()?;
+    let limit = utils::param_parse(&url, "limit")?
+        .unwrap_or(100)
+        .min(MAX_LIST_PAGE_SIZE);
+    let prefix = utils::param_from(&url, "prefix")
+        .map(String::from)
+        .unwrap_or_default();
+    let cursor = utils::param_from(&url, "cursor").map(String::from);
+
+    let format = utils::param_from(&url, "format").unwrap_or_else(|| "json".into());
+
+    // Clients can narrow the list down to keys with any of the given content-types.
+    let content_types = utils::params_from(&url, "content_type");
+    let count = utils::param_from(&url, "count").is_some_and(|count| count == "true");
+
+    // KV can only list by prefix, so listing a range of keys means listing the prefix and dropping
+    // the keys outside of `[start, end)`.
+    let start = utils::param_from(&url, "start").map(String::from);
+    let end = utils::param_from(&url, "end").map(String::from);
+    if let (Some(start), Some(end)) = (&start, &end) {
+        if start > end {
+            return Err(ApiError::InvalidRequest("start must not be after end".into()).into());
+        }
+    }
+
+    // Pages can be sorted by their metadata, but only one page at a time.
+    let sort = utils::param_from(&url, "sort")
+        .map(|field| field.parse::<SortField>())
+        .transpose()?;
+    let order = utils::param_from(&url, "order")
+        .map(|order| order.parse::<SortOrder>())
+        .transpose()?
+        .unwrap_or(SortOrder::Asc);
+    let sort = sort.map(|field| PageSort::new(field, order));
+
+    // KV only lists keys in ascending order, so listing them in descending order without a field to
+    // sort a page by means walking the whole prefix and reversing it. The walk is bounded the same way
+    // as `all=1`, and there's no cursor to carry on from, so only the first page is returned.
+    let descending = sort.is_none() && order == SortOrder::Desc;
+    if descending && cursor.is_some() {
+        return Err(ApiError::InvalidRequest(
+            "cursor can't be combined with order=desc without sort".into(),
+        )
+        .into());
+    }
+
+    // Metadata makes up most of a listing, so clients that only want names and expirations can leave
+    // it out. KV always lists it, so we still use it to filter keys but don't send it on.
+    let omit_metadata =
+        utils::param_from(&url, "metadata").is_some_and(|metadata| metadata == "false");
+
+    // Small stores can be listed in one go rather than a page at a time.
+    let all = utils::param_from(&url, "all").is_some_and(|all| all == "1");
+
+    // Autocomplete and the like only need the names, so clients can ask for just those.
+    let keys_only = utils::param_from(&url, "keys_only")
+        .is_some_and(|keys_only| keys_only == "1" || keys_only == "true");
+
+    // The worker's own keys are only listed for trusted clients that ask for them.
+    let include_system =
+        utils::param_from(&url, "include_system").is_some_and(|include| include == "1");
+    if include_system && !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let filter = ListFilter {
+        include_system,
+        start,
+        end,
+        content_types,
+    };
+    let matches = |key: &kv::Key| filter.matches(key);
+
+    // Listing with a delimiter groups keys the way directories group files, which has its own shape.
+    let delimiter = utils::param_from(&url, "delimiter").filter(|delimiter| !delimiter.is_empty());
+    if let Some(delimiter) = delimiter {
+        if format != "json" || keys_only || sort.is_some() || descending || all {
+            return Err(ApiError::InvalidRequest(
+                "delimiter can't be combined with format=csv, keys_only, sort, order or all".into(),
+            )
+            .into());
+        }
+
+        let list = delimited_list(&store, &prefix, cursor, limit, &delimiter, &matches).await?;
+        let mut response = Response::from_json(&list)?;
+        if let Some(cursor) = &list.cursor {
+            let next = utils::with_param(&url, "cursor", cursor);
+            response
+                .headers_mut()
+                .set("link", &format!("<{}>; rel=\"next\"", next))?;
+        }
+        return Ok(response);
+    }
+
+    let mut list = if all || descending {
+        list_all(&store, &prefix, cursor, &matches).await?
+    } else {
+        list_page(&store, &prefix, cursor, Some(limit)).await?
+    };
+    list.keys.retain(|key| matches(key));
+    let mut truncated = all && !list.list_complete;
+    if descending {
+        truncated = reverse_listing(&mut list, (!all).then_some(limit));
+    }
+    let next_cursor = list.cursor.clone().filter(|_| !list.list_complete);
+    if let Some(sort) = &sort {
+        sort.sort(&mut list.keys);
+    }
+
+    let mut response = match format.as_ref() {
+        // The body is just an array of names, so the cursor has to go in a header instead.
+        "json" if keys_only => {
+            let names: Vec<&str> = list.keys.iter().map(|key| key.name.as_str()).collect();
+            let mut response = Response::from_json(&names)?;
+            if let Some(cursor) = &next_cursor {
+                response.headers_mut().set("x-cursor", cursor)?;
+            }
+            response
+        }
+        "json" => {
+            let mut result = ListResult {
+                truncated,
+                sort,
+                ..ListResult::from(list)
+            };
+            if omit_metadata {
+                result = result.without_metadata();
+            }
+            Response::from_json(&result)?
+        }
+        // Spreadsheets are much happier with CSV, so let's flatten the keys into rows.
+        "csv" => {
+            let mut csv = String::from("key,expiration,content_type\n");
+            for key in &list.keys {
+                let expiration = key.expiration.map(|e| e.to_string()).unwrap_or_default();
+                let content_type = key_content_type(key).unwrap_or_default();
+
+                csv.push_str(&format!(
+                    "{},{},{}\n",
+                    utils::csv_field(&key.name),
+                    expiration,
+                    utils::csv_field(content_type)
+                ));
+            }
+
+            let mut headers = Headers::default();
+            headers.append("content-type", "text/csv")?;
+            Response::ok(csv)?.with_headers(headers)
+        }
+        _ => {
+            return Err(ApiError::InvalidRequest(
+                "unknown format, supported formats are: json, csv".into(),
+            )
+            .into())
+        }
+    };
+
+    // Counting every key means walking the prefix rather than just this page, which can be slow for
+    // big prefixes, so it's only done when asked for. The count covers every key matching the prefix
+    // and filters, not just the ones in this page. The walk is bounded the same way as `all=1`, so for
+    // prefixes bigger than that the count is only how many keys there are at least, which is flagged
+    // with `x-total-count-partial`.
+    if count {
+        let counted = list_all(&store, &prefix, None, &matches).await?;
+        response
+            .headers_mut()
+            .set("x-total-count", &counted.keys.len().to_string())?;
+        if !counted.list_complete {
+            response
+                .headers_mut()
+                .set("x-total-count-partial", "true")?;
+        }
+    }
+
+    // Let's point clients at the next page using the same URL they used for this one, so they can
+    // page through without having to look at the body.
+    if let Some(cursor) = &next_cursor {
+        let next = utils::with_param(&url, "cursor", cursor);
+        response
+            .headers_mut()
+            .set("link", &format!("<{}>; rel=\"next\"", next))?;
+    }
+
+    Ok(response)
+}
+
+/// Gets the file uploaded in a `multipart/form-data` body, failing with a 400 unless there's exactly
+/// one file in it.
+fn multipart_file(content_type: &str, body: &[u8]) -> Result<utils::MultipartPart> {
+    let boundary = utils::header_param(content_type, "boundary").ok_or_else(|| {
+        Error::from(ApiError::InvalidRequest(
+            "multipart body is missing a boundary".into(),
+        ))
+    })?;
+
+    let mut files: Vec<_> = utils::parse_multipart(body, &boundary)?
+        .into_iter()
+        .filter(|part| part.filename.is_some())
+        .collect();
+    match files.len() {
+        1 => Ok(files.remove(0)),
+        count => Err(ApiError::InvalidRequest(format!(
+            "expected exactly one file, found {}",
+            count
+        ))
+        .into()),
+    }
+}
+
+async fn put(mut req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &write_key_param(&ctx)?;
+    // The body is read a chunk at a time so that uploads too big for KV are turned away without
+    // holding more than the limit in memory.
+    let utils::Body {
+        bytes: body,
+        checksum,
+    } = utils::read_body(&mut req, max_value_size(&ctx)?).await?;
+    let content_type = req.headers().get("content-type")?;
+
+    // Browsers upload files wrapped in a multipart form, which we don't want to store as is, so let's
+    // unwrap the file and use the content-type the browser gave it.
+    let (body, content_type, filename, checksum) = match content_type {
+        Some(content_type) if utils::media_type_essence(&content_type) == "multipart/form-data" => {
+            let file = multipart_file(&content_type, &body)?;
+            let checksum = utils::sha256_hex(&file.body);
+            (file.body, file.content_type, file.filename, checksum)
+        }
+        content_type => (body, content_type, None, checksum),
+    };
+
+    // A PUT without a body is far more likely to be a mistake than a deliberate empty value, so
+    // clients have to ask for an empty value to overwrite a key with one.
+    let url = req.url()?;
+    let allow_empty = utils::param_from(&url, "allow_empty").is_some_and(|allow| allow == "1");
+    if body.is_empty() && !allow_empty {
+        return Err(ApiError::InvalidRequest("empty body".into()).into());
+    }
+
+    // If none was sent we'll try to work out the content-type from the body, but an explicit
+    // content-type is always trusted over our guess.
+    let sniffed_content_type = match &content_type {
+        Some(_) => None,
+        None => utils::sniff_content_type(&body),
+    };
+
+    // If the client told us what the checksum of their body should be, let's make sure nothing got
+    // mangled along the way before we store it.
+    let size = body.len() as u64;
+    if let Some(expected) = req.headers().get("x-checksum-sha256")? {
+        if !expected.trim().eq_ignore_ascii_case(&checksum) {
+            return Err(ApiError::InvalidRequest("checksum mismatch".into()).into());
+        }
+    }
+
+    // Let's hold on to the value we're about to overwrite so the write can be undone, which also
+    // tells us if this write creates the key. Latency-sensitive clients can skip this read, at the
+    // cost of not knowing whether the key is new and not keeping the previous version.
+    let fast = utils::param_from(&url, "fast").is_some_and(|fast| fast == "true");
+    let custom = custom_metadata(req.headers())?;
+    let tags = utils::params_from(&url, "tag");
+    if tags.iter().any(|tag| tag.is_empty()) {
+        return Err(ApiError::InvalidRequest("tags must not be empty".into()).into());
+    }
+    // Overwriting a value keeps the metadata it had unless the request says otherwise, so a plain
+    // re-upload doesn't lose the content-type or tags. Clients that want to start from scratch can
+    // ask to replace the metadata instead.
+    let replace_metadata =
+        utils::param_from(&url, "replace_metadata").is_some_and(|replace| replace == "1");
+    let mut created = false;
+    let mut created_at = None;
+    let mut old_content_type = None;
+    let mut kept_metadata = None;
+    if !fast {
+        let replaced = replace_current(&ctx, &store, key).await?;
+        if replaced.existed {
+            created_at = replaced
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.created_at);
+            old_content_type = replaced
+                .metadata
+                .as_ref()
+                .map(|metadata| metadata.content_type.clone());
+            if !replace_metadata {
+                kept_metadata = replaced.metadata;
+            }
+        } else {
+            created = true;
+            created_at = Some(utils::now());
+        }
+    }
+
+    // Let's store the content-type in our metadata, preferring the one that was sent, then the one
+    // the value already had, then our guess.
+    let content_type = content_type
+        .or_else(|| {
+            kept_metadata
+                .as_ref()
+                .map(|metadata| metadata.content_type.clone())
+        })
+        .or(sniffed_content_type)
+        .map_or_else(|| default_content_type(&ctx), Ok)?;
+    let (filename, tags, custom) = match kept_metadata {
+        Some(kept) => (
+            filename.or(kept.filename),
+            if tags.is_empty() { kept.tags } else { tags },
+            if custom.is_empty() {
+                kept.custom
+            } else {
+                custom
+            },
+        ),
+        None => (filename, tags, custom),
+    };
+
+    let ttl = write_ttl(&ctx, &url)?;
+    let metadata = ExampleMetadata {
+        checksum: Some(checksum.clone()),
+        filename,
+        created_at,
+        tags,
+        custom,
+        ..ExampleMetadata::new(content_type.clone())
+    };
+    let (metadata, kv_duration) =
+        utils::timed(store_value(&ctx.env, &store, key, body, metadata, ttl)).await;
+    let metadata = metadata?;
+    let expiration = metadata.expiration;
+
+    // Let's move the key in the content-type index if its content-type changed. Without the old value
+    // we can't tell, so the key might be left listed under its old content-type too.
+    if let Some(old_content_type) = old_content_type {
+        if utils::media_type_essence(&old_content_type) != utils::media_type_essence(&content_type)
+        {
+            unindex_content_type(&store, key, &old_content_type).await;
+        }
+    }
+
+    let location = format!("/{}", utils::percent_encode(key));
+    let mut response = PutResponse {
+        ttl,
+        expiration,
+        metadata: Some(metadata),
+        ..PutResponse::new(key, size, &content_type, &checksum)
+    }
+    .into_response(&location, created)?;
+    response
+        .headers_mut()
+        .set("server-timing", &utils::server_timing("kv", kv_duration))?;
+    response.headers_mut().set("x-checksum-sha256", &checksum)?;
+
+    Ok(response)
+}
+
+/// The response to a write sent with an `Idempotency-Key` header, kept so a retry can be answered
+/// with it.
+#[derive(Debug, Serialize, Deserialize)]
+struct IdempotencyRecord {
+    // The hex SHA-256 of the method, path and body of the request, so reusing the key for a
+    // different request can be caught.
+    request_hash: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body_base64: String,
+}
+
+/// Runs a write handler at most once for each `Idempotency-Key`, answering any retry with the
+/// response the first request got. Reusing a key for a different request fails with a 422. Only
+/// successful responses are kept, so a write that failed can be retried with the same key.
+///
+/// KV can't update a key atomically, so two requests with the same key racing each other can both
+/// be run.
+async fn idempotent<F, Fut>(
+    req: Request,
+    ctx: RouteContext<Retries>,
+    handler: F,
+) -> Result<Response>
+where
+    F: FnOnce(Request, RouteContext<Retries>) -> Fut,
+    Fut: Future<Output = Result<Response>>,
+{
+    let idempotency_key = match req.headers().get("idempotency-key")? {
+        Some(idempotency_key) if !idempotency_key.is_empty() => idempotency_key,
+        _ => return handler(req, ctx).await,
+    };
+    // A retry of a write that did reach the store would be answered from the record anyway.
+    ctx.data.allow_puts();
+
+    // The handler needs the body too, so we hash a copy of the request.
+    let url = req.url()?;
+    let body = req.clone()?.bytes().await?;
+    let request_hash =
+        utils::sha256_hex(&[format!("{:?}\n{}\n", req.method(), url).as_bytes(), &body].concat());
+
+    let store = Store::new(&ctx)?;
+    let record_key = format!("{}{}", IDEMPOTENCY_KEY_PREFIX, idempotency_key);
+    if let Some(record) = store.get(&record_key).json::<IdempotencyRecord>().await? {
+        if record.request_hash != request_hash {
+            return Err(ApiError::IdempotencyKeyReused.into());
+        }
+
+        let mut headers = Headers::default();
+        for (name, value) in &record.headers {
+            headers.append(name, value)?;
+        }
+        headers.set("x-idempotent-replayed", "true")?;
+        let body =
+            base64::decode(&record.body_base64).map_err(|err| Error::RustError(err.to_string()))?;
+        return Ok(Response::from_bytes(body)?
+            .with_status(record.status)
+            .with_headers(headers));
+    }
+
+    let mut response = handler(req, ctx).await?;
+    if !(200..300).contains(&response.status_code()) {
+        return Ok(response);
+    }
+
+    let body = response.bytes().await?;
+    let record = IdempotencyRecord {
+        request_hash,
+        status: response.status_code(),
+        headers: response.headers().entries().collect(),
+        body_base64: base64::encode(&body),
+    };
+    store
+        .put_bytes(&record_key, &serde_json::to_vec(&record)?)?
+        .expiration_ttl(IDEMPOTENCY_TTL)
+        .execute()
+        .await?;
+
+    let headers = response.headers().clone();
+    Ok(Response::from_bytes(body)?
+        .with_status(record.status)
+        .with_headers(headers))
+}
+
+/// Describes a value that was just written.
+#[derive(Debug, Serialize)]
+struct PutResponse {
+    key: String,
+    size: u64,
+    content_type: String,
+    etag: String,
+    // When the value will expire, if it was written with a ttl.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration: Option<u64>,
+    // The document as it was stored, which can differ from the one that was sent once defaults are
+    // filled in and unknown fields are dropped.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<serde_json::Value>,
+    // The metadata that was stored along with the value, so clients don't have to read it back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<ExampleMetadata>,
+}
+
+impl PutResponse {
+    fn new(key: &str, size: u64, content_type: &str, checksum: &str) -> Self {
+        Self {
+            key: key.into(),
+            size,
+            content_type: content_type.into(),
+            etag: format!("\"{}\"", checksum),
+            ttl: None,
+            expiration: None,
+            value: None,
+            metadata: None,
+        }
+    }
+
+    /// Builds the response to a write, which is a 201 when it created the key and a 200 when it
+    /// overwrote one, pointing at where the value can be read back from.
+    fn into_response(self, location: &str, created: bool) -> Result<Response> {
+        let mut response = Response::from_json(&self)?.with_status(if created { 201 } else { 200 });
+        response.headers_mut().set("location", location)?;
+        response.headers_mut().set("etag", &self.etag)?;
+        Ok(response)
+    }
+}
+
+/// The body of `PUT /:key/with-meta`, which carries the value along with metadata that would be
+/// awkward to send as headers.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WithMetaBody {
+    value_base64: String,
+    content_type: Option<String>,
+    metadata: Option<serde_json::Value>,
+}
+
+/// Stores a base64 encoded value along with arbitrary JSON metadata, which is handed back as is by
+/// `GET /meta/:key`. Otherwise the value is written the way `PUT /:key` writes it.
+async fn put_with_meta(mut req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &write_key_param(&ctx)?;
+    let body: WithMetaBody = match req.json().await {
+        Ok(body) => body,
+        Err(_) => return Err(ApiError::InvalidBody(None).into()),
+    };
+    let value = match base64::decode(&body.value_base64) {
+        Ok(value) => value,
+        Err(_) => {
+            return Err(ApiError::InvalidRequest("value_base64 isn't valid base64".into()).into())
+        }
+    };
+
+    let ttl = write_ttl(&ctx, &req.url()?)?;
+
+    let content_type = body
+        .content_type
+        .or_else(|| utils::sniff_content_type(&value))
+        .map_or_else(|| default_content_type(&ctx), Ok)?;
+    let checksum = utils::sha256_hex(&value);
+    let size = value.len() as u64;
+    let mut metadata = ExampleMetadata {
+        checksum: Some(checksum.clone()),
+        size: Some(size),
+        extra: body.metadata,
+        ..ExampleMetadata::new(content_type.clone())
+    };
+    // The metadata is the part most likely to be too big here, so let's find out before writing
+    // anything.
+    metadata.check_size()?;
+
+    let replaced = replace_current(&ctx, &store, key).await?;
+    let created = !replaced.existed;
+    metadata.created_at = match &replaced.metadata {
+        Some(old) => old.created_at,
+        None if created => metadata.updated_at,
+        None => None,
+    };
+    let metadata = store_value(&ctx.env, &store, key, value, metadata, ttl).await?;
+    let expiration = metadata.expiration;
+
+    if let Some(old) = &replaced.metadata {
+        if utils::media_type_essence(&old.content_type) != utils::media_type_essence(&content_type)
+        {
+            unindex_content_type(&store, key, &old.content_type).await;
+        }
+    }
+
+    let location = format!("/{}", utils::percent_encode(key));
+    PutResponse {
+        ttl,
+        expiration,
+        metadata: Some(metadata),
+        ..PutResponse::new(key, size, &content_type, &checksum)
+    }
+    .into_response(&location, created)
+}
+
+/// Checks that a request is allowed to read `key`, failing with a 401 if reads are private and the
+/// request isn't authorized, or a 403 if it has a bad signature.
+fn authorize_read(req: &Request, ctx: &RouteContext<Retries>, key: &str) -> Result<()> {
+    // A signed URL lets anyone holding it read the key until it expires, even when reads are private.
+    // Let's check the signature whenever there is one so that a tampered link is never silently
+    // treated as a plain read.
+    let url = req.url()?;
+    match utils::param_from(&url, "signature") {
+        Some(signature) => verify_signed_read(ctx, &url, key, &signature),
+        None => authorize_private_read(req, ctx),
+    }
+}
+
+/// Checks that a request is allowed to read from the store, failing with a 401 if reads are private
+/// and the request doesn't have the write token. A signed URL only grants access to the key it was
+/// signed for, so endpoints that read or list several keys at once can't be used with one.
+fn authorize_private_read(req: &Request, ctx: &RouteContext<Retries>) -> Result<()> {
+    let private = utils::var(ctx, PRIVATE_READS_VAR).is_some_and(|private| private == "true");
+    if private && !write_authorized(req, ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    Ok(())
+}
+
+async fn get(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+
+    authorize_read(&req, &ctx, key)?;
+
+    // Let's keep track of how long the store takes to answer so we can report it to the client.
+    let (result, kv_duration) = utils::timed(store.get_stream(key)).await;
+    let (maybe_stream, maybe_metadata) = result?;
+
+    // Values changed behind the worker's back can't be trusted, and that's the store's fault rather
+    // than ours or the client's.
+    let tombstone = maybe_metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.deleted_at.is_some());
+    if maybe_stream.is_some() && !tombstone {
+        verify_metadata_signature(&ctx.env, maybe_metadata.as_ref())?;
+    }
+
+    // Big values are passed on as they arrive from the store, rather than being held in memory until
+    // all of it has been read, as long as we don't need the whole value to answer.
+    if let (Some(stream), Some(metadata)) = (&maybe_stream, &maybe_metadata) {
+        if streamable(&req, metadata)? {
+            let mut response = stream_response(&req, stream.clone(), metadata)?;
+            response
+                .headers_mut()
+                .set("server-timing", &utils::server_timing("kv", kv_duration))?;
+            return Ok(response);
+        }
+    }
+
+    // Otherwise we read the value in full, which lets us check its checksum, decrypt it, compress it
+    // or send part of it.
+    let maybe_value = match maybe_stream {
+        Some(stream) => Some(
+            Response::from(worker_sys::Response::new_with_opt_stream(Some(&stream))?)
+                .bytes()
+                .await?,
+        ),
+        None => None,
+    };
+    let (maybe_value, maybe_metadata) = follow_blob(&store, maybe_value, maybe_metadata).await?;
+
+    let maybe_value = match (maybe_value, &maybe_metadata) {
+        (Some(value), Some(metadata)) => match decrypt_value(&ctx.env, value, metadata) {
+            Ok(value) => Some(value),
+            Err(err) => return Err(ApiError::Internal(err.to_string()).into()),
+        },
+        (maybe_value, _) => maybe_value,
+    };
+
+    // Clients can say what they'd like instead of a 404 for a missing key, which saves them from
+    // handling the missing case themselves. The header lets them tell it apart from a stored value.
+    let missing = maybe_value.is_none()
+        || maybe_metadata
+            .as_ref()
+            .is_some_and(|metadata| metadata.deleted_at.is_some());
+    let url = req.url()?;
+    let mut response = match utils::param_from(&url, "default") {
+        Some(default) if missing => {
+            let mut headers = Headers::default();
+            headers.append("content-type", "text/plain; charset=utf-8")?;
+            headers.append("x-default", "true")?;
+            Response::ok(default.into_owned())?.with_headers(headers)
+        }
+        _ => value_response(&req, maybe_value, maybe_metadata)?,
+    };
+    response
+        .headers_mut()
+        .set("server-timing", &utils::server_timing("kv", kv_duration))?;
+
+    Ok(response)
+}
+
+/// Whether a value can be streamed straight from the store. We can't stream values that have to be
+/// decrypted, are stored in a blob, or would be compressed or cut down to a range, since all of those
+/// need the whole value. Small values aren't worth it, as they're read quickly anyway.
+fn streamable(req: &Request, metadata: &ExampleMetadata) -> Result<bool> {
+    let large = metadata.size.is_some_and(|size| size >= STREAM_THRESHOLD);
+    if !large
+        || metadata.deleted_at.is_some()
+        || metadata.nonce.is_some()
+        || metadata.blob.is_some()
+        || req.headers().has("range")?
+    {
+        return Ok(false);
+    }
+
+    let raw = utils::param_from(&req.url()?, "raw").is_some_and(|raw| raw == "1");
+    let compress = !raw
+        && utils::is_compressible(&metadata.content_type)
+        && utils::negotiate_encoding(req).name().is_some();
+
+    Ok(!compress)
+}
+
+/// Passes a value on to the client as it's read from the store. We never have all of it at once, so
+/// its checksum is checked as it goes, and a value that doesn't match ends with an error in place of
+/// its last chunk.
+fn stream_response(
+    req: &Request,
+    stream: web_sys::ReadableStream,
+    metadata: &ExampleMetadata,
+) -> Result<Response> {
+    let mut headers = Headers::default();
+    append_metadata_headers(&mut headers, metadata)?;
+    if not_modified(req, metadata)? {
+        return Ok(Response::empty()?.with_status(304).with_headers(headers));
+    }
+
+    headers.append("content-type", &metadata.content_type)?;
+    headers.append("accept-ranges", "bytes")?;
+    headers.append("vary", "accept-encoding")?;
+    if let Some(size) = metadata.size {
+        headers.append("content-length", &size.to_string())?;
+    }
+
+    let mut response = Response::from(worker_sys::Response::new_with_opt_stream(Some(&stream))?);
+    let response = match &metadata.checksum {
+        Some(checksum) => {
+            Response::from_stream(utils::verify_checksum(response.stream()?, checksum.clone()))?
+        }
+        None => response,
+    };
+    Ok(response.with_headers(headers))
+}
+
+/// Whether the client already has a copy of the value that's at least as new as ours, in which case
+/// there's no need to send it again. An If-None-Match header takes precedence over
+/// If-Modified-Since, so we only look at the date when there isn't one.
+fn not_modified(req: &Request, metadata: &ExampleMetadata) -> Result<bool> {
+    let updated_at = match metadata.updated_at {
+        Some(updated_at) => updated_at,
+        None => return Ok(false),
+    };
+
+    Ok(!req.headers().has("if-none-match")?
+        && req
+            .headers()
+            .get("if-modified-since")?
+            .and_then(|since| utils::parse_http_date(&since))
+            .is_some_and(|since| updated_at <= since))
+}
+
+#[derive(Debug, Serialize)]
+struct SignResponse {
+    url: String,
+    expires: u64,
+}
+
+/// Creates a URL that can be handed out to let anyone read a key for the next `expires` seconds.
+async fn sign(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let key = &read_key_param(&ctx)?;
+    let signing_key = match signing_key(&ctx) {
+        Some(signing_key) => signing_key,
+        None => return Err(ApiError::NotImplemented("no signing key is configured".into()).into()),
+    };
+
+    let mut url = req.url()?;
+    let expires_in: u64 = utils::param_required(&url, "expires")?;
+    if expires_in == 0 {
+        return Err(ApiError::InvalidRequest("expires must be at least 1 second".into()).into());
+    }
+    let expires = utils::now() + expires_in;
+    let signature = utils::hmac_sha256_hex(&signing_key, &signed_read_message(key, expires));
+
+    url.set_path(&format!("/{}", utils::percent_encode(key)));
+    url.query_pairs_mut()
+        .clear()
+        .append_pair("expires", &expires.to_string())
+        .append_pair("signature", &signature);
+
+    Response::from_json(&SignResponse {
+        url: url.into(),
+        expires,
+    })
+}
+
+/// Reads the version of a key that was overwritten by the latest put.
+async fn previous(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+    authorize_read(&req, &ctx, key)?;
+
+    let (maybe_value, maybe_metadata) = read_value(&store, &previous_key(key)).await?;
+    if maybe_value.is_some() {
+        verify_metadata_signature(&ctx.env, maybe_metadata.as_ref())?;
+    }
+
+    let maybe_value = match (maybe_value, &maybe_metadata) {
+        (Some(value), Some(metadata)) => match decrypt_value(&ctx.env, value, metadata) {
+            Ok(value) => Some(value),
+            Err(err) => return Err(ApiError::Internal(err.to_string()).into()),
+        },
+        (None, _) => return Err(ApiError::NotFound("no previous version found".into()).into()),
+        (maybe_value, _) => maybe_value,
+    };
+
+    value_response(&req, maybe_value, maybe_metadata)
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryResponse {
+    key: String,
+    versions: Vec<HistoryVersion>,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryVersion {
+    version: u64,
+    // When the version was written, if we know it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    updated_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+}
+
+/// Lists the older versions of a key kept in its history, oldest first.
+async fn history(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+    authorize_read(&req, &ctx, key)?;
+
+    let versions = history_versions(&store, key)
+        .await?
+        .into_iter()
+        .map(|(version, metadata)| match metadata {
+            Some(metadata) => HistoryVersion {
+                version,
+                updated_at: metadata.updated_at,
+                size: metadata.size,
+                content_type: Some(metadata.content_type),
+                checksum: metadata.checksum,
+            },
+            None => HistoryVersion {
+                version,
+                updated_at: None,
+                size: None,
+                content_type: None,
+                checksum: None,
+            },
+        })
+        .collect();
+
+    Response::from_json(&HistoryResponse {
+        key: key.into(),
+        versions,
+    })
+}
+
+/// Reads a version of a key from its history.
+async fn history_version(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+    authorize_read(&req, &ctx, key)?;
+
+    let version = ctx
+        .param("version")
+        .and_then(|version| version.parse().ok())
+        .ok_or_else(|| ApiError::InvalidRequest("invalid version".into()))?;
+    let (value, metadata) = read_value(&store, &history_key(key, version)).await?;
+    if value.is_some() {
+        verify_metadata_signature(&ctx.env, metadata.as_ref())?;
+    }
+
+    let value = match (value, &metadata) {
+        (Some(value), Some(metadata)) => match decrypt_value(&ctx.env, value, metadata) {
+            Ok(value) => Some(value),
+            Err(err) => return Err(ApiError::Internal(err.to_string()).into()),
+        },
+        (None, _) => return Err(ApiError::NotFound("version not found".into()).into()),
+        (value, _) => value,
+    };
+
+    value_response(&req, value, metadata)
+}
+
+/// Reads the value of a key as UTF-8 text, failing with a 422 that says where the first invalid
+/// byte is rather than sending back garbled text. Values stored with some other charset are sent as
+/// they are, labelled with their charset.
+async fn text(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+    authorize_read(&req, &ctx, key)?;
+
+    let (value, metadata) = match read_value(&store, key).await? {
+        (Some(_), Some(metadata)) if metadata.deleted_at.is_some() => {
+            return Err(ApiError::KeyNotFound.into())
+        }
+        (Some(value), Some(metadata)) => {
+            verify_metadata_signature(&ctx.env, Some(&metadata))?;
+            let value = match decrypt_value(&ctx.env, value, &metadata) {
+                Ok(value) => value,
+                Err(err) => return Err(ApiError::Internal(err.to_string()).into()),
+            };
+            check_checksum(&value, &metadata)?;
+            (value, Some(metadata))
+        }
+        (Some(value), None) => {
+            verify_metadata_signature(&ctx.env, None)?;
+            (value, None)
+        }
+        (None, _) => return Err(ApiError::KeyNotFound.into()),
+    };
+
+    let charset = metadata
+        .and_then(|metadata| utils::header_param(&metadata.content_type, "charset"))
+        .unwrap_or_else(|| "utf-8".into());
+    if charset.eq_ignore_ascii_case("utf-8") {
+        if let Err(err) = std::str::from_utf8(&value) {
+            return Ok(Response::from_json(&serde_json::json!({
+                "error": "value isn't valid UTF-8",
+                "offset": err.valid_up_to(),
+            }))?
+            .with_status(422));
+        }
+    }
+
+    let mut headers = Headers::default();
+    headers.append("content-type", &format!("text/plain; charset={}", charset))?;
+    Ok(Response::from_bytes(value)?.with_headers(headers))
+}
+
+#[derive(Debug, Serialize)]
+struct InspectResponse {
+    key: String,
+    size: u64,
+    sha256: String,
+    expiration: Option<u64>,
+    metadata: Option<ExampleMetadata>,
+    // How many bytes from the start of the value `hex` and `text` show.
+    bytes: usize,
+    hex: String,
+    text: String,
+}
+
+/// Describes what's really stored in a key for debugging, showing the start of the value as a hex
+/// dump and as text.
+async fn inspect(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+    authorize_read(&req, &ctx, key)?;
+
+    let url = req.url()?;
+    let bytes = utils::param_parse(&url, "bytes")?
+        .unwrap_or(INSPECT_DEFAULT_BYTES)
+        .min(INSPECT_MAX_BYTES);
+
+    let (value, metadata) = match read_value(&store, key).await? {
+        (Some(_), Some(metadata)) if metadata.deleted_at.is_some() => {
+            return Err(ApiError::KeyNotFound.into())
+        }
+        (Some(value), Some(metadata)) => {
+            verify_metadata_signature(&ctx.env, Some(&metadata))?;
+            let value = match decrypt_value(&ctx.env, value, &metadata) {
+                Ok(value) => value,
+                Err(err) => return Err(ApiError::Internal(err.to_string()).into()),
+            };
+            check_checksum(&value, &metadata)?;
+            (value, Some(metadata))
+        }
+        (Some(value), None) => {
+            verify_metadata_signature(&ctx.env, None)?;
+            (value, None)
+        }
+        (None, _) => return Err(ApiError::KeyNotFound.into()),
+    };
+    let expiration = listed_key(&store, key)
+        .await?
+        .and_then(|listed| listed.expiration);
+
+    let start = &value[..bytes.min(value.len())];
+    Response::from_json(&InspectResponse {
+        key: key.into(),
+        size: value.len() as u64,
+        sha256: utils::sha256_hex(&value),
+        expiration,
+        metadata,
+        bytes: start.len(),
+        hex: utils::hex_dump(start),
+        text: String::from_utf8_lossy(start).into_owned(),
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct AppendResponse {
+    key: String,
+    size: u64,
+}
+
+/// Adds the request body to the end of a key's value, which is handy for building up things like
+/// logs, creating the key if it doesn't exist yet. The value keeps its content-type and expiration.
+///
+/// KV can't update a key atomically, so two appends racing for the same key can lose one of them.
+/// Every append also reads and writes the whole value, and values can't grow past `MAX_VALUE_SIZE`,
+/// so this is only practical for values that stay fairly small.
+async fn append(mut req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &write_key_param(&ctx)?;
+    let max_value_size = max_value_size(&ctx)?;
+    let body = utils::read_body(&mut req, max_value_size).await?.bytes;
+
+    let (old_value, old_metadata) = match read_value(&store, key).await? {
+        (Some(_), Some(metadata)) if metadata.deleted_at.is_some() => (None, None),
+        (Some(value), Some(metadata)) => match decrypt_value(&ctx.env, value, &metadata) {
+            Ok(value) => (Some(value), Some(metadata)),
+            Err(err) => return Err(ApiError::Internal(err.to_string()).into()),
+        },
+        (value, _) => (value, None),
+    };
+    let created = old_value.is_none();
+
+    let mut value = old_value.unwrap_or_default();
+    if value.len() + body.len() > max_value_size {
+        return Err(ApiError::PayloadTooLarge(format!(
+            "the value would be larger than the limit of {} bytes",
+            max_value_size
+        ))
+        .into());
+    }
+    value.extend_from_slice(&body);
+
+    let content_type = old_metadata
+        .as_ref()
+        .map(|metadata| metadata.content_type.clone())
+        .or_else(|| req.headers().get("content-type").ok().flatten())
+        .map_or_else(|| default_content_type(&ctx), Ok)?;
+    let size = value.len() as u64;
+
+    // The appended value is written the way `PUT /:key` writes values, keeping the one it replaces
+    // as the previous version. It keeps the expiration the key already had, while a new key expires
+    // the way a put would.
+    let now = utils::now();
+    let ttl = match &old_metadata {
+        Some(old) => kept_ttl(old.expiration, now),
+        None if created => write_ttl(&ctx, &req.url()?)?,
+        None => None,
+    };
+    let metadata = match old_metadata {
+        Some(old) => ExampleMetadata {
+            checksum: None,
+            updated_at: Some(now),
+            ..old
+        },
+        None => ExampleMetadata {
+            created_at: Some(now).filter(|_| created),
+            ..ExampleMetadata::new(content_type)
+        },
+    };
+    replace_current(&ctx, &store, key).await?;
+    store_value(&ctx.env, &store, key, value, metadata, ttl).await?;
+
+    let response = Response::from_json(&AppendResponse {
+        key: key.into(),
+        size,
+    })?;
+    Ok(response.with_status(if created { 201 } else { 200 }))
+}
+
+/// Rolls a key back to its previous version. The current value becomes the new previous version,
+/// so restoring twice gets you back to where you started. The restored value is written the way a put
+/// writes it, with the ttl asked for with `?ttl=` or else the default one.
+async fn restore(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &write_key_param(&ctx)?;
+    let previous = previous_key(key);
+    let ttl = write_ttl(&ctx, &req.url()?)?;
+
+    let (previous_value, previous_metadata) = store
+        .get(&previous)
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
+    let previous_value = match previous_value {
+        Some(value) => value,
+        None => return Err(ApiError::NotFound("no previous version found".into()).into()),
+    };
+    // The previous version can point at a blob or be encrypted, so let's get the value itself back
+    // to write it again the way a put would.
+    let (previous_value, previous_metadata) =
+        follow_blob(&store, Some(previous_value), previous_metadata).await?;
+    let previous_value = previous_value.unwrap_or_default();
+    let (previous_value, previous_metadata) = match previous_metadata {
+        Some(metadata) => (
+            decrypt_value(&ctx.env, previous_value, &metadata)?,
+            metadata,
+        ),
+        None => (
+            previous_value,
+            ExampleMetadata::new(default_content_type(&ctx)?),
+        ),
+    };
+
+    let (current_value, current_metadata) = store
+        .get(key)
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
+    let deleted = current_metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.deleted_at.is_some());
+
+    let metadata = ExampleMetadata {
+        checksum: None,
+        created_at: current_metadata
+            .as_ref()
+            .filter(|_| !deleted)
+            .and_then(|current| current.created_at)
+            .or(previous_metadata.created_at),
+        updated_at: Some(utils::now()),
+        deleted_at: None,
+        ..previous_metadata
+    };
+    let metadata = store_value(&ctx.env, &store, key, previous_value, metadata, ttl).await?;
+
+    // The restored value holds its own reference to the blob now, so the previous version can let go
+    // of its one. The current value takes its place, unless there isn't one or it's a tombstone, in
+    // which case nothing is left to restore to afterwards.
+    match current_value {
+        Some(current_value) if !deleted => {
+            keep_previous(&ctx, &store, key, &current_value, current_metadata.as_ref()).await?;
+        }
+        _ => {
+            drop_previous(&store, key).await?;
+            if let Some(blob) = current_metadata
+                .as_ref()
+                .and_then(|metadata| metadata.blob.as_ref())
+            {
+                release_blob(&store, blob).await?;
+            }
+        }
+    }
+    if let Some(current) = current_metadata.filter(|_| !deleted) {
+        if utils::media_type_essence(&current.content_type)
+            != utils::media_type_essence(&metadata.content_type)
+        {
+            unindex_content_type(&store, key, &current.content_type).await;
+        }
+    }
+
+    Response::ok("restored")
+}
+
+/// Rolls a key back to a version from its history. The value being replaced is kept the way a put
+/// keeps it, as the previous version and in the history, so a rollback can be undone too.
+async fn rollback(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &write_key_param(&ctx)?;
+    let url = req.url()?;
+    let version = utils::param_required(&url, "version")?;
+    let ttl = write_ttl(&ctx, &url)?;
+
+    let (value, metadata) = store
+        .get(&history_key(key, version))
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
+    let value = match value {
+        Some(value) => value,
+        None => return Err(ApiError::NotFound("version not found".into()).into()),
+    };
+    // Versions in the history hold the value itself rather than pointing at a blob, but they're
+    // still encrypted if the value was.
+    let (value, metadata) = match metadata {
+        Some(metadata) => (decrypt_value(&ctx.env, value, &metadata)?, metadata),
+        None => (value, ExampleMetadata::new(default_content_type(&ctx)?)),
+    };
+
+    let current_metadata = replace_current(&ctx, &store, key).await?.metadata;
+
+    // The version becomes a new write, which keeps the key's creation time.
+    let metadata = ExampleMetadata {
+        checksum: None,
+        created_at: current_metadata
+            .as_ref()
+            .and_then(|current| current.created_at)
+            .or(metadata.created_at),
+        updated_at: Some(utils::now()),
+        deleted_at: None,
+        ..metadata
+    };
+    let metadata = store_value(&ctx.env, &store, key, value, metadata, ttl).await?;
+
+    if let Some(current) = &current_metadata {
+        if utils::media_type_essence(&current.content_type)
+            != utils::media_type_essence(&metadata.content_type)
+        {
+            unindex_content_type(&store, key, &current.content_type).await;
+        }
+    }
+
+    Response::from_json(&MetaResponse {
+        key: key.clone(),
+        expiration: metadata.expiration,
+        metadata: Some(metadata),
+    })
+}
+
+/// Reads what a counter's key holds the way `GET /:key` would, checking its integrity and decrypting
+/// it, along with the metadata it was stored with. A soft deleted key has no value, but its metadata
+/// is still returned so that its blob can be released when the counter is written.
+async fn read_counter(
+    env: &Env,
+    store: &Store,
+    key: &str,
+) -> Result<(Option<Vec<u8>>, Option<ExampleMetadata>)> {
+    let (value, metadata) = read_value(store, key).await?;
+    let deleted = metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.deleted_at.is_some());
+
+    let value = match (value, &metadata) {
+        (Some(_), _) if deleted => None,
+        (Some(value), metadata) => {
+            verify_metadata_signature(env, metadata.as_ref())?;
+            match metadata {
+                Some(metadata) => match decrypt_value(env, value, metadata) {
+                    Ok(value) => Some(value),
+                    Err(err) => return Err(ApiError::Internal(err.to_string()).into()),
+                },
+                None => Some(value),
+            }
+        }
+        (None, _) => None,
+    };
+
+    Ok((value, metadata))
+}
+
+/// The value of a counter held in a key, which is 0 for a key that doesn't exist yet.
+fn counter_value(stored: Option<&[u8]>) -> Result<i64> {
+    match stored {
+        Some(value) => std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.trim().parse().ok())
+            .ok_or_else(|| ApiError::Conflict("key doesn't hold a counter".into()).into()),
+        None => Ok(0),
+    }
+}
+
+/// Adds `by` to a counter, failing with a 409 rather than wrapping around.
+fn next_counter_value(value: i64, by: i64) -> Result<i64> {
+    value
+        .checked_add(by)
+        .ok_or_else(|| ApiError::Conflict("counter would overflow".into()).into())
+}
+
+/// Writes the value of a counter as plain text, so it can be read with `GET /:key` like any other
+/// value. It's written the way `PUT /:key` writes values, keeping the metadata and expiration the
+/// key already had, given as `old` by [read_counter]. A new counter expires after `DEFAULT_TTL`, if
+/// it's set.
+async fn write_counter(
+    env: &Env,
+    store: &Store,
+    key: &str,
+    value: i64,
+    old: Option<ExampleMetadata>,
+) -> Result<ExampleMetadata> {
+    let now = utils::now();
+    let live = old
+        .as_ref()
+        .filter(|metadata| metadata.deleted_at.is_none());
+
+    let (metadata, ttl) = match live {
+        Some(live) => {
+            let ttl = kept_ttl(live.expiration, now);
+            let metadata = ExampleMetadata {
+                checksum: None,
+                updated_at: Some(now),
+                ..live.clone()
+            };
+            (metadata, ttl)
+        }
+        None => {
+            let default = env.var(DEFAULT_TTL_VAR).ok().map(|ttl| ttl.to_string());
+            let metadata = ExampleMetadata {
+                created_at: Some(now),
+                ..ExampleMetadata::new("text/plain; charset=utf-8")
+            };
+            (metadata, resolve_ttl(None, default)?)
+        }
+    };
+
+    let body = value.to_string().into_bytes();
+    let metadata = store_value(env, store, key, body, metadata, ttl).await?;
+
+    // Nothing points at the old value's blob anymore, as counters don't keep a previous version.
+    if let Some(blob) = old.and_then(|old| old.blob) {
+        release_blob(store, &blob).await?;
+    }
+
+    Ok(metadata)
+}
+
+#[derive(Debug, Serialize)]
+struct CounterResponse {
+    key: String,
+    value: i64,
+    // Whether the increment went through the counter's Durable Object, in which case no other
+    // increment can have been lost.
+    consistent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    warning: Option<String>,
+}
+
+/// Adds `?by=` to a counter, 1 by default. Reading and writing KV isn't atomic, so increments made
+/// at the same time can be lost, unless `?consistent=1` is given and a `COUNTER` Durable Object is
+/// bound, which keeps the authoritative value and copies it back to KV every few seconds. Without
+/// the binding the increment falls back to KV and the response says so.
+async fn increment(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let key = &write_key_param(&ctx)?;
+    let url = req.url()?;
+    let by = utils::param_parse::<i64>(&url, "by")?.unwrap_or(1);
+    let consistent =
+        utils::param_from(&url, "consistent").is_some_and(|consistent| consistent == "1");
+    let store = Store::new(&ctx)?;
+
+    let mut warning = None;
+    if consistent {
+        match ctx.durable_object(COUNTER_BINDING_NAME) {
+            Ok(namespace) => {
+                // Each counter gets its own object, named after the key as it's stored so that
+                // deployments sharing a namespace don't share counters.
+                let name = utils::scope_key(&store.prefix, key);
+                let value = counter::increment(&namespace, &name, key, by).await?;
+                return Response::from_json(&CounterResponse {
+                    key: key.clone(),
+                    value,
+                    consistent: true,
+                    warning: None,
+                });
+            }
+            Err(_) => {
+                warning = Some(
+                    "no counter Durable Object is configured, the increment went to KV and may race with others"
+                        .into(),
+                )
+            }
+        }
+    }
+
+    let (stored, metadata) = read_counter(&ctx.env, &store, key).await?;
+    let value = next_counter_value(counter_value(stored.as_deref())?, by)?;
+    write_counter(&ctx.env, &store, key, value, metadata).await?;
+
+    Response::from_json(&CounterResponse {
+        key: key.clone(),
+        value,
+        consistent: false,
+        warning,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct TouchResponse {
+    key: String,
+    expiration: u64,
+}
+
+/// Pushes a key's expiration out without the client having to send the value again, which is handy
+/// for things like sessions that should only expire once they stop being used.
+async fn touch(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+
+    let url = req.url()?;
+    let ttl: u64 = utils::param_required(&url, "ttl")?;
+    if ttl < MIN_TTL {
+        return Err(
+            ApiError::InvalidRequest(format!("ttl must be at least {} seconds", MIN_TTL)).into(),
+        );
+    }
+
+    let (value, metadata) = store
+        .get(key)
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
+
+    let (value, metadata) = match (value, metadata) {
+        (Some(_), Some(metadata)) if metadata.deleted_at.is_some() => {
+            return Err(ApiError::KeyNotFound.into())
+        }
+        (Some(value), metadata) => (value, metadata),
+        (None, _) => return Err(ApiError::KeyNotFound.into()),
+    };
+
+    // Keys without metadata stay that way, we don't want to make up a content-type for them.
+    let expiration = utils::now() + ttl;
+    let metadata = metadata.map(|metadata| ExampleMetadata {
+        expiration: Some(expiration),
+        ..metadata
+    });
+    write_value(&store, key, &value, metadata.as_ref(), Some(ttl)).await?;
+    if let Some(metadata) = &metadata {
+        refresh_indexes(&ctx.env, &store, key, value, metadata, Some(ttl)).await;
+    }
+
+    Response::from_json(&TouchResponse {
+        key: key.into(),
+        expiration,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct PrefixTtlResponse {
+    updated: u64,
+    expiration: u64,
+    cursor: Option<String>,
+    complete: bool,
+}
+
+/// Gives every key under a prefix a fresh expiration, like [touch] does for a single key. KV can't
+/// change a key's expiration without writing it again, so each key costs a read and a write, and only
+/// `PREFIX_TTL_KEY_LIMIT` keys are updated per request. The rest can be updated by calling this again
+/// with the returned cursor.
+async fn prefix_ttl(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let store = Store::new(&ctx)?;
+    let prefix = utils::percent_decode(ctx.param("prefix").unwrap())?;
+
+    let url = req.url()?;
+    let ttl: u64 = utils::param_required(&url, "seconds")?;
+    if ttl < MIN_TTL {
+        return Err(
+            ApiError::InvalidRequest(format!("seconds must be at least {}", MIN_TTL)).into(),
+        );
+    }
+    let cursor = utils::param_from(&url, "cursor").map(String::from);
+
+    let page = list_page(&store, &prefix, cursor, Some(PREFIX_TTL_KEY_LIMIT)).await?;
+    let expiration = utils::now() + ttl;
+    let mut updated = 0;
+    for key in page
+        .keys
+        .iter()
+        .filter(|key| !is_system_key(&key.name) && !is_tombstone(key))
+    {
+        let (value, metadata) = store
+            .get(&key.name)
+            .bytes_with_metadata::<ExampleMetadata>()
+            .await?;
+        let value = match value {
+            Some(value) => value,
+            None => continue,
+        };
+
+        let metadata = metadata.map(|metadata| ExampleMetadata {
+            expiration: Some(expiration),
+            ..metadata
+        });
+        write_value(&store, &key.name, &value, metadata.as_ref(), Some(ttl)).await?;
+        if let Some(metadata) = &metadata {
+            refresh_indexes(&ctx.env, &store, &key.name, value, metadata, Some(ttl)).await;
+        }
+        updated += 1;
+    }
+
+    let complete = page.list_complete;
+    Response::from_json(&PrefixTtlResponse {
+        updated,
+        expiration,
+        cursor: page.cursor.filter(|_| !complete),
+        complete,
+    })
+}
+
+/// Adds the headers describing a value's metadata to a response.
+fn append_metadata_headers(headers: &mut Headers, metadata: &ExampleMetadata) -> Result<()> {
+    if let Some(checksum) = &metadata.checksum {
+        headers.append("x-checksum-sha256", checksum)?;
+        // The checksum changes whenever the value does, which makes it a fine version to use with
+        // `If-Match`.
+        headers.append("etag", &format!("\"{}\"", checksum))?;
+    }
+    if let Some(size) = metadata.size {
+        headers.append("x-content-length", &size.to_string())?;
+    }
+    if let Some(created_at) = metadata.created_at {
+        headers.append("x-created-at", &created_at.to_string())?;
+    }
+    if let Some(updated_at) = metadata.updated_at {
+        headers.append("x-updated-at", &updated_at.to_string())?;
+        headers.append("last-modified", &utils::http_date(updated_at))?;
+    }
+    for (name, value) in &metadata.custom {
+        headers.append(&format!("{}{}", CUSTOM_METADATA_HEADER_PREFIX, name), value)?;
+    }
+
+    Ok(())
+}
+
+/// Describes a value without sending it, using the metadata from listing the key so the value itself
+/// is never read, which makes it cheap to check how big a value is before fetching it.
+async fn head(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+    authorize_read(&req, &ctx, key)?;
+
+    let listed = match listed_key(&store, key).await? {
+        Some(listed) if !is_tombstone(&listed) => listed,
+        _ => return Ok(Response::empty()?.with_status(404)),
+    };
+
+    let mut headers = Headers::default();
+    let metadata = listed
+        .metadata
+        .and_then(|metadata| serde_json::from_value::<ExampleMetadata>(metadata).ok());
+    match metadata {
+        Some(metadata) => {
+            headers.append("content-type", &metadata.content_type)?;
+            if let Some(size) = metadata.size {
+                headers.append("content-length", &size.to_string())?;
+            }
+            append_metadata_headers(&mut headers, &metadata)?;
+        }
+        None => headers.append("content-type", "application/octet-stream")?,
+    }
+
+    Ok(Response::empty()?.with_headers(headers))
+}
+
+#[derive(Debug, Serialize)]
+struct MetaResponse {
+    key: String,
+    // When the key expires according to KV, which is set even if it was written before we started
+    // keeping track of expirations in the metadata.
+    expiration: Option<u64>,
+    metadata: Option<ExampleMetadata>,
+}
+
+/// Describes a value as JSON without reading it, using the metadata from listing the key like
+/// [head] does.
+async fn meta(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+    authorize_read(&req, &ctx, key)?;
+
+    let listed = match listed_key(&store, key).await? {
+        Some(listed) if !is_tombstone(&listed) => listed,
+        _ => return Err(ApiError::KeyNotFound.into()),
+    };
+
+    Response::from_json(&MetaResponse {
+        key: listed.name,
+        expiration: listed.expiration,
+        metadata: listed
+            .metadata
+            .and_then(|metadata| serde_json::from_value(metadata).ok()),
+    })
+}
+
+// The metadata fields the worker looks after itself, which can't be changed with `PATCH /meta/:key`.
+const MANAGED_METADATA_FIELDS: &[&str] = &[
+    "created_at",
+    "updated_at",
+    "checksum",
+    "nonce",
+    "deleted_at",
+    "expiration",
+    "blob",
+    "size",
+    "signature",
+];
+
+/// The metadata fields that can be changed without writing the value again.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MetadataPatch {
+    content_type: Option<String>,
+    filename: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+/// Changes a value's metadata without the client having to send the value again. KV can only write
+/// metadata along with the value, so the value is read and written back unchanged.
+async fn patch_meta(mut req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &write_key_param(&ctx)?;
+
+    let patch: serde_json::Map<String, serde_json::Value> = match req.json().await {
+        Ok(patch) => patch,
+        Err(_) => return Err(ApiError::InvalidBody(None).into()),
+    };
+    if let Some(field) = patch
+        .keys()
+        .find(|field| MANAGED_METADATA_FIELDS.contains(&field.as_str()))
+    {
+        return Err(ApiError::InvalidRequest(format!("{} can't be changed", field)).into());
+    }
+    let patch: MetadataPatch = match serde_json::from_value(patch.into()) {
+        Ok(patch) => patch,
+        Err(err) => return Err(ApiError::InvalidBody(Some(err.to_string())).into()),
+    };
+    if patch.tags.iter().flatten().any(|tag| tag.is_empty()) {
+        return Err(ApiError::InvalidRequest("tags must not be empty".into()).into());
+    }
+
+    // The expiration KV knows about is the one that counts, whether or not the metadata has it.
+    let listed = match listed_key(&store, key).await? {
+        Some(listed) if !is_tombstone(&listed) => listed,
+        _ => return Err(ApiError::KeyNotFound.into()),
+    };
+    let (value, metadata) = store
+        .get(key)
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
+    let value = match value {
+        Some(value) => value,
+        None => return Err(ApiError::KeyNotFound.into()),
+    };
+
+    let old = metadata.unwrap_or_else(|| ExampleMetadata {
+        updated_at: None,
+        ..ExampleMetadata::new("application/octet-stream")
+    });
+    let old_content_type = old.content_type.clone();
+    let mut metadata = ExampleMetadata {
+        content_type: patch.content_type.unwrap_or(old.content_type),
+        filename: patch.filename.or(old.filename),
+        tags: patch.tags.unwrap_or(old.tags),
+        ..old
+    };
+    metadata.signature = metadata_signature(&ctx.env, &metadata);
+    metadata.check_size()?;
+
+    let mut put = store.put_bytes(key, &value)?.metadata(&metadata)?;
+    if let Some(expiration) = listed.expiration {
+        put = put.expiration(expiration);
+    }
+    put.execute().await?;
+
+    if utils::media_type_essence(&old_content_type)
+        != utils::media_type_essence(&metadata.content_type)
+    {
+        unindex_content_type(&store, key, &old_content_type).await;
+        index_content_type(&store, key, &metadata.content_type, None).await;
+    }
+
+    Response::from_json(&MetaResponse {
+        key: key.clone(),
+        expiration: listed.expiration,
+        metadata: Some(metadata),
+    })
+}
+
+/// Makes sure a decrypted value is the one its checksum was computed from. We'd rather fail loudly
+/// than hand out a value that doesn't match what was stored.
+fn check_checksum(value: &[u8], metadata: &ExampleMetadata) -> Result<()> {
+    if let Some(checksum) = &metadata.checksum {
+        if !utils::sha256_hex(value).eq_ignore_ascii_case(checksum) {
+            return Err(ApiError::Internal("checksum mismatch".into()).into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Builds the response for a value read from the store, taking the request's conditional and range
+/// headers into account.
+fn value_response(
+    req: &Request,
+    maybe_value: Option<Vec<u8>>,
+    maybe_metadata: Option<ExampleMetadata>,
+) -> Result<Response> {
+    // In our store we might have the key and that key might have metadata, so we need to check.
+    let url = req.url()?;
+    let strict = utils::param_from(&url, "strict").is_some_and(|strict| strict == "true");
+
+    let (value, metadata) = match (maybe_value, maybe_metadata) {
+        (Some(_), Some(metadata)) if metadata.deleted_at.is_some() => {
+            return Err(ApiError::KeyNotFound.into())
+        }
+        (Some(value), Some(metadata)) => (value, metadata),
+        // Our KV store might have that key, but no metadata associated. That only happens for keys
+        // written before we stored metadata (or if the store is manipulated manually), so clients
+        // that really need the metadata can ask for a 500 instead.
+        (Some(_), None) if strict => {
+            return Err(ApiError::Internal("no metadata found".into()).into())
+        }
+        // Otherwise the value is still perfectly readable, we just don't know what it is.
+        (Some(value), None) => {
+            let metadata = ExampleMetadata {
+                updated_at: None,
+                ..ExampleMetadata::new("application/octet-stream")
+            };
+            (value, metadata)
+        }
+        _ => return Err(ApiError::KeyNotFound.into()),
+    };
+
+    check_checksum(&value, &metadata)?;
+
+    let mut headers = Headers::default();
+    append_metadata_headers(&mut headers, &metadata)?;
+
+    if not_modified(req, &metadata)? {
+        return Ok(Response::empty()?.with_status(304).with_headers(headers));
+    }
+
+    // Let's return a body containing the bytes in the KV store with a content-type header from our
+    // metadata.
+    headers.append("content-type", &metadata.content_type)?;
+    headers.append("accept-ranges", "bytes")?;
+
+    // Clients might only want part of a large value, so let's only send the bytes they asked for.
+    let range = match req.headers().get("range")? {
+        Some(range) => utils::byte_range(&range, value.len()),
+        None => utils::ByteRange::Full,
+    };
+
+    match range {
+        utils::ByteRange::Full => {
+            headers.append("vary", "accept-encoding")?;
+
+            // Let's compress large values if the client supports it, unless they asked for the raw
+            // bytes or the value wouldn't compress well anyway.
+            let raw = utils::param_from(&url, "raw").is_some_and(|raw| raw == "1");
+            let compress = !raw
+                && value.len() > COMPRESSION_THRESHOLD
+                && utils::is_compressible(&metadata.content_type);
+            let encoding = if compress {
+                utils::negotiate_encoding(req)
+            } else {
+                utils::Encoding::Identity
+            };
+
+            if let Some(name) = encoding.name() {
+                headers.append("content-encoding", name)?;
+            }
+            Ok(Response::from_bytes(encoding.encode(value)?)?.with_headers(headers))
+        }
+        utils::ByteRange::Partial(range) => {
+            headers.append(
+                "content-range",
+                &format!("bytes {}-{}/{}", range.start, range.end - 1, value.len()),
+            )?;
+            headers.append("content-length", &range.len().to_string())?;
+
+            Ok(Response::from_bytes(value[range].to_vec())?
+                .with_status(206)
+                .with_headers(headers))
+        }
+        utils::ByteRange::Unsatisfiable => {
+            let mut response = ApiError::RangeNotSatisfiable.response()?;
+            response
+                .headers_mut()
+                .set("content-range", &format!("bytes */{}", value.len()))?;
+
+            Ok(response)
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MultiGetValue {
+    // The value is base64 encoded since it can contain arbitrary bytes.
+    value: String,
+    content_type: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct MultiGetResponse {
+    values: BTreeMap<String, MultiGetValue>,
+    missing: Vec<String>,
+}
+
+/// Sometimes we want to read a bunch of keys at once without making a request for each of them, so
+/// let's allow fetching a comma-separated list of keys in one go.
+async fn multi_get(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    authorize_private_read(&req, &ctx)?;
+    let store = Store::new(&ctx)?;
+    // The keys are split up before they're decoded so that keys containing a comma can be sent as
+    // `%2C`.
+    let keys = ctx
+        .param("keys")
+        .unwrap()
+        .split(',')
+        .filter(|key| !key.is_empty())
+        .map(utils::percent_decode)
+        .collect::<Result<Vec<_>>>()?;
+
+    if keys.iter().any(|key| is_system_key(key)) {
+        return Err(ApiError::Forbidden("key is reserved for internal use".into()).into());
+    }
+
+    if keys.len() > MULTI_GET_LIMIT {
+        return Err(ApiError::InvalidRequest(format!(
+            "too many keys, at most {} are allowed",
+            MULTI_GET_LIMIT
+        ))
+        .into());
+    }
+
+    // Keys that can't be written can't exist, so there's no need to look for them.
+    let (keys, invalid): (Vec<_>, Vec<_>) = keys
+        .into_iter()
+        .partition(|key| key_violation(key).is_none());
+
+    // Let's fetch all of the keys at the same time rather than waiting on each one in turn.
+    let results = join_all(keys.iter().map(|key| read_value(&store, key))).await;
+
+    let mut response = MultiGetResponse {
+        missing: invalid,
+        ..MultiGetResponse::default()
+    };
+    for (key, result) in keys.into_iter().zip(results) {
+        match result? {
+            (Some(_), Some(metadata)) if metadata.deleted_at.is_some() => {
+                response.missing.push(key)
+            }
+            (Some(value), metadata) => {
+                verify_metadata_signature(&ctx.env, metadata.as_ref())?;
+                let value = match &metadata {
+                    Some(metadata) => {
+                        let value = decrypt_value(&ctx.env, value, metadata)?;
+                        check_checksum(&value, metadata)?;
+                        value
+                    }
+                    None => value,
+                };
+                let value = MultiGetValue {
+                    value: base64::encode(value),
+                    content_type: metadata.map(|metadata| metadata.content_type),
+                };
+                response.values.insert(key, value);
+            }
+            (None, _) => response.missing.push(key),
+        }
+    }
+
+    Response::from_json(&response)
+}
+
+/// Sync clients often only need to know which keys exist, so this checks a batch of keys without
+/// reading any of their values.
+async fn batch_exists(mut req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    authorize_private_read(&req, &ctx)?;
+    let store = Store::new(&ctx)?;
+    let keys: Vec<String> = match req.json().await {
+        Ok(keys) => keys,
+        Err(_) => {
+            return Err(ApiError::InvalidBody(Some("expected an array of keys".into())).into())
+        }
+    };
+
+    if keys.iter().any(|key| is_system_key(key)) {
+        return Err(ApiError::Forbidden("key is reserved for internal use".into()).into());
+    }
+
+    if keys.len() > BATCH_EXISTS_LIMIT {
+        return Err(ApiError::InvalidRequest(format!(
+            "too many keys, at most {} are allowed",
+            BATCH_EXISTS_LIMIT
+        ))
+        .into());
+    }
+
+    // Keys that can't be written can't exist, so there's no need to look for them.
+    let (keys, invalid): (Vec<_>, Vec<_>) = keys
+        .into_iter()
+        .partition(|key| key_violation(key).is_none());
+
+    let results = join_all(keys.iter().map(|key| key_exists(&store, key))).await;
+
+    let mut response: BTreeMap<_, _> = invalid.into_iter().map(|key| (key, false)).collect();
+    for (key, exists) in keys.into_iter().zip(results) {
+        response.insert(key, exists?);
+    }
+
+    Response::from_json(&response)
+}
+
+/// A single key in an export, which is written as one line of newline-delimited JSON.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportEntry {
+    key: String,
+    value_base64: String,
+    metadata: Option<serde_json::Value>,
+    expiration: Option<u64>,
+}
+
+/// The last line of an export, telling the client where to pick up from if there's more to read.
+#[derive(Debug, Serialize)]
+struct ExportTrailer {
+    cursor: Option<String>,
+    complete: bool,
+}
+
+struct ExportState {
+    store: Store,
+    prefix: String,
+    cursor: Option<String>,
+    pending: VecDeque<kv::Key>,
+    // How many keys have been listed that will be exported, and how many list calls it took.
+    listed: u64,
+    pages: usize,
+    complete: bool,
+    finished: bool,
+}
+
+/// Backing up a namespace means reading every key in it, which won't fit in memory (or a single
+/// request) for large namespaces. So let's stream the keys out one line at a time as we page through
+/// the list, and stop after a fixed number of keys with a cursor the client can resume from.
+async fn export(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    // This dumps everything in the store, so only trusted clients should be able to use it.
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let url = req.url()?;
+    let state = ExportState {
+        store: Store::new(&ctx)?,
+        prefix: utils::param_from(&url, "prefix")
+            .map(String::from)
+            .unwrap_or_default(),
+        cursor: utils::param_from(&url, "cursor").map(String::from),
+        pending: VecDeque::new(),
+        listed: 0,
+        pages: 0,
+        complete: false,
+        finished: false,
+    };
+
+    let mut headers = Headers::default();
+    headers.append("content-type", "application/x-ndjson")?;
+
+    Ok(Response::from_stream(export_lines(state))?.with_headers(headers))
+}
+
+fn export_lines(state: ExportState) -> impl Stream<Item = Result<Vec<u8>>> {
+    stream::try_unfold(state, |mut state| async move {
+        loop {
+            if let Some(key) = state.pending.pop_front() {
+                // The key might have been deleted since we listed it, in which case we just skip it.
+                let value = match state.store.get(&key.name).bytes().await? {
+                    Some(value) => value,
+                    None => continue,
+                };
+
+                // Deduplicated values are exported in full, since the blob they point at won't be
+                // exported along with them.
+                let mut metadata = key.metadata;
+                let blob = metadata
+                    .as_mut()
+                    .and_then(|metadata| metadata.as_object_mut())
+                    .and_then(|metadata| metadata.remove("blob"));
+                let value = match blob.as_ref().and_then(|blob| blob.as_str()) {
+                    Some(blob) => match state.store.get(&blob_key(blob)).bytes().await? {
+                        Some(value) => value,
+                        None => continue,
+                    },
+                    None => value,
+                };
+
+                let entry = ExportEntry {
+                    key: key.name,
+                    value_base64: base64::encode(value),
+                    metadata,
+                    expiration: key.expiration,
+                };
+                return Ok(Some((utils::ndjson_line(&entry)?, state)));
+            }
+
+            if state.finished {
+                return Ok(None);
+            }
+
+            if state.complete
+                || state.listed >= EXPORT_KEY_LIMIT
+                || state.pages >= EXPORT_PAGE_LIMIT
+            {
+                state.finished = true;
+                let trailer = ExportTrailer {
+                    cursor: state.cursor.clone().filter(|_| !state.complete),
+                    complete: state.complete,
+                };
+                return Ok(Some((utils::ndjson_line(&trailer)?, state)));
+            }
+
+            let limit = EXPORT_PAGE_SIZE.min(EXPORT_KEY_LIMIT - state.listed);
+            let page = list_page(
+                &state.store,
+                &state.prefix,
+                state.cursor.take(),
+                Some(limit),
+            )
+            .await?;
+            // The worker's own keys can't be imported, and tombstones stand for keys that are gone,
+            // so neither is exported.
+            let keys = page
+                .keys
+                .into_iter()
+                .filter(|key| !is_system_key(&key.name) && !is_tombstone(key));
+            let pending = state.pending.len();
+            state.pending.extend(keys);
+            state.listed += (state.pending.len() - pending) as u64;
+            state.pages += 1;
+            state.complete = page.list_complete;
+            state.cursor = page.cursor;
+        }
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct ImportError {
+    line: usize,
+    error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ImportResponse {
+    imported: u64,
+    failed: u64,
+    errors: Vec<ImportError>,
+    dry_run: bool,
+}
+
+/// The counterpart to [export], which writes every line of an export back into the store. Each line
+/// is handled on its own so one bad entry doesn't stop the rest of the import.
+async fn import(mut req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let store = Store::new(&ctx)?;
+    let url = req.url()?;
+    let body = req.text().await?;
+
+    // A dry run checks every line without writing anything, so clients can see what would happen.
+    let mut response = ImportResponse {
+        dry_run: utils::param_from(&url, "dry_run").is_some_and(|dry_run| dry_run == "1"),
+        ..ImportResponse::default()
+    };
+    // Empty values are rejected like they are by `PUT /:key`, unless the client says they're meant.
+    let allow_empty = utils::param_from(&url, "allow_empty").is_some_and(|allow| allow == "1");
+
+    for (index, line) in body.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match import_line(&ctx.env, &store, line, response.dry_run, allow_empty).await {
+            Ok(true) => response.imported += 1,
+            Ok(false) => {}
+            Err(err) => {
+                response.failed += 1;
+                response.errors.push(ImportError {
+                    line: index + 1,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+
+    Response::from_json(&response)
+}
+
+/// Imports a single line of an export, returning whether it held an entry.
+async fn import_line(
+    env: &Env,
+    store: &Store,
+    line: &str,
+    dry_run: bool,
+    allow_empty: bool,
+) -> Result<bool> {
+    let line: serde_json::Value = serde_json::from_str(line)?;
+
+    // The trailing line of an export only tells us where it stopped, so there's nothing to import.
+    if line.get("key").is_none() && line.get("complete").is_some() {
+        return Ok(false);
+    }
+
+    let entry: ExportEntry = serde_json::from_value(line)?;
+    import_entry(env, store, entry, dry_run, allow_empty).await?;
+
+    Ok(true)
+}
+
+/// Writes an entry of an export, or only checks it when it's a dry run. Entries are written the same
+/// way `PUT /:key` writes values, so the metadata they carry is only trusted to describe the value:
+/// its checksum and size are worked out again and it's signed with our own key.
+async fn import_entry(
+    env: &Env,
+    store: &Store,
+    entry: ExportEntry,
+    dry_run: bool,
+    allow_empty: bool,
+) -> Result<()> {
+    if is_system_key(&entry.key) {
+        return Err(Error::RustError("key is reserved for internal use".into()));
+    }
+    if let Some(violation) = key_violation(&entry.key) {
+        return Err(Error::RustError(violation));
+    }
+
+    let value = base64::decode(&entry.value_base64)
+        .map_err(|err| Error::RustError(format!("invalid value_base64: {}", err)))?;
+    if value.is_empty() && !allow_empty {
+        return Err(Error::RustError("empty value".into()));
+    }
+
+    // Keys exported without metadata were written before we stored any, so they get the same
+    // defaults `/admin/migrate` gives them.
+    let metadata = match entry.metadata {
+        Some(metadata) => serde_json::from_value::<ExampleMetadata>(metadata)
+            .map_err(|err| Error::RustError(format!("invalid metadata: {}", err)))?,
+        None => ExampleMetadata::new(DEFAULT_CONTENT_TYPE),
+    };
+    if metadata.deleted_at.is_some() {
+        return Err(Error::RustError("entry is a deleted key".into()));
+    }
+    // KV won't take an expiration less than a minute away, which an entry exported close to expiring
+    // can have by now.
+    let ttl = match entry.expiration {
+        Some(expiration) => match expiration.checked_sub(utils::now()) {
+            Some(ttl) if ttl >= MIN_TTL => Some(ttl),
+            _ => return Err(Error::RustError("entry has expired".into())),
+        },
+        None => None,
+    };
+    metadata.check_size()?;
+
+    if dry_run {
+        return Ok(());
+    }
+
+    // Encrypted values are exported as they're stored, so they're decrypted before being encrypted
+    // again under a fresh nonce.
+    let value = decrypt_value(env, value, &metadata)?;
+    let metadata = ExampleMetadata {
+        checksum: Some(utils::sha256_hex(&value)),
+        updated_at: Some(utils::now()),
+        ..metadata
+    };
+    store_value(env, store, &entry.key, value, metadata, ttl).await?;
+
+    Ok(())
+}
+
+/// How writing or deleting a single key of a bulk request went. Dry runs report what would have
+/// happened in the same shape.
+#[derive(Debug, Serialize)]
+struct EntryResult {
+    key: String,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl EntryResult {
+    fn new(key: impl Into<String>, result: Result<()>) -> Self {
+        let (ok, error) = match result {
+            Ok(()) => (true, None),
+            Err(err) => (false, Some(err.to_string())),
+        };
+        Self {
+            key: key.into(),
+            ok,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BulkPutResponse {
+    written: u64,
+    failed: u64,
+    results: Vec<EntryResult>,
+    dry_run: bool,
+}
+
+/// Writes a JSON array of entries shaped like the lines of an export in one go, reporting how each
+/// one went. Entries are written like imported ones, so one bad entry doesn't stop the rest, and a
+/// dry run checks every entry without writing anything.
+async fn bulk_put(mut req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let store = Store::new(&ctx)?;
+    let url = req.url()?;
+    let dry_run = utils::param_from(&url, "dry_run").is_some_and(|dry_run| dry_run == "1");
+    let allow_empty = utils::param_from(&url, "allow_empty").is_some_and(|allow| allow == "1");
+    let entries: Vec<ExportEntry> = match req.json().await {
+        Ok(entries) => entries,
+        Err(_) => {
+            return Err(ApiError::InvalidBody(Some("expected an array of entries".into())).into())
+        }
+    };
+    if entries.len() > BULK_PUT_LIMIT {
+        return Err(ApiError::InvalidRequest(format!(
+            "too many entries, at most {} are allowed",
+            BULK_PUT_LIMIT
+        ))
+        .into());
+    }
+
+    let mut response = BulkPutResponse {
+        written: 0,
+        failed: 0,
+        results: Vec::with_capacity(entries.len()),
+        dry_run,
+    };
+    for entry in entries {
+        let key = entry.key.clone();
+        let result = import_entry(&ctx.env, &store, entry, dry_run, allow_empty).await;
+        match result {
+            Ok(()) => response.written += 1,
+            Err(_) => response.failed += 1,
+        }
+        response.results.push(EntryResult::new(key, result));
+    }
+
+    Response::from_json(&response)
+}
+
+/// Removes a key for good, along with its index entries, its previous version, its history and
+/// their shares of a deduplicated blob. Every delete that isn't soft goes through this, so deleting
+/// keys in bulk leaves nothing more behind than deleting them one at a time.
+async fn purge_key(env: &Env, store: &Store, key: &str) -> Result<()> {
+    let (value, metadata) = store
+        .get(key)
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
+    store.delete(key).await?;
+    if let Some(metadata) = metadata {
+        unindex_content_type(store, key, &metadata.content_type).await;
+        let document = stored_document(env, store, value, &metadata).await;
+        reindex_structured(store, key, document.as_ref(), None, None).await;
+        if let Some(blob) = metadata.blob {
+            release_blob(store, &blob).await?;
+        }
+    }
+
+    drop_previous(store, key).await?;
+    clear_history(store, key).await
+}
+
+/// Checks the `If-Match` header of a delete against the key it's deleting, failing with a 412 if
+/// the key has changed. Without the header anything goes. A key that's already gone is just not
+/// found, there's no version to compare.
+fn check_if_match(if_match: Option<&str>, current: Option<&kv::Key>) -> Result<()> {
+    let if_match = match if_match {
+        Some(if_match) => if_match,
+        None => return Ok(()),
+    };
+    let current = match current.filter(|current| !is_tombstone(current)) {
+        Some(current) => current,
+        None => return Err(ApiError::KeyNotFound.into()),
+    };
+
+    let checksum = current
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get("checksum"))
+        .and_then(|checksum| checksum.as_str())
+        .unwrap_or_default();
+    if !utils::if_match(if_match, checksum) {
+        return Err(ApiError::PreconditionFailed.into());
+    }
+
+    Ok(())
+}
+
+async fn delete(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+
+    // Clients can make sure they're deleting the version they last saw, rather than one somebody
+    // else wrote since. The key only has to be looked up when they do.
+    let if_match = req.headers().get("if-match")?;
+    let current = match &if_match {
+        Some(_) => listed_key(&store, key).await?,
+        None => None,
+    };
+    check_if_match(if_match.as_deref(), current.as_ref())?;
+
+    // Deletes can't be taken back, so we can instead leave a tombstone behind that hides the key
+    // until it expires. Purging always removes the key for good.
+    let url = req.url()?;
+    let purge = utils::param_from(&url, "purge").is_some_and(|purge| purge == "1");
+    let soft = match utils::param_from(&url, "soft") {
+        Some(soft) => soft == "1",
+        None => utils::var(&ctx, SOFT_DELETE_VAR).is_some_and(|soft| soft == "true"),
+    };
+
+    // The history only goes with the key when it's gone for good, so a soft deleted key that's
+    // undeleted still has it.
+    if purge || !soft {
+        purge_key(&ctx.env, &store, key).await?;
+        return Response::ok("deleted");
+    }
+
+    let (value, metadata) = store
+        .get(key)
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
+
+    if let Some(value) = value {
+        let metadata = metadata.unwrap_or_else(|| ExampleMetadata {
+            updated_at: None,
+            ..ExampleMetadata::new("application/octet-stream")
+        });
+        let ttl = match utils::var(&ctx, TOMBSTONE_TTL_VAR) {
+            Some(ttl) => ttl
+                .parse()
+                .map_err(|_| Error::RustError("invalid TOMBSTONE_TTL".into()))?,
+            None => DEFAULT_TOMBSTONE_TTL,
+        };
+        let now = utils::now();
+
+        let content_type = metadata.content_type.clone();
+        let document = stored_document(&ctx.env, &store, Some(value.clone()), &metadata).await;
+        store
+            .put_bytes(key, &value)?
+            .expiration_ttl(tombstone_ttl(metadata.expiration, ttl, now))
+            .metadata(ExampleMetadata {
+                deleted_at: Some(now),
+                ..metadata
+            })?
+            .execute()
+            .await?;
+
+        unindex_content_type(&store, key, &content_type).await;
+        reindex_structured(&store, key, document.as_ref(), None, None).await;
+    }
+
+    Response::ok("deleted")
+}
+
+#[derive(Debug, Serialize)]
+struct DeleteTagResponse {
+    deleted: u64,
+    scanned: u64,
+    cursor: Option<String>,
+    complete: bool,
+}
+
+/// Deletes every key written with a tag. Tags aren't indexed, so this goes through the metadata of
+/// every key in the store to find them, a few pages at a time. The rest of the store can be gone
+/// through by calling this again with the returned cursor until it's complete.
+async fn delete_tag(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let store = Store::new(&ctx)?;
+    let tag = utils::percent_decode(ctx.param("tag").unwrap())?;
+
+    let mut response = DeleteTagResponse {
+        deleted: 0,
+        scanned: 0,
+        cursor: utils::param_from(&req.url()?, "cursor").map(String::from),
+        complete: false,
+    };
+    loop {
+        let page = list_page(
+            &store,
+            "",
+            response.cursor.take(),
+            Some(DELETE_TAG_PAGE_SIZE),
+        )
+        .await?;
+        response.scanned += page.keys.len() as u64;
+        let tagged = page.keys.iter().filter(|key| {
+            !is_system_key(&key.name)
+                && key
+                    .metadata
+                    .as_ref()
+                    .and_then(|metadata| metadata.get("tags"))
+                    .and_then(|tags| tags.as_array())
+                    .is_some_and(|tags| tags.iter().any(|t| t.as_str() == Some(tag.as_str())))
+        });
+        for key in tagged {
+            purge_key(&ctx.env, &store, &key.name).await?;
+            response.deleted += 1;
+        }
+
+        if page.list_complete {
+            response.complete = true;
+            break;
+        }
+        response.cursor = page.cursor;
+        if response.scanned >= DELETE_TAG_SCAN_LIMIT || response.deleted >= DELETE_TAG_KEY_LIMIT {
+            break;
+        }
+    }
+
+    Response::from_json(&response)
+}
+
+#[derive(Debug, Serialize)]
+struct PrepareDeleteResponse {
+    token: String,
+    prefix: String,
+    keys: u64,
+    // Counting is bounded the same way as `/list?all=1`, so for big prefixes `keys` is only how many
+    // there are at least.
+    complete: bool,
+    expiration: u64,
+}
+
+/// The first step of deleting every key under a prefix, which counts the keys that would be deleted
+/// and hands out a token that has to be sent with the delete within `CONFIRM_TTL` seconds. That way
+/// nobody deletes a prefix without having seen how much it holds, and this doubles as a dry run.
+async fn prepare_prefix_delete(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let store = Store::new(&ctx)?;
+    let prefix = utils::percent_decode(ctx.param("prefix").unwrap())?;
+    let counted = list_all(&store, &prefix, None, &|key| !is_system_key(&key.name)).await?;
+
+    let token = uuid::Uuid::new_v4().to_string();
+    store
+        .put_bytes(
+            &format!("{}{}", CONFIRM_KEY_PREFIX, token),
+            prefix.as_bytes(),
+        )?
+        .expiration_ttl(CONFIRM_TTL)
+        .execute()
+        .await?;
+
+    Response::from_json(&PrepareDeleteResponse {
+        token,
+        prefix,
+        keys: counted.keys.len() as u64,
+        complete: counted.list_complete,
+        expiration: utils::now() + CONFIRM_TTL,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct PrefixDeleteResponse {
+    deleted: u64,
+    failed: u64,
+    results: Vec<EntryResult>,
+    dry_run: bool,
+    cursor: Option<String>,
+    complete: bool,
+}
+
+/// Deletes every key under a prefix, as long as the request has the `X-Confirm-Token` handed out by
+/// [prepare_prefix_delete] for the same prefix. Only `PREFIX_DELETE_KEY_LIMIT` keys are deleted per
+/// request, the rest can be deleted by calling this again with the returned cursor and the same
+/// token, which stays valid for another `CONFIRM_TTL` seconds each time until the delete is complete.
+/// It's used up then, unless the delete is a dry run, which checks the token and reports the keys
+/// that would be deleted in the same shape without deleting anything.
+async fn prefix_delete(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let store = Store::new(&ctx)?;
+    let prefix = utils::percent_decode(ctx.param("prefix").unwrap())?;
+    let url = req.url()?;
+    let dry_run = utils::param_from(&url, "dry_run").is_some_and(|dry_run| dry_run == "1");
+    let token = match req.headers().get("x-confirm-token")? {
+        Some(token) => token,
+        None => return Err(ApiError::InvalidRequest("missing X-Confirm-Token".into()).into()),
+    };
+
+    let confirm_key = format!("{}{}", CONFIRM_KEY_PREFIX, token);
+    match store.get(&confirm_key).text().await? {
+        Some(confirmed) if confirmed == prefix => {}
+        Some(_) => {
+            return Err(ApiError::Conflict("confirm token is for a different prefix".into()).into())
+        }
+        None => {
+            return Err(ApiError::Conflict("confirm token is unknown or has expired".into()).into())
+        }
+    }
+
+    let cursor = utils::param_from(&url, "cursor").map(String::from);
+    let page = list_page(&store, &prefix, cursor, Some(PREFIX_DELETE_KEY_LIMIT)).await?;
+    let mut response = PrefixDeleteResponse {
+        deleted: 0,
+        failed: 0,
+        results: Vec::new(),
+        dry_run,
+        cursor: None,
+        complete: page.list_complete,
+    };
+    // Each key is reported on its own, so one that fails to delete doesn't stop the rest.
+    for key in page.keys.iter().filter(|key| !is_system_key(&key.name)) {
+        let result = if dry_run {
+            Ok(())
+        } else {
+            purge_key(&ctx.env, &store, &key.name).await
+        };
+        match result {
+            Ok(()) => response.deleted += 1,
+            Err(_) => response.failed += 1,
+        }
+        response.results.push(EntryResult::new(&key.name, result));
+    }
+
+    let complete = page.list_complete;
+    if complete && !dry_run {
+        store.delete(&confirm_key).await?;
+    } else if !complete {
+        store
+            .put_bytes(&confirm_key, prefix.as_bytes())?
+            .expiration_ttl(CONFIRM_TTL)
+            .execute()
+            .await?;
+    }
+
+    response.cursor = page.cursor.filter(|_| !complete);
+    Response::from_json(&response)
+}
+
+/// How long the tombstone of a key is kept for. A key that would have expired sooner can't be
+/// brought back past its expiration, so its tombstone goes when it would have.
+fn tombstone_ttl(expiration: Option<u64>, ttl: u64, now: u64) -> u64 {
+    match kept_ttl(expiration, now) {
+        Some(kept) => kept.min(ttl),
+        None => ttl,
+    }
+}
+
+/// Brings back a key that was soft deleted, as long as its tombstone hasn't expired yet. The key
+/// expires when it would have if it had never been deleted.
+async fn undelete(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+
+    let (value, metadata) = store
+        .get(key)
+        .bytes_with_metadata::<ExampleMetadata>()
+        .await?;
+
+    let (value, metadata) = match (value, metadata) {
+        (Some(value), Some(metadata)) if metadata.deleted_at.is_some() => (value, metadata),
+        _ => return Err(ApiError::NotFound("no deleted key found".into()).into()),
+    };
+
+    // Writing the key again without an expiration also stops the tombstone from expiring.
+    let ttl = kept_ttl(metadata.expiration, utils::now());
+    let content_type = metadata.content_type.clone();
+    let document = stored_document(&ctx.env, &store, Some(value.clone()), &metadata).await;
+    let mut put = store.put_bytes(key, &value)?.metadata(ExampleMetadata {
+        deleted_at: None,
+        ..metadata
+    })?;
+    if let Some(ttl) = ttl {
+        put = put.expiration_ttl(ttl);
+    }
+    put.execute().await?;
+
+    index_content_type(&store, key, &content_type, ttl).await;
+    reindex_structured(&store, key, None, document.as_ref(), ttl).await;
+
+    Response::ok("undeleted")
+}
+
+/// Gets the key of the lock a request is for.
+fn lock_key_param(ctx: &RouteContext<Retries>) -> Result<String> {
+    let name = utils::percent_decode(ctx.param("name").unwrap())?;
+    let key = format!("{}{}", LOCK_KEY_PREFIX, name);
+    if key.len() > MAX_KEY_LENGTH {
+        return Err(ApiError::InvalidRequest(format!(
+            "lock name must be at most {} bytes",
+            MAX_KEY_LENGTH - LOCK_KEY_PREFIX.len()
+        ))
+        .into());
+    }
+
+    Ok(key)
+}
+
+#[derive(Debug, Serialize)]
+struct LockResponse {
+    token: String,
+    expiration: u64,
+}
+
+/// Acquires a lock for `ttl` seconds, handing back a token that's needed to release it. Locks that
+/// are never released expire on their own, so a client that dies while holding one can't block
+/// everyone else forever.
+///
+/// KV has no way to write a key only if it doesn't exist, so checking if the lock is free and taking
+/// it are two separate steps. Two clients racing for the same lock can both see it free and both be
+/// told they hold it, and since KV is eventually consistent a lock taken in one location can take a
+/// while to be seen in another. These locks are fine for avoiding duplicate work, but shouldn't be
+/// relied on when correctness depends on only one client holding the lock.
+async fn lock(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &lock_key_param(&ctx)?;
+
+    let url = req.url()?;
+    let ttl = utils::param_parse(&url, "ttl")?.unwrap_or(MIN_TTL);
+    if ttl < MIN_TTL {
+        return Err(
+            ApiError::InvalidRequest(format!("ttl must be at least {} seconds", MIN_TTL)).into(),
+        );
+    }
+
+    if store.get(key).text().await?.is_some() {
+        return Err(ApiError::Conflict("lock is already held".into()).into());
+    }
+
+    let token = uuid::Uuid::new_v4().to_string();
+    store
+        .put_bytes(key, token.as_bytes())?
+        .expiration_ttl(ttl)
+        .execute()
+        .await?;
+
+    Response::from_json(&LockResponse {
+        token,
+        expiration: utils::now() + ttl,
+    })
+}
+
+/// Releases a lock, as long as the request has the token handed out when it was acquired.
+async fn unlock(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &lock_key_param(&ctx)?;
+
+    let url = req.url()?;
+    let token: String = utils::param_required(&url, "token")?;
+
+    match store.get(key).text().await? {
+        Some(held) if held == token => {
+            store.delete(key).await?;
+            Response::ok("unlocked")
+        }
+        Some(_) => Err(ApiError::Conflict("lock is held by someone else".into()).into()),
+        None => Err(ApiError::NotFound("lock not found".into()).into()),
+    }
+}
+
+/// Lists the keys with a content-type using the content-type index, which only has to look at the
+/// matching keys rather than filtering every key like `/list?content_type=` does. The content-type's
+/// slash doesn't need to be encoded, so `/by-content-type/image/png` works.
+async fn by_content_type(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    authorize_private_read(&req, &ctx)?;
+    let store = Store::new(&ctx)?;
+    let content_type = ctx.param("type").unwrap();
+    let content_type =
+        utils::percent_decode(content_type.strip_prefix('/').unwrap_or(content_type))?;
+    if !content_type.contains('/') {
+        return Err(ApiError::InvalidRequest("invalid content-type".into()).into());
+    }
+
+    let url = req.url()?;
+    let limit = utils::param_parse(&url, "limit")?.unwrap_or(100);
+    let cursor = utils::param_from(&url, "cursor").map(String::from);
+
+    let prefix = content_type_index_prefix(&content_type);
+    indexed_keys(&store, &prefix, cursor, limit).await
+}
+
+/// Lists a page of the keys in an index under `prefix`.
+async fn indexed_keys(
+    store: &Store,
+    prefix: &str,
+    cursor: Option<String>,
+    limit: u64,
+) -> Result<Response> {
+    let page = list_page(store, prefix, cursor, Some(limit)).await?;
+    let list_complete = page.list_complete;
+    let keys = page
+        .keys
+        .into_iter()
+        .filter_map(|key| {
+            key.name.strip_prefix(prefix).map(|name| ListedKey {
+                name: name.to_string(),
+                expiration: key.expiration,
+                metadata: None,
+            })
+        })
+        .collect();
+
+    Response::from_json(&ListResult::new(keys, page.cursor, list_complete))
+}
+
+/// Finds the structured documents with a top-level field set to a value using the structured index,
+/// which unlike `/structured/search` doesn't have to read every document. Values are compared as
+/// text, so `/structured/by/bar/5` finds documents where `bar` is the number 5.
+async fn structured_by(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    authorize_private_read(&req, &ctx)?;
+    let store = Store::new(&ctx)?;
+    let field = utils::percent_decode(ctx.param("field").unwrap())?;
+    let value = utils::percent_decode(ctx.param("value").unwrap())?;
+
+    let url = req.url()?;
+    let limit = utils::param_parse(&url, "limit")?.unwrap_or(100);
+    let cursor = utils::param_from(&url, "cursor").map(String::from);
+
+    let prefix = structured_index_prefix(&field, &value);
+    indexed_keys(&store, &prefix, cursor, limit).await
+}
+
+#[derive(Debug, Serialize)]
+struct ContentTypesResponse {
+    prefix: String,
+    content_types: BTreeMap<String, u64>,
+    scanned: u64,
+    // Set when there were more keys than we're willing to look at, in which case the cursor carries
+    // on from where we stopped.
+    partial: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+/// Counts how many keys under a prefix there are of each content-type, going by their listed
+/// metadata. Keys without a readable content-type are skipped, and only `CONTENT_TYPES_SCAN_LIMIT`
+/// keys are looked at per request.
+async fn content_types(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    authorize_private_read(&req, &ctx)?;
+    let store = Store::new(&ctx)?;
+    let url = req.url()?;
+    let prefix = utils::param_from(&url, "prefix")
+        .map(String::from)
+        .unwrap_or_default();
+    let mut cursor = utils::param_from(&url, "cursor").map(String::from);
+
+    let mut response = ContentTypesResponse {
+        prefix,
+        content_types: BTreeMap::new(),
+        scanned: 0,
+        partial: false,
+        cursor: None,
+    };
+    loop {
+        let remaining = CONTENT_TYPES_SCAN_LIMIT - response.scanned;
+        let limit = remaining.min(MAX_LIST_PAGE_SIZE);
+        let page = list_page(&store, &response.prefix, cursor, Some(limit)).await?;
+        response.scanned += page.keys.len() as u64;
+
+        let keys = page
+            .keys
+            .iter()
+            .filter(|key| !is_tombstone(key) && !is_system_key(&key.name));
+        for content_type in keys.filter_map(key_content_type) {
+            *response
+                .content_types
+                .entry(content_type.to_string())
+                .or_default() += 1;
+        }
+
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+        if response.scanned >= CONTENT_TYPES_SCAN_LIMIT {
+            response.partial = true;
+            response.cursor = cursor;
+            break;
+        }
+    }
+
+    Response::from_json(&response)
+}
+
+/// How often the store let us down, kept in `STORE_STATS_KEY`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoreStats {
+    // How many requests failed with a 503 because the store was unavailable.
+    unavailable: u64,
+    // When that last happened, as a unix timestamp in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_unavailable_at: Option<u64>,
+}
+
+/// Counts a request that failed because the store was unavailable, so operators can tell KV having
+/// trouble apart from bugs of our own. The store is likely still struggling when this runs, so it's
+/// only best-effort and can undercount, and two requests counting at once can lose one of them.
+async fn count_unavailable(env: Env) {
+    let count = async {
+        let store = Store::from_env(&env)?;
+        let mut stats = store
+            .get(STORE_STATS_KEY)
+            .json::<StoreStats>()
+            .await?
+            .unwrap_or_default();
+        stats.unavailable += 1;
+        stats.last_unavailable_at = Some(utils::now());
+        store
+            .put_bytes(STORE_STATS_KEY, &serde_json::to_vec(&stats)?)?
+            .execute()
+            .await?;
+        Ok::<_, Error>(())
+    };
+    if let Err(err) = count.await {
+        console_error!("failed to count the store being unavailable: {}", err);
+    }
+}
+
+/// Shows how often requests failed because the store was unavailable.
+async fn store_stats(_: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let stats = store
+        .get(STORE_STATS_KEY)
+        .json::<StoreStats>()
+        .await?
+        .unwrap_or_default();
+
+    Response::from_json(&stats)
+}
+
+#[derive(Debug, Serialize)]
+struct UsageResponse {
+    prefix: String,
+    keys: u64,
+    bytes: u64,
+    // Keys written before we started recording sizes can't be counted towards `bytes`.
+    unsized_keys: u64,
+    scanned: u64,
+    // Set when there were more keys than we're willing to look at, in which case the cursor carries
+    // on from where we stopped.
+    partial: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+/// Adds up how much space the keys under a prefix take, going by the sizes in their metadata so none
+/// of the values have to be read. This still walks the keys under the prefix, so only
+/// `USAGE_SCAN_LIMIT` keys are looked at per request, and the totals for bigger prefixes have to be
+/// added up from the pages the cursor carries on to.
+async fn usage(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    authorize_private_read(&req, &ctx)?;
+    let store = Store::new(&ctx)?;
+    let url = req.url()?;
+    let prefix = utils::param_from(&url, "prefix")
+        .map(String::from)
+        .unwrap_or_default();
+
+    let mut cursor = utils::param_from(&url, "cursor").map(String::from);
+
+    let mut usage = UsageResponse {
+        prefix,
+        keys: 0,
+        bytes: 0,
+        unsized_keys: 0,
+        scanned: 0,
+        partial: false,
+        cursor: None,
+    };
+    loop {
+        let remaining = USAGE_SCAN_LIMIT - usage.scanned;
+        let limit = remaining.min(MAX_LIST_PAGE_SIZE);
+        let page = list_page(&store, &usage.prefix, cursor, Some(limit)).await?;
+        usage.scanned += page.keys.len() as u64;
+
+        let keys = page
+            .keys
+            .iter()
+            .filter(|key| !is_tombstone(key) && !is_system_key(&key.name));
+        for key in keys {
+            usage.keys += 1;
+            let size = key
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get("size"))
+                .and_then(|size| size.as_u64());
+            match size {
+                Some(size) => usage.bytes += size,
+                None => usage.unsized_keys += 1,
+            }
+        }
+
+        if page.list_complete {
+            break;
+        }
+        cursor = page.cursor;
+        if usage.scanned >= USAGE_SCAN_LIMIT {
+            usage.partial = true;
+            usage.cursor = cursor;
+            break;
+        }
+    }
+
+    Response::from_json(&usage)
+}
+
+#[derive(Debug, Serialize)]
+struct MigrateResponse {
+    migrated: u64,
+    cursor: Option<String>,
+    complete: bool,
+}
+
+/// Keys written before we started storing metadata can't be read with `get`, so this goes through
+/// the keys under a prefix and gives the ones without metadata some sensible defaults. Only
+/// `MIGRATE_KEY_LIMIT` keys are looked at per request, the rest can be migrated by calling this again
+/// with the returned cursor.
+async fn migrate(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !write_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+
+    let store = Store::new(&ctx)?;
+    let url = req.url()?;
+    let prefix = utils::param_from(&url, "prefix")
+        .map(String::from)
+        .unwrap_or_default();
+    let cursor = utils::param_from(&url, "cursor").map(String::from);
+
+    let page = list_page(&store, &prefix, cursor, Some(MIGRATE_KEY_LIMIT)).await?;
+    let mut migrated = 0;
+    let legacy = page
+        .keys
+        .iter()
+        .filter(|key| key.metadata.is_none() && !is_system_key(&key.name));
+    for key in legacy {
+        let value = match store.get(&key.name).bytes().await? {
+            Some(value) => value,
+            None => continue,
+        };
+
+        // The value is written again the way `PUT /:key` writes values, so it's encrypted, signed
+        // and indexed like the rest. Let's make sure we don't accidentally make a key live forever.
+        let metadata = ExampleMetadata::new("application/octet-stream");
+        let ttl = kept_ttl(key.expiration, utils::now());
+        store_value(&ctx.env, &store, &key.name, value, metadata, ttl).await?;
+
+        migrated += 1;
+    }
+
+    let complete = page.list_complete;
+    Response::from_json(&MigrateResponse {
+        migrated,
+        cursor: page.cursor.filter(|_| !complete),
+        complete,
+    })
+}
+
+/// A request that changed the store, as recorded in the audit log.
+#[derive(Debug, Serialize, Deserialize)]
+struct AuditEntry {
+    // When the request came in, as a unix timestamp in milliseconds.
+    timestamp: u64,
+    method: String,
+    // The path of the request, which names the key it was for.
+    path: String,
+    // The status the request was answered with.
+    status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<String>,
+    request_id: String,
+    // Who made the request, going by the token it carried: `admin`, `writer` or `anonymous`.
+    subject: String,
+}
+
+impl AuditEntry {
+    /// Starts an entry for a request, if it's one that can change the store. Reads aren't recorded.
+    /// Most POSTs change keys, so they're all recorded rather than picking out the few that don't.
+    fn new(req: &Request, env: &Env, request_id: &str) -> Result<Option<Self>> {
+        match req.method() {
+            Method::Put | Method::Patch | Method::Delete | Method::Post => {}
+            _ => return Ok(None),
+        }
+
+        Ok(Some(Self {
+            timestamp: Date::now().as_millis(),
+            method: req.method().to_string(),
+            path: req.path(),
+            status: 0,
+            ip: req.headers().get("cf-connecting-ip")?,
+            request_id: request_id.into(),
+            subject: auth_subject(req, env)?.into(),
+        }))
+    }
+}
+
+/// Works out who made a request from the bearer token it carries.
+fn auth_subject(req: &Request, env: &Env) -> Result<&'static str> {
+    let bearer = match utils::bearer_token(req)? {
+        Some(bearer) => bearer,
+        None => return Ok("anonymous"),
+    };
+    let is = |secret| {
+        env.secret(secret)
+            .is_ok_and(|token| token.to_string() == bearer)
+    };
+
+    Ok(if is(ADMIN_TOKEN_SECRET) {
+        "admin"
+    } else if is(WRITE_TOKEN_SECRET) {
+        "writer"
+    } else {
+        "anonymous"
+    })
+}
+
+/// Writes an entry to the audit log. Recording a request mustn't get in the way of the request
+/// itself, so this runs after it was answered and failures are only logged.
+async fn record_audit(env: Env, entry: AuditEntry) {
+    let record = async {
+        let audit_log = env.kv(AUDIT_BINDING_NAME)?;
+        let key = format!(
+            "{}{:013}:{}",
+            AUDIT_KEY_PREFIX,
+            entry.timestamp,
+            uuid::Uuid::new_v4()
+        );
+        audit_log
+            .put(&key, &entry)?
+            .execute()
+            .await
+            .map_err(Error::from)
+    };
+    if let Err(err) = record.await {
+        console_warn!(
+            "[{}] failed to write to the audit log: {}",
+            entry.request_id,
+            err
+        );
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct AuditResponse {
+    entries: Vec<AuditEntry>,
+    // Set when there are more entries to read, by sending it back as `?cursor=`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+/// Reads the audit log, oldest first, starting at `?since=` if it's given as a unix timestamp in
+/// seconds. Keys can only be listed from the start, so entries before `since` still have to be
+/// listed to be skipped. Like [list_all] it stops after `LIST_ALL_PAGE_LIMIT` pages, handing back a
+/// cursor to carry on from.
+async fn audit(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    if !admin_authorized(&req, &ctx)? {
+        return Err(ApiError::Unauthorized.into());
+    }
+    let audit_log = match ctx.kv(AUDIT_BINDING_NAME) {
+        Ok(audit_log) => audit_log,
+        Err(_) => {
+            return Err(ApiError::NotImplemented("the audit log isn't configured".into()).into())
+        }
+    };
+
+    let url = req.url()?;
+    let since = utils::param_parse::<u64>(&url, "since")?.unwrap_or_default();
+    let limit = utils::param_parse::<u64>(&url, "limit")?
+        .unwrap_or(DEFAULT_AUDIT_LIMIT)
+        .min(MAX_AUDIT_LIMIT);
+    let since_key = format!("{}{:013}", AUDIT_KEY_PREFIX, since * 1000);
+    let mut cursor = utils::param_from(&url, "cursor").map(String::from);
+
+    // Pages are never bigger than what's left of the limit, so no entry is listed without being
+    // returned and the cursor carries on right after the last one.
+    let mut keys = Vec::new();
+    for _ in 0..LIST_ALL_PAGE_LIMIT {
+        let mut options = audit_log
+            .list()
+            .prefix(AUDIT_KEY_PREFIX.into())
+            .limit(limit - keys.len() as u64);
+        if let Some(cursor) = cursor {
+            options = options.cursor(cursor);
+        }
+        let page = options.execute().await.map_err(ApiError::from)?;
+
+        keys.extend(
+            page.keys
+                .into_iter()
+                .map(|key| key.name)
+                .filter(|name| *name >= since_key),
+        );
+        cursor = if page.list_complete {
+            None
+        } else {
+            page.cursor
+        };
+        if cursor.is_none() || keys.len() as u64 >= limit {
+            break;
+        }
+    }
+
+    let entries = join_all(
+        keys.iter()
+            .map(|key| audit_log.get(key).json::<AuditEntry>()),
+    )
+    .await;
+    let entries = entries
+        .into_iter()
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(ApiError::from)?;
+
+    Response::from_json(&AuditResponse {
+        entries: entries.into_iter().flatten().collect(),
+        cursor,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct FlushResponse {
+    deleted: u64,
+    cursor: Option<String>,
+    complete: bool,
+}
+
+/// Deletes every key in the store, including the worker's own, which is handy for tearing down
+/// after tests. There's no undoing this, so it needs both the admin token and `?confirm=true`. Only
+/// `FLUSH_KEY_LIMIT` keys are deleted per request, the rest can be deleted by calling this again with
+/// the returned cursor until it's complete.
+async fn flush(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let url = req.url()?;
+    let confirmed = utils::param_from(&url, "confirm").is_some_and(|confirm| confirm == "true");
+    if !confirmed || !admin_authorized(&req, &ctx)? {
+        return Err(ApiError::Forbidden(
+            "flushing requires the admin token and ?confirm=true".into(),
+        )
+        .into());
+    }
+
+    let store = Store::new(&ctx)?;
+    let cursor = utils::param_from(&url, "cursor").map(String::from);
+    let page = list_page(&store, "", cursor, Some(FLUSH_KEY_LIMIT)).await?;
+    let mut deleted = 0;
+    // The worker's own keys are deleted as they are, while keys written by clients are deleted the way
+    // `DELETE /:key` deletes them, so whatever they leave behind goes with them even if the flush
+    // isn't carried on to the end.
+    let (env, store) = (&ctx.env, &store);
+    let results = join_all(page.keys.iter().map(|key| async move {
+        if is_system_key(&key.name) {
+            store.delete(&key.name).await.map_err(Error::from)
+        } else {
+            purge_key(env, store, &key.name).await
+        }
+    }))
+    .await;
+    for result in results {
+        result?;
+        deleted += 1;
+    }
+
+    let complete = page.list_complete;
+    Response::from_json(&FlushResponse {
+        deleted,
+        cursor: page.cursor.filter(|_| !complete),
+        complete,
+    })
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StructuredValue {
+    foo: String,
+    bar: i32,
+}
+
+/// We might not always want to deal with having vague data types in our KV store we can use serde
+/// to write any serializable type to a key. So let's just only put the request body in the store
+/// if it matches the schema for [StructuredValue], or the JSON Schema configured in the
+/// `STRUCTURED_SCHEMA` environment variable if there is one.
+async fn structured_put(mut req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &write_key_param(&ctx)?;
+
+    let url = req.url()?;
+    let content_type = req
+        .headers()
+        .get("content-type")?
+        .map(|content_type| utils::media_type_essence(&content_type));
+    let schema = ctx.var(STRUCTURED_SCHEMA_VAR);
+
+    let body: serde_json::Value = match content_type.as_deref() {
+        None | Some("application/json") => match req.json().await {
+            Ok(body) => body,
+            // Reject all requests that aren't even JSON.
+            Err(_) => return Err(ApiError::InvalidBody(None).into()),
+        },
+        // HTML forms can't send JSON, so let's accept form fields as well. Forms don't have types, so
+        // without a schema we parse them straight into a [StructuredValue] to turn numeric fields
+        // into numbers. A schema gets to see every field as a string.
+        Some("application/x-www-form-urlencoded") => {
+            let body = req.bytes().await?;
+            let parsed = match schema {
+                Ok(_) => serde_urlencoded::from_bytes::<BTreeMap<String, String>>(&body)
+                    .map(|fields| serde_json::json!(fields)),
+                Err(_) => serde_urlencoded::from_bytes::<StructuredValue>(&body)
+                    .map(|value| serde_json::json!(value)),
+            };
+
+            // Forms are easy to get subtly wrong by hand, so let's say which field is missing or
+            // couldn't be parsed.
+            match parsed {
+                Ok(body) => body,
+                Err(err) => return Err(ApiError::InvalidBody(Some(err.to_string())).into()),
+            }
+        }
+        // CBOR is a lot easier on small devices than JSON, but we still store the document as JSON.
+        Some("application/cbor") => {
+            let body = req.bytes().await?;
+            match ciborium::de::from_reader(body.as_slice()) {
+                Ok(body) => body,
+                Err(_) => return Err(ApiError::InvalidBody(None).into()),
+            }
+        }
+        // YAML is friendlier for documents people edit by hand.
+        Some("application/yaml") => {
+            let body = req.bytes().await?;
+            match serde_yaml::from_slice(&body) {
+                Ok(body) => body,
+                Err(err) => return Err(ApiError::InvalidBody(Some(err.to_string())).into()),
+            }
+        }
+        Some(_) => {
+            return Err(ApiError::UnsupportedMediaType(
+                "unsupported content-type, supported types are: application/json, \
+                 application/x-www-form-urlencoded, application/cbor, application/yaml"
+                    .into(),
+            )
+            .into())
+        }
+    };
+
+    let body = match schema {
+        // When we have a schema we'll let it decide what a valid document looks like, and tell the
+        // user everything that is wrong with their document if it doesn't match.
+        Ok(schema) => {
+            let errors = utils::schema_errors(&schema.to_string(), &body)?;
+            if !errors.is_empty() {
+                return Ok(
+                    Response::from_json(&serde_json::json!({ "errors": errors }))?.with_status(422),
+                );
+            }
+
+            body
+        }
+        // Otherwise reject all requests that don't follow our body schema.
+        Err(_) => match serde_json::from_value::<StructuredValue>(body) {
+            Ok(value) => serde_json::to_value(value)?,
+            Err(_) => return Err(ApiError::InvalidBody(None).into()),
+        },
+    };
+
+    // We need the document we're replacing to know which of its index entries have gone stale, and
+    // whether there was anything there at all to know if this write creates the key.
+    let old = read_structured(&ctx, &store, key).await?;
+    let replaced = replace_current(&ctx, &store, key).await?;
+    let created = !replaced.existed;
+
+    // The document is written the way `PUT /:key` writes values, so it's encrypted or deduplicated
+    // as configured. Let's add an expiration ttl if the user specifies one, or there's a default.
+    let document = body;
+    let body = serde_json::to_vec(&document)?;
+    let size = body.len() as u64;
+    let checksum = utils::sha256_hex(&body);
+    let ttl = write_ttl(&ctx, &url)?;
+    let metadata = ExampleMetadata {
+        checksum: Some(checksum.clone()),
+        created_at: match &replaced.metadata {
+            Some(old) => old.created_at,
+            None if created => Some(utils::now()),
+            None => None,
+        },
+        ..ExampleMetadata::new("application/json")
+    };
+    let metadata = store_value(&ctx.env, &store, key, body, metadata, ttl).await?;
+    let expiration = metadata.expiration;
+
+    if let Some(old) = &replaced.metadata {
+        if utils::media_type_essence(&old.content_type) != "application/json" {
+            unindex_content_type(&store, key, &old.content_type).await;
+        }
+    }
+    reindex_structured(&store, key, old.as_ref(), Some(&document), ttl).await;
+
+    let location = format!("/structured/{}", utils::percent_encode(key));
+    PutResponse {
+        ttl,
+        expiration,
+        value: Some(document),
+        metadata: Some(metadata),
+        ..PutResponse::new(key, size, "application/json", &checksum)
+    }
+    .into_response(&location, created)
+}
+
+/// The ways we can render a structured document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StructuredFormat {
+    Json,
+    PrettyJson,
+    Text,
+    Cbor,
+    Yaml,
+}
+
+// The content-types a structured document can be rendered as.
+const STRUCTURED_CONTENT_TYPES: &[&str] = &[
+    "application/json",
+    "text/plain",
+    "application/cbor",
+    "application/yaml",
+];
+
+/// Picks how to render a structured document from the request's `Accept` header, failing with a 406
+/// if the client won't take anything we can render.
+fn structured_format(req: &Request) -> Result<StructuredFormat> {
+    let url = req.url()?;
+    let pretty = utils::param_from(&url, "pretty").is_some_and(|pretty| pretty == "1");
+
+    for range in utils::accepted_media_ranges(req) {
+        if range.matches("application/json") {
+            return Ok(if pretty || range.has_param("pretty") {
+                StructuredFormat::PrettyJson
+            } else {
+                StructuredFormat::Json
+            });
+        }
+        if range.matches("text/plain") {
+            return Ok(StructuredFormat::Text);
+        }
+        if range.matches("application/cbor") {
+            return Ok(StructuredFormat::Cbor);
+        }
+        if range.matches("application/yaml") {
+            return Ok(StructuredFormat::Yaml);
+        }
+    }
+
+    Err(ApiError::NotAcceptable(format!(
+        "not acceptable, supported types are: {}",
+        STRUCTURED_CONTENT_TYPES.join(", ")
+    ))
+    .into())
+}
+
+/// Renders a document as `field = value` lines for people to read.
+fn structured_text(value: &serde_json::Value) -> String {
+    let render = |value: &serde_json::Value| match value {
+        serde_json::Value::String(string) => string.clone(),
+        value => value.to_string(),
+    };
+
+    match value {
+        serde_json::Value::Object(fields) => fields
+            .iter()
+            .map(|(field, value)| format!("{} = {}\n", field, render(value)))
+            .collect(),
+        value => format!("{}\n", render(value)),
+    }
+}
+
+/// Reads a structured document, treating soft deleted keys as if they don't exist.
+async fn read_structured(
+    ctx: &RouteContext<Retries>,
+    store: &Store,
+    key: &str,
+) -> Result<Option<serde_json::Value>> {
+    // Documents are read back the way `GET /:key` reads values, so ones that were encrypted or
+    // deduplicated can be read too.
+    let (value, metadata) = read_value(store, key).await?;
+    let value = match (value, metadata) {
+        (Some(_), Some(metadata)) if metadata.deleted_at.is_some() => return Ok(None),
+        (Some(value), Some(metadata)) => match decrypt_value(&ctx.env, value, &metadata) {
+            Ok(value) => value,
+            Err(err) => return Err(ApiError::Internal(err.to_string()).into()),
+        },
+        (Some(value), None) => value,
+        (None, _) => return Ok(None),
+    };
+
+    // Documents validated by a configured schema can have any shape, so we can only read them back
+    // as plain JSON.
+    let value = if ctx.var(STRUCTURED_SCHEMA_VAR).is_ok() {
+        serde_json::from_slice::<serde_json::Value>(&value)
+    } else {
+        serde_json::from_slice::<StructuredValue>(&value).map(|value| serde_json::json!(value))
+    };
+
+    // The key might have already been inserted with out non-structured put endpoint, so let's
+    // pretend it doesn't exist if it's invalid.
+    Ok(value.ok())
+}
+
+async fn structured_get(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+    authorize_read(&req, &ctx, key)?;
+    let format = structured_format(&req)?;
+
+    match read_structured(&ctx, &store, key).await? {
+        Some(value) => match format {
+            StructuredFormat::Json => Response::from_json(&value),
+            StructuredFormat::PrettyJson => {
+                let mut headers = Headers::default();
+                headers.append("content-type", "application/json")?;
+                Ok(Response::ok(serde_json::to_string_pretty(&value)?)?.with_headers(headers))
+            }
+            StructuredFormat::Text => {
+                let mut headers = Headers::default();
+                headers.append("content-type", "text/plain; charset=utf-8")?;
+                Ok(Response::ok(structured_text(&value))?.with_headers(headers))
+            }
+            StructuredFormat::Cbor => {
+                let mut body = Vec::new();
+                ciborium::ser::into_writer(&value, &mut body)
+                    .map_err(|err| Error::RustError(err.to_string()))?;
+
+                let mut headers = Headers::default();
+                headers.append("content-type", "application/cbor")?;
+                Ok(Response::from_bytes(body)?.with_headers(headers))
+            }
+            StructuredFormat::Yaml => {
+                let body = serde_yaml::to_string(&value)
+                    .map_err(|err| Error::RustError(err.to_string()))?;
+
+                let mut headers = Headers::default();
+                headers.append("content-type", "application/yaml")?;
+                Ok(Response::ok(body)?.with_headers(headers))
+            }
+        },
+        None => Err(ApiError::KeyNotFound.into()),
+    }
+}
+
+/// Picks parts of a structured document out with a JSONPath expression, so big documents can be
+/// inspected without fetching all of them. Every match is returned, in a JSON array.
+async fn structured_query(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    let store = Store::new(&ctx)?;
+    let key = &read_key_param(&ctx)?;
+    authorize_read(&req, &ctx, key)?;
+
+    let url = req.url()?;
+    let path: String = utils::param_required(&url, "path")?;
+    let path = match JsonPathInst::from_str(&path) {
+        Ok(path) => path,
+        Err(err) => return Err(ApiError::InvalidRequest(format!("invalid path: {}", err)).into()),
+    };
+
+    let value = match read_structured(&ctx, &store, key).await? {
+        Some(value) => value,
+        None => return Err(ApiError::KeyNotFound.into()),
+    };
+
+    let matches: Vec<serde_json::Value> = path
+        .find_slice(&value, JsonPathConfig::default())
+        .into_iter()
+        .map(|found| (*found).clone())
+        .collect();
+    Response::from_json(&matches)
+}
+
+#[derive(Debug, Serialize)]
+struct SearchMatch {
+    key: String,
+    value: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResponse {
+    matches: Vec<SearchMatch>,
+    scanned: u64,
+    // Whether the scan stopped before looking at every key under the prefix, in which case it can be
+    // carried on from `cursor`.
+    partial: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cursor: Option<String>,
+}
+
+/// The content-type to store values with when we don't know any better.
+fn default_content_type(ctx: &RouteContext<Retries>) -> Result<String> {
+    match utils::var(ctx, DEFAULT_CONTENT_TYPE_VAR) {
+        Some(content_type) if utils::is_media_type(&content_type) => Ok(content_type),
+        Some(_) => Err(Error::RustError("invalid DEFAULT_CONTENT_TYPE".into())),
+        None => Ok(DEFAULT_CONTENT_TYPE.into()),
+    }
+}
+
+/// The biggest value that can be written, which is never more than KV allows.
+fn max_value_size(ctx: &RouteContext<Retries>) -> Result<usize> {
+    match utils::var(ctx, MAX_VALUE_SIZE_VAR) {
+        Some(size) => size
+            .parse::<usize>()
+            .map(|size| size.min(MAX_VALUE_SIZE))
+            .map_err(|_| Error::RustError("invalid MAX_VALUE_SIZE".into())),
+        None => Ok(MAX_VALUE_SIZE),
+    }
+}
+
+/// The ttl for a write, which is the one the request asked for or else the configured default.
+fn write_ttl(ctx: &RouteContext<Retries>, url: &Url) -> Result<Option<u64>> {
+    let requested = utils::param_parse::<u64>(url, "ttl")?;
+    resolve_ttl(requested, utils::var(ctx, DEFAULT_TTL_VAR))
+}
+
+/// The ttl that keeps the expiration a value already had when it's written again. KV won't expire
+/// keys any sooner than `MIN_TTL`, so a value that was about to expire gets a little longer.
+fn kept_ttl(expiration: Option<u64>, now: u64) -> Option<u64> {
+    expiration.map(|expiration| expiration.saturating_sub(now).max(MIN_TTL))
+}
+
+/// Picks the ttl for a write from the one that was asked for and the `DEFAULT_TTL` variable, if
+/// it's set. KV won't expire keys any sooner than `MIN_TTL`, so shorter ones are rejected.
+fn resolve_ttl(requested: Option<u64>, default: Option<String>) -> Result<Option<u64>> {
+    if let Some(ttl) = requested {
+        if ttl < MIN_TTL {
+            return Err(ApiError::InvalidRequest(format!(
+                "ttl must be at least {} seconds",
+                MIN_TTL
+            ))
+            .into());
+        }
+        return Ok(Some(ttl));
+    }
+
+    match default {
+        Some(ttl) => match ttl.parse() {
+            Ok(ttl) if ttl >= MIN_TTL => Ok(Some(ttl)),
+            _ => Err(Error::RustError("invalid DEFAULT_TTL".into())),
+        },
+        None => Ok(None),
+    }
+}
+
+fn history_depth(ctx: &RouteContext<Retries>) -> Result<u64> {
+    match utils::var(ctx, HISTORY_DEPTH_VAR) {
+        Some(depth) => depth
+            .parse()
+            .map_err(|_| Error::RustError("invalid HISTORY_DEPTH".into())),
+        None => Ok(0),
+    }
+}
+
+fn scan_limit(ctx: &RouteContext<Retries>) -> Result<u64> {
+    match utils::var(ctx, SCAN_LIMIT_VAR) {
+        Some(limit) => limit
+            .parse()
+            .map_err(|_| Error::RustError("invalid SCAN_LIMIT".into())),
+        None => Ok(DEFAULT_SCAN_LIMIT),
+    }
+}
+
+/// Reads the structured documents under a prefix, carrying on from `cursor` if there is one, and
+/// keeps the ones `matches` accepts. There's no index behind this, so every key has to be read and
+/// checked one by one, which makes it slow for big prefixes. To keep it from running forever it
+/// stops after looking at `SCAN_LIMIT` keys and hands back a cursor to carry on from.
+async fn scan_structured(
+    ctx: &RouteContext<Retries>,
+    url: &Url,
+    matches: impl Fn(&serde_json::Value) -> bool,
+) -> Result<SearchResponse> {
+    let store = Store::new(ctx)?;
+    let scan_limit = scan_limit(ctx)?;
+    let prefix = utils::param_from(url, "prefix")
+        .map(String::from)
+        .unwrap_or_default();
+    let mut cursor = utils::param_from(url, "cursor").map(String::from);
+
+    let mut response = SearchResponse {
+        matches: Vec::new(),
+        scanned: 0,
+        partial: false,
+        cursor: None,
+    };
+    while response.scanned < scan_limit {
+        let page = list_page(&store, &prefix, cursor, Some(scan_limit - response.scanned)).await?;
+        let keys: Vec<_> = page
+            .keys
+            .into_iter()
+            .filter(|key| !is_tombstone(key) && !is_system_key(&key.name))
+            .map(|key| key.name)
+            .collect();
+        response.scanned += keys.len() as u64;
+
+        // Let's read the whole page at the same time rather than waiting on each key in turn.
+        let documents = join_all(keys.iter().map(|key| read_structured(ctx, &store, key))).await;
+        for (key, document) in keys.into_iter().zip(documents) {
+            if let Some(document) = document? {
+                if matches(&document) {
+                    response.matches.push(SearchMatch {
+                        key,
+                        value: document,
+                    });
+                }
+            }
+        }
+
+        if page.list_complete {
+            return Ok(response);
+        }
+        cursor = page.cursor;
+    }
+
+    response.partial = true;
+    response.cursor = cursor;
+    Ok(response)
+}
+
+/// Checks if a document's field holds the value from a search. Strings are compared as is, numbers
+/// are compared numerically so `5` matches `5.0`.
+fn field_equals(document: &serde_json::Value, field: &str, expected: &str) -> bool {
+    match document.get(field) {
+        Some(serde_json::Value::String(value)) => value == expected,
+        Some(serde_json::Value::Number(value)) => match (value.as_i64(), expected.parse::<i64>()) {
+            (Some(value), Ok(expected)) => value == expected,
+            _ => value.as_f64() == expected.parse::<f64>().ok(),
+        },
+        _ => false,
+    }
+}
+
+/// Finds the structured documents under a prefix with a field set to a value. Like every scan this
+/// isn't indexed, so it's O(n) in the number of keys under the prefix.
+async fn structured_search(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    authorize_private_read(&req, &ctx)?;
+    let url = req.url()?;
+    let field: String = utils::param_required(&url, "field")?;
+    let value: String = utils::param_required(&url, "value")?;
+
+    let response = scan_structured(&ctx, &url, |document| {
+        field_equals(document, &field, &value)
+    })
+    .await?;
+    Response::from_json(&response)
+}
+
+/// The comparisons a query can make against a field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueryOp {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Contains,
+}
+
+impl FromStr for QueryOp {
+    type Err = Error;
+
+    fn from_str(op: &str) -> Result<Self> {
+        match op {
+            "eq" => Ok(QueryOp::Eq),
+            "ne" => Ok(QueryOp::Ne),
+            "gt" => Ok(QueryOp::Gt),
+            "lt" => Ok(QueryOp::Lt),
+            "contains" => Ok(QueryOp::Contains),
+            _ => Err(ApiError::InvalidRequest(
+                "unknown op, supported ops are: eq, ne, gt, lt, contains".into(),
+            )
+            .into()),
+        }
+    }
+}
+
+/// Checks that a query makes sense for the field it's on, explaining why with a 400 if it doesn't.
+/// Without a schema we know the type of every field of [StructuredValue], but a schema could allow
+/// anything, so all we can do is make sure numeric comparisons are against a number.
+fn query_violation(schema: bool, field: &str, op: QueryOp, value: &str) -> Option<String> {
+    let numeric = value.parse::<f64>().is_ok();
+    match (schema, field, op) {
+        (_, _, QueryOp::Gt) | (_, _, QueryOp::Lt) if !numeric => {
+            Some("gt and lt can only compare against a number".into())
+        }
+        (true, _, _) => None,
+        (false, "foo", QueryOp::Gt) | (false, "foo", QueryOp::Lt) => {
+            Some("foo is a string, which can't be compared with gt or lt".into())
+        }
+        (false, "foo", _) => None,
+        (false, "bar", QueryOp::Contains) => {
+            Some("bar is a number, which can't be compared with contains".into())
+        }
+        (false, "bar", _) if value.parse::<i32>().is_err() => {
+            Some("bar is an integer, so it can only be compared against one".into())
+        }
+        (false, "bar", _) => None,
+        (false, field, _) => Some(format!("unknown field {}, fields are: foo, bar", field)),
+    }
+}
+
+/// Checks if a document's field passes a query.
+fn query_matches(document: &serde_json::Value, field: &str, op: QueryOp, value: &str) -> bool {
+    let actual = match document.get(field) {
+        Some(actual) => actual,
+        None => return false,
+    };
+
+    match op {
+        QueryOp::Eq => field_equals(document, field, value),
+        QueryOp::Ne => !field_equals(document, field, value),
+        QueryOp::Gt => actual
+            .as_f64()
+            .zip(value.parse().ok())
+            .is_some_and(|(a, v)| a > v),
+        QueryOp::Lt => actual
+            .as_f64()
+            .zip(value.parse().ok())
+            .is_some_and(|(a, v)| a < v),
+        QueryOp::Contains => actual.as_str().is_some_and(|actual| actual.contains(value)),
+    }
+}
+
+/// Finds the structured documents under a prefix with a field passing a comparison, like `bar > 10`.
+/// Like every scan this isn't indexed, so it's O(n) in the number of keys under the prefix.
+async fn structured_filter(req: Request, ctx: RouteContext<Retries>) -> Result<Response> {
+    authorize_private_read(&req, &ctx)?;
+    let url = req.url()?;
+    let field: String = utils::param_required(&url, "field")?;
+    let op: QueryOp = utils::param_required::<String>(&url, "op")?.parse()?;
+    let value: String = utils::param_required(&url, "value")?;
+
+    let schema = ctx.var(STRUCTURED_SCHEMA_VAR).is_ok();
+    if let Some(violation) = query_violation(schema, &field, op, &value) {
+        return Err(ApiError::InvalidRequest(violation).into());
+    }
+
+    let response = scan_structured(&ctx, &url, |document| {
+        query_matches(document, &field, op, &value)
+    })
+    .await?;
+    Response::from_json(&response)
+}
+
+/// A route we serve, used to describe the API to clients.
+#[derive(Debug, Serialize)]
+struct Endpoint {
+    method: &'static str,
+    path: &'static str,
+    description: &'static str,
+}
+
+impl Endpoint {
+    const fn new(method: &'static str, path: &'static str, description: &'static str) -> Self {
+        Self {
+            method,
+            path,
+            description,
+        }
+    }
+}
+
+// Every route registered in `main`, which should be kept up to date whenever a route is added.
+const ENDPOINTS: &[Endpoint] = &[
+    Endpoint::new("GET", "/", "describes the API"),
+    Endpoint::new(
+        "GET",
+        "/openapi.json",
+        "describes the API in the OpenAPI format",
+    ),
+    Endpoint::new("GET", "/version", "describes the build that is deployed"),
+    Endpoint::new("GET", "/list", "lists keys, optionally under a prefix"),
+    Endpoint::new(
+        "GET",
+        "/export",
+        "exports keys and values as newline-delimited JSON",
+    ),
+    Endpoint::new(
+        "POST",
+        "/import",
+        "imports newline-delimited JSON produced by /export",
+    ),
+    Endpoint::new("PUT", "/:key", "stores the request body under a key"),
+    Endpoint::new("GET", "/:key", "reads the value of a key"),
+    Endpoint::new(
+        "HEAD",
+        "/:key",
+        "describes the value of a key without reading it",
+    ),
+    Endpoint::new(
+        "GET",
+        "/meta/:key",
+        "describes the value of a key as JSON without reading it",
+    ),
+    Endpoint::new(
+        "PATCH",
+        "/meta/:key",
+        "changes the metadata of a key without writing its value",
+    ),
+    Endpoint::new("DELETE", "/:key", "deletes a key"),
+    Endpoint::new(
+        "GET",
+        "/multi/:keys",
+        "reads a comma-separated list of keys",
+    ),
+    Endpoint::new(
+        "POST",
+        "/batch/exists",
+        "checks which of a JSON array of keys exist",
+    ),
+    Endpoint::new(
+        "POST",
+        "/bulk/put",
+        "writes a JSON array of entries shaped like the lines of an export",
+    ),
+    Endpoint::new(
+        "GET",
+        "/:key/previous",
+        "reads the value a key had before it was last overwritten",
+    ),
+    Endpoint::new(
+        "GET",
+        "/:key/history",
+        "lists the older versions of a key kept in its history",
+    ),
+    Endpoint::new(
+        "GET",
+        "/:key/history/:version",
+        "reads a version of a key from its history",
+    ),
+    Endpoint::new("GET", "/:key/text", "reads the value of a key as text"),
+    Endpoint::new(
+        "GET",
+        "/:key/inspect",
+        "shows what's stored in a key for debugging",
+    ),
+    Endpoint::new("POST", "/:key/append", "adds to the end of a key's value"),
+    Endpoint::new(
+        "POST",
+        "/:key/restore",
+        "rolls a key back to its previous value",
+    ),
+    Endpoint::new(
+        "POST",
+        "/:key/rollback",
+        "rolls a key back to a version from its history",
+    ),
+    Endpoint::new("POST", "/:key/touch", "extends a key's expiration"),
+    Endpoint::new(
+        "POST",
+        "/counter/:key/increment",
+        "adds to a counter, optionally through a Durable Object",
+    ),
+    Endpoint::new(
+        "POST",
+        "/:key/sign",
+        "creates a URL that can read a key until it expires",
+    ),
+    Endpoint::new(
+        "PUT",
+        "/:key/with-meta",
+        "stores a base64 encoded value along with arbitrary JSON metadata",
+    ),
+    Endpoint::new("POST", "/undelete/:key", "restores a soft deleted key"),
+    Endpoint::new(
+        "PUT",
+        "/structured/:key",
+        "stores a JSON document under a key",
+    ),
+    Endpoint::new("GET", "/structured/:key", "reads a JSON document"),
+    Endpoint::new(
+        "GET",
+        "/structured/search",
+        "finds the JSON documents with a field set to a value",
+    ),
+    Endpoint::new(
+        "GET",
+        "/structured/by/:field/:value",
+        "lists the JSON documents with a field set to a value using an index",
+    ),
+    Endpoint::new(
+        "GET",
+        "/structured/query",
+        "finds the JSON documents with a field passing a comparison",
+    ),
+    Endpoint::new(
+        "GET",
+        "/structured/:key/query",
+        "picks parts of a JSON document out with a JSONPath expression",
+    ),
+    Endpoint::new(
+        "PUT",
+        "/keys/*key",
+        "stores the request body under a key containing slashes",
+    ),
+    Endpoint::new(
+        "GET",
+        "/keys/*key",
+        "reads the value of a key containing slashes",
+    ),
+    Endpoint::new(
+        "HEAD",
+        "/keys/*key",
+        "describes the value of a key containing slashes without reading it",
+    ),
+    Endpoint::new("DELETE", "/keys/*key", "deletes a key containing slashes"),
+    Endpoint::new(
+        "PUT",
+        "/structured/keys/*key",
+        "stores a JSON document under a key containing slashes",
+    ),
+    Endpoint::new(
+        "GET",
+        "/structured/keys/*key",
+        "reads a JSON document from a key containing slashes",
+    ),
+    Endpoint::new(
+        "GET",
+        "/by-content-type/*type",
+        "lists the keys with a content-type",
+    ),
+    Endpoint::new(
+        "GET",
+        "/stats/usage",
+        "adds up the size of the values under a prefix",
+    ),
+    Endpoint::new(
+        "GET",
+        "/stats/store",
+        "shows how often the store was unavailable",
+    ),
+    Endpoint::new(
+        "GET",
+        "/content-types",
+        "counts the keys of each content-type under a prefix",
+    ),
+    Endpoint::new("POST", "/locks/:name", "acquires a lock"),
+    Endpoint::new("DELETE", "/locks/:name", "releases a lock"),
+    Endpoint::new(
+        "POST",
+        "/admin/migrate",
+        "adds default metadata to keys written without any",
+    ),
+    Endpoint::new("DELETE", "/tags/:tag", "deletes every key with a tag"),
+    Endpoint::new(
+        "POST",
+        "/prefix-delete/:prefix/prepare",
+        "counts the keys under a prefix and hands out a token to delete them",
+    ),
+    Endpoint::new(
+        "POST",
+        "/prefix/:prefix/ttl",
+        "gives every key under a prefix a new expiration",
+    ),
+    Endpoint::new(
+        "DELETE",
+        "/prefix/:prefix",
+        "deletes every key under a prefix, given a token",
+    ),
+    Endpoint::new("DELETE", "/admin/flush", "deletes every key"),
+    Endpoint::new(
+        "GET",
+        "/admin/audit",
+        "reads the log of requests that changed the store",
+    ),
+];
+
+/// A small document describing what the API can do, for anyone poking around at the root.
+async fn index(_: Request, _: RouteContext<Retries>) -> Result<Response> {
+    Response::from_json(&serde_json::json!({
+        "name": env!("CARGO_PKG_NAME"),
+        "endpoints": ENDPOINTS,
+    }))
+}
+
+/// The OpenAPI description of the API. It can only change when the worker is deployed, so clients can
+/// hang on to it for a while.
+async fn openapi(_: Request, _: RouteContext<Retries>) -> Result<Response> {
+    let mut response = Response::from_json(&spec::openapi())?;
+    response
+        .headers_mut()
+        .set("cache-control", "public, max-age=86400")?;
+    Ok(response)
+}
+
+#[derive(Debug, Serialize)]
+struct VersionResponse {
+    version: &'static str,
+    commit: Option<&'static str>,
+    built_at: Option<u64>,
+    colo: Option<String>,
+}
+
+/// Tells us which build is deployed and where it's running. The commit and build time are baked in by
+/// `build.rs`, but local builds might not have them.
+async fn version(req: Request, _: RouteContext<Retries>) -> Result<Response> {
+    let colo = req.cf().colo();
+    Response::from_json(&VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: option_env!("GIT_COMMIT_HASH"),
+        built_at: option_env!("BUILD_TIMESTAMP").and_then(|timestamp| timestamp.parse().ok()),
+        colo: Some(colo).filter(|colo| !colo.is_empty()),
+    })
+}
+
+/// Requests that don't match any of our routes get pointed at the ones that exist.
+async fn not_found(_: Request, _: RouteContext<Retries>) -> Result<Response> {
+    Ok(Response::from_json(&serde_json::json!({
+        "error": "not found",
+        "endpoints": ENDPOINTS,
+    }))?
+    .with_status(404))
+}
+
+#[event(fetch)]
+pub async fn main(req: Request, env: Env, context: worker::Context) -> Result<Response> {
+    // Every request gets an ID we can use to find its log lines, which we also hand back to the
+    // client.
+    let request_id = utils::request_id(&req);
+    utils::log_request(&req, &request_id);
+    let json_errors = utils::prefers_json_errors(&req);
+
+    // Panics are logged along with the IDs of the requests in flight so we can find them later.
+    utils::set_panic_hook();
+    let _in_flight = utils::track_request(&request_id);
+
+    // Requests that change the store are recorded once we know how they went.
+    let audit_entry = AuditEntry::new(&req, &env, &request_id)?;
+
+    // The router takes the environment, but we need it afterwards to count failures of the store
+    // and write to the audit log. `Env` itself can't be cloned, the JavaScript object behind it can.
+    let stats_env: Env = env.clone().unchecked_into();
+    let audit_env: Env = env.clone().unchecked_into();
+
+    // We can use a Router to route our incoming requests to our handlers, using `:param` syntax to
+    // add URL patterns or `*name` for catch-alls.
+    // Each request keeps track of its own retries, which every store it opens shares.
+    let retries = Retries::default();
+    let result = Router::with_data(retries.clone())
+        .get_async("/", index)
+        .get_async("/openapi.json", openapi)
+        .get_async("/version", version)
+        .get_async("/list", list)
+        .get_async("/export", export)
+        .post_async("/import", |req, ctx| idempotent(req, ctx, import))
+        .put_async("/:key", |req, ctx| idempotent(req, ctx, put))
+        .get_async("/:key", get)
+        .head_async("/:key", head)
+        .get_async("/meta/:key", meta)
+        .patch_async("/meta/:key", patch_meta)
+        .get_async("/multi/:keys", multi_get)
+        .post_async("/batch/exists", batch_exists)
+        .post_async("/bulk/put", |req, ctx| idempotent(req, ctx, bulk_put))
+        .get_async("/:key/previous", previous)
+        .get_async("/:key/history", history)
+        .get_async("/:key/history/:version", history_version)
+        .get_async("/:key/text", text)
+        .get_async("/:key/inspect", inspect)
+        .post_async("/:key/restore", restore)
+        .post_async("/:key/rollback", rollback)
+        .post_async("/:key/append", append)
+        .post_async("/:key/touch", touch)
+        .post_async("/counter/:key/increment", increment)
+        .post_async("/:key/sign", sign)
+        .put_async("/:key/with-meta", put_with_meta)
+        .delete_async("/:key", delete)
+        .post_async("/undelete/:key", undelete)
+        .put_async("/structured/:key", |req, ctx| {
+            idempotent(req, ctx, structured_put)
+        })
+        .get_async("/structured/:key", structured_get)
+        .get_async("/structured/search", structured_search)
+        .get_async("/structured/query", structured_filter)
+        .get_async("/structured/by/:field/:value", structured_by)
+        .get_async("/structured/:key/query", structured_query)
+        // Keys containing a `/` can't be matched by `:key`, so these catch-all routes let them be
+        // used without having to encode every slash.
+        .put_async("/keys/*key", |req, ctx| idempotent(req, ctx, put))
+        .get_async("/keys/*key", get)
+        .head_async("/keys/*key", head)
+        .delete_async("/keys/*key", delete)
+        .put_async("/structured/keys/*key", |req, ctx| {
+            idempotent(req, ctx, structured_put)
+        })
+        .get_async("/structured/keys/*key", structured_get)
+        .get_async("/by-content-type/*type", by_content_type)
+        .get_async("/stats/usage", usage)
+        .get_async("/stats/store", store_stats)
+        .get_async("/content-types", content_types)
+        .post_async("/locks/:name", lock)
+        .delete_async("/locks/:name", unlock)
+        .post_async("/admin/migrate", migrate)
+        .delete_async("/tags/:tag", delete_tag)
+        .post_async("/prefix-delete/:prefix/prepare", prepare_prefix_delete)
+        .delete_async("/prefix/:prefix", prefix_delete)
+        .post_async("/prefix/:prefix/ttl", prefix_ttl)
+        .delete_async("/admin/flush", flush)
+        .get_async("/admin/audit", audit)
+        // This has to stay last so that it only catches requests none of the routes above match.
+        .or_else_any_method_async("/*path", not_found)
+        .run(req, env);
+
+    let result = result.await;
+
+    // Errors would otherwise be turned into a response by the runtime, so let's turn them into one
+    // ourselves so they get a request ID too.
+    // Errors are rendered as JSON or plain text depending on what the client prefers.
+    let mut response = match result {
+        Ok(response) => utils::negotiate_error(response, json_errors)?,
+        // Handlers can bail out early with an error that already knows which status it should have.
+        Err(Error::Json((message, status))) => {
+            let error = ApiError::from_status(status, &message);
+            // Counting happens after the response is sent, the client shouldn't wait on a store
+            // that's already struggling.
+            if let ApiError::Unavailable(reason) = &error {
+                console_error!("[{}] {}", request_id, reason);
+                context.wait_until(count_unavailable(stats_env));
+            }
+            utils::error_response(&error, json_errors)?
+        }
+        Err(err) => {
+            console_error!("[{}] {}", request_id, err);
+            let error = ApiError::Internal("internal server error".into());
+            utils::error_response(&error, json_errors)?
+        }
+    };
+
+    // Let's make it visible when the store needed retrying, both to the client and in our logs.
+    let retries = retries.outcome();
+    if retries.retries > 0 {
+        utils::log_retries(&request_id, retries);
+    }
+    if let Some(mut entry) = audit_entry {
+        entry.status = response.status_code();
+        context.wait_until(record_audit(audit_env, entry));
+    }
+    response.headers_mut().set("x-request-id", &request_id)?;
+    response
+        .headers_mut()
+        .set("x-kv-retries", &retries.retries.to_string())?;
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(err: Error) -> u16 {
+        match err {
+            Error::Json((_, status)) => status,
+            err => panic!("expected an ApiError, got {}", err),
+        }
+    }
+
+    fn listed(name: &str) -> kv::Key {
+        kv::Key {
+            name: name.into(),
+            expiration: None,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn listed_keys_have_the_prefix_stripped() {
+        let mut keys = vec![listed("staging/a"), listed("staging/b/c")];
+        unscope_keys("staging/", &mut keys);
+
+        let names: Vec<_> = keys.iter().map(|key| key.name.as_str()).collect();
+        assert_eq!(names, ["a", "b/c"]);
+    }
+
+    #[test]
+    fn listed_keys_are_untouched_without_a_prefix() {
+        let mut keys = vec![listed("a"), listed("staging/b")];
+        unscope_keys("", &mut keys);
+
+        let names: Vec<_> = keys.iter().map(|key| key.name.as_str()).collect();
+        assert_eq!(names, ["a", "staging/b"]);
+    }
+
+    fn with_metadata(name: &str, metadata: serde_json::Value) -> kv::Key {
+        kv::Key {
+            metadata: Some(metadata),
+            ..listed(name)
+        }
+    }
+
+    #[test]
+    fn deletes_go_ahead_when_the_version_matches() {
+        let current = with_metadata("a", serde_json::json!({ "checksum": "abc" }));
+        assert!(check_if_match(Some("\"abc\""), Some(&current)).is_ok());
+        assert!(check_if_match(Some("\"xyz\", \"abc\""), Some(&current)).is_ok());
+        assert!(check_if_match(Some("*"), Some(&current)).is_ok());
+    }
+
+    #[test]
+    fn deletes_fail_when_the_version_has_changed() {
+        let current = with_metadata("a", serde_json::json!({ "checksum": "abc" }));
+        let err = check_if_match(Some("\"xyz\""), Some(&current)).unwrap_err();
+        assert_eq!(status(err), 412);
+
+        // Keys written without a checksum can't match any version.
+        let err = check_if_match(Some("\"abc\""), Some(&listed("a"))).unwrap_err();
+        assert_eq!(status(err), 412);
+    }
+
+    #[test]
+    fn deletes_without_if_match_are_unconditional() {
+        assert!(check_if_match(None, None).is_ok());
+        let current = with_metadata("a", serde_json::json!({ "checksum": "abc" }));
+        assert!(check_if_match(None, Some(&current)).is_ok());
+    }
+
+    #[test]
+    fn conditional_deletes_of_missing_keys_are_not_found() {
+        assert_eq!(status(check_if_match(Some("*"), None).unwrap_err()), 404);
+
+        let tombstone = with_metadata(
+            "a",
+            serde_json::json!({ "checksum": "abc", "deleted_at": 1 }),
+        );
+        let err = check_if_match(Some("\"abc\""), Some(&tombstone)).unwrap_err();
+        assert_eq!(status(err), 404);
+    }
+
+    fn page(keys: Vec<kv::Key>) -> kv::ListResponse {
+        kv::ListResponse {
+            keys,
+            list_complete: true,
+            cursor: None,
+        }
+    }
+
+    #[test]
+    fn listings_include_metadata_by_default() {
+        let keys = vec![with_metadata(
+            "a",
+            serde_json::json!({ "content_type": "text/plain" }),
+        )];
+        let json = serde_json::to_value(ListResult::from(page(keys))).unwrap();
+        assert_eq!(
+            json["keys"][0]["metadata"],
+            serde_json::json!({ "content_type": "text/plain" })
+        );
+    }
+
+    #[test]
+    fn listings_can_leave_metadata_out() {
+        let keys = vec![
+            with_metadata("a", serde_json::json!({ "content_type": "text/plain" })),
+            listed("b"),
+        ];
+        let result = ListResult::from(page(keys)).without_metadata();
+        let json = serde_json::to_value(result).unwrap();
+
+        for key in json["keys"].as_array().unwrap() {
+            assert!(key.get("metadata").is_none(), "{} has metadata", key);
+        }
+        assert_eq!(json["keys"][0]["name"], "a");
+    }
+
+    #[test]
+    fn listings_only_include_expirations_for_keys_that_expire() {
+        let keys = vec![
+            kv::Key {
+                expiration: Some(1_700_000_000),
+                ..listed("expiring")
+            },
+            listed("lasting"),
+        ];
+        let json = serde_json::to_value(ListResult::from(page(keys))).unwrap();
+
+        assert_eq!(
+            json["keys"],
+            serde_json::json!([
+                { "name": "expiring", "expiration": 1_700_000_000 },
+                { "name": "lasting" },
+            ])
+        );
+    }
+
+    #[test]
+    fn rewritten_values_keep_their_expiration() {
+        assert_eq!(kept_ttl(Some(1_000_600), 1_000_000), Some(600));
+        assert_eq!(kept_ttl(Some(1_000_600), 1_000_590), Some(MIN_TTL));
+        assert_eq!(kept_ttl(Some(1_000_600), 2_000_000), Some(MIN_TTL));
+        assert_eq!(kept_ttl(None, 1_000_000), None);
+    }
+
+    #[test]
+    fn tombstones_of_lasting_keys_are_kept_for_the_tombstone_ttl() {
+        assert_eq!(tombstone_ttl(None, 3600, 1_000_000), 3600);
+        assert_eq!(tombstone_ttl(Some(2_000_000), 3600, 1_000_000), 3600);
+    }
+
+    #[test]
+    fn tombstones_go_when_their_key_would_have_expired() {
+        assert_eq!(tombstone_ttl(Some(1_000_600), 3600, 1_000_000), 600);
+        assert_eq!(tombstone_ttl(Some(1_000_000), 3600, 1_000_000), MIN_TTL);
+    }
+
+    #[test]
+    fn writes_only_expire_when_asked_to_or_by_default() {
+        assert_eq!(resolve_ttl(None, None).unwrap(), None);
+        assert_eq!(resolve_ttl(None, Some("3600".into())).unwrap(), Some(3600));
+    }
+
+    #[test]
+    fn the_requested_ttl_wins_over_the_default() {
+        assert_eq!(
+            resolve_ttl(Some(120), Some("3600".into())).unwrap(),
+            Some(120)
+        );
+        assert_eq!(resolve_ttl(Some(120), None).unwrap(), Some(120));
+    }
+
+    #[test]
+    fn ttls_shorter_than_kv_allows_are_rejected() {
+        assert_eq!(status(resolve_ttl(Some(59), None).unwrap_err()), 400);
+        assert!(resolve_ttl(Some(MIN_TTL), None).is_ok());
+    }
+
+    #[test]
+    fn invalid_default_ttls_are_rejected() {
+        assert!(resolve_ttl(None, Some("soon".into())).is_err());
+        assert!(resolve_ttl(None, Some("59".into())).is_err());
+    }
+
+    #[test]
+    fn identical_values_share_a_blob() {
+        let blob = BlobMetadata::new(Some("nonce".into()));
+        assert_eq!(blob.refs, 1);
+
+        let blob = blob.referenced().referenced();
+        assert_eq!(blob.refs, 3);
+        assert_eq!(blob.nonce.as_deref(), Some("nonce"));
+    }
+
+    #[test]
+    fn blobs_are_kept_while_other_keys_point_at_them() {
+        let blob = BlobMetadata::new(None).referenced().released().unwrap();
+        assert_eq!(blob.refs, 1);
+    }
+
+    #[test]
+    fn blobs_go_with_the_last_key_pointing_at_them() {
+        assert!(BlobMetadata::new(None).released().is_none());
+        assert!(BlobMetadata::new(None)
+            .referenced()
+            .released()
+            .and_then(BlobMetadata::released)
+            .is_none());
+    }
+
+    fn metadata(json: serde_json::Value) -> ExampleMetadata {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn previous_versions_holding_a_blob_dont_expire() {
+        let deduplicated = metadata(serde_json::json!({
+            "content_type": "text/plain",
+            "blob": "abc",
+        }));
+        assert_eq!(previous_ttl(Some(&deduplicated)), None);
+    }
+
+    #[test]
+    fn other_previous_versions_expire() {
+        let plain = metadata(serde_json::json!({ "content_type": "text/plain" }));
+        assert_eq!(previous_ttl(Some(&plain)), Some(PREVIOUS_TTL));
+        assert_eq!(previous_ttl(None), Some(PREVIOUS_TTL));
+    }
+
+    #[test]
+    fn values_matching_their_checksum_pass() {
+        let checksummed = metadata(serde_json::json!({
+            "content_type": "text/plain",
+            "checksum": utils::sha256_hex(b"hello").to_uppercase(),
+        }));
+        assert!(check_checksum(b"hello", &checksummed).is_ok());
+    }
+
+    #[test]
+    fn values_not_matching_their_checksum_fail() {
+        let checksummed = metadata(serde_json::json!({
+            "content_type": "text/plain",
+            "checksum": utils::sha256_hex(b"hello"),
+        }));
+        assert_eq!(
+            status(check_checksum(b"hello!", &checksummed).unwrap_err()),
+            500
+        );
+    }
+
+    #[test]
+    fn values_without_a_checksum_pass() {
+        let plain = metadata(serde_json::json!({ "content_type": "text/plain" }));
+        assert!(check_checksum(b"anything", &plain).is_ok());
+    }
+
+    #[test]
+    fn missing_counters_start_from_zero() {
+        assert_eq!(counter_value(None).unwrap(), 0);
+        assert_eq!(next_counter_value(0, 5).unwrap(), 5);
+    }
+
+    #[test]
+    fn counters_carry_on_from_their_value() {
+        assert_eq!(counter_value(Some(b"41\n")).unwrap(), 41);
+        assert_eq!(next_counter_value(41, 1).unwrap(), 42);
+        assert_eq!(next_counter_value(41, -50).unwrap(), -9);
+    }
+
+    #[test]
+    fn keys_not_holding_a_counter_conflict() {
+        assert_eq!(status(counter_value(Some(b"hello")).unwrap_err()), 409);
+        assert_eq!(status(counter_value(Some(&[0xff])).unwrap_err()), 409);
+    }
+
+    #[test]
+    fn counters_conflict_rather_than_overflow() {
+        assert_eq!(status(next_counter_value(i64::MAX, 1).unwrap_err()), 409);
+        assert_eq!(status(next_counter_value(i64::MIN, -1).unwrap_err()), 409);
+    }
+
+    #[test]
+    fn keys_are_decoded_from_their_route() {
+        assert_eq!(decode_key("my%20notes").unwrap(), "my notes");
+        assert_eq!(decode_key("a%2Fb").unwrap(), "a/b");
+        assert_eq!(decode_key("caf%C3%A9").unwrap(), "café");
+        // The catch-all routes under `/keys/` hand the key over with a leading slash.
+        assert_eq!(decode_key("/a/b").unwrap(), "a/b");
+        assert_eq!(decode_key("/%2Fa").unwrap(), "/a");
+        assert_eq!(status(decode_key("%FF").unwrap_err()), 400);
+    }
+
+    #[test]
+    fn encoded_keys_decode_to_themselves() {
+        for key in ["my notes", "a/b", "a%2Fb", "日本/café"] {
+            assert_eq!(decode_key(&utils::percent_encode(key)).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn keys_breaking_the_rules_are_rejected() {
+        let too_long = "a".repeat(MAX_KEY_LENGTH + 1);
+        let cases: &[(&str, Option<&str>)] = &[
+            ("", Some("key must not be empty")),
+            (&too_long, Some("key must be at most 512 bytes")),
+            ("openapi.json", Some("openapi.json is a reserved key")),
+            ("prefix", Some("prefix is a reserved key")),
+            ("__mine", Some("keys starting with __ are reserved")),
+            ("notes/__mine", None),
+            ("prefix/a", None),
+            ("a", None),
+        ];
+        for (key, violation) in cases {
+            assert_eq!(key_violation(key).as_deref(), *violation, "{:?}", key);
+        }
+        assert_eq!(key_violation(&"a".repeat(MAX_KEY_LENGTH)), None);
+    }
+
+    #[test]
+    fn keys_breaking_the_rules_cant_be_written() {
+        assert_eq!(status(writable_key("".into()).unwrap_err()), 400);
+        assert_eq!(status(writable_key("__mine".into()).unwrap_err()), 400);
+        assert_eq!(writable_key("a".into()).unwrap(), "a");
+    }
+
+    #[test]
+    fn keys_breaking_the_rules_arent_found() {
+        for key in ["", "openapi.json", "__mine"] {
+            assert_eq!(status(readable_key(key.into()).unwrap_err()), 404);
+        }
+        assert_eq!(
+            status(readable_key("a".repeat(MAX_KEY_LENGTH + 1)).unwrap_err()),
+            404
+        );
+        assert_eq!(readable_key("a".into()).unwrap(), "a");
+    }
+
+    #[test]
+    fn the_workers_own_keys_are_system_keys() {
+        for key in [
+            "__blobs__/abc",
+            "__prev__/a",
+            "__history__/a/1",
+            "__idx__/content-type/text/plain/a",
+            "__health__",
+        ] {
+            assert!(is_system_key(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn other_keys_arent_system_keys() {
+        for key in ["a", "__custom", "blobs/__blobs__/abc", "_idx_/a"] {
+            assert!(!is_system_key(key), "{}", key);
+        }
+    }
+
+    #[test]
+    fn system_keys_are_forbidden() {
+        assert_eq!(status(user_key("__prev__/a".into()).unwrap_err()), 403);
+        assert_eq!(user_key("notes/a".into()).unwrap(), "notes/a");
+    }
+
+    #[test]
+    fn system_keys_are_only_listed_when_asked_for() {
+        let keys = [listed("a"), listed("__blobs__/abc"), listed("__prev__/a")];
+
+        let filter = ListFilter::default();
+        let shown: Vec<_> = keys
+            .iter()
+            .filter(|key| filter.matches(key))
+            .map(|key| key.name.as_str())
+            .collect();
+        assert_eq!(shown, ["a"]);
+
+        let filter = ListFilter {
+            include_system: true,
+            ..ListFilter::default()
+        };
+        assert!(keys.iter().all(|key| filter.matches(key)));
+    }
+
+    #[test]
+    fn tombstones_are_never_listed() {
+        let filter = ListFilter {
+            include_system: true,
+            ..ListFilter::default()
+        };
+        let tombstone = with_metadata("a", serde_json::json!({ "deleted_at": 1 }));
+        assert!(!filter.matches(&tombstone));
+    }
+
+    #[test]
+    fn listings_can_be_narrowed_to_a_range_and_content_types() {
+        let filter = ListFilter {
+            start: Some("b".into()),
+            end: Some("d".into()),
+            content_types: vec!["text/plain".into()],
+            ..ListFilter::default()
+        };
+        let text = serde_json::json!({ "content_type": "text/plain" });
+        assert!(filter.matches(&with_metadata("b", text.clone())));
+        assert!(!filter.matches(&with_metadata("d", text.clone())));
+        assert!(!filter.matches(&with_metadata("a", text)));
+        assert!(!filter.matches(&listed("c")));
+        assert!(!filter.matches(&with_metadata(
+            "c",
+            serde_json::json!({ "content_type": "image/png" })
+        )));
+    }
+
+    /// An unfinished page of `count` keys named after their position in the whole listing.
+    fn unfinished_page(from: usize, count: u64) -> kv::ListResponse {
+        let keys = (from..from + count as usize)
+            .map(|i| listed(&format!("{:05}", i)))
+            .collect();
+        kv::ListResponse {
+            list_complete: false,
+            cursor: Some(format!("after-{}", from + count as usize)),
+            ..page(keys)
+        }
+    }
+
+    #[test]
+    fn listing_everything_stops_when_the_listing_is_complete() {
+        let mut listing = AllKeys::new(None);
+        assert_eq!(listing.next_limit(), Some(MAX_LIST_PAGE_SIZE));
+
+        listing.add(unfinished_page(0, 1000), &|_| true);
+        assert_eq!(listing.cursor.as_deref(), Some("after-1000"));
+        listing.add(page(vec![listed("last")]), &|_| true);
+        assert_eq!(listing.next_limit(), None);
+
+        let list = kv::ListResponse::from(listing);
+        assert_eq!(list.keys.len(), 1001);
+        assert!(list.list_complete);
+        assert_eq!(list.cursor, None);
+    }
+
+    #[test]
+    fn listing_everything_stops_at_the_key_limit() {
+        let mut listing = AllKeys::new(None);
+        let mut listed_keys = 0;
+        while let Some(limit) = listing.next_limit() {
+            listing.add(unfinished_page(listed_keys, limit), &|_| true);
+            listed_keys += limit as usize;
+        }
+
+        let list = kv::ListResponse::from(listing);
+        assert_eq!(list.keys.len() as u64, LIST_ALL_KEY_LIMIT);
+        // There's more to list, so the listing is truncated and can be carried on from its cursor.
+        assert!(!list.list_complete);
+        assert_eq!(list.cursor, Some(format!("after-{}", LIST_ALL_KEY_LIMIT)));
+    }
+
+    #[test]
+    fn pages_never_go_past_the_key_limit() {
+        let mut listing = AllKeys::new(None);
+        listing.add(unfinished_page(0, LIST_ALL_KEY_LIMIT - 10), &|_| true);
+        assert_eq!(listing.next_limit(), Some(10));
+    }
+
+    #[test]
+    fn listing_everything_stops_at_the_page_limit() {
+        let mut listing = AllKeys::new(Some("start".into()));
+        let mut pages = 0;
+        while listing.next_limit().is_some() {
+            // Nothing matches, so the key limit is never reached.
+            listing.add(unfinished_page(pages * 10, 10), &|_| false);
+            pages += 1;
+        }
+
+        assert_eq!(pages, LIST_ALL_PAGE_LIMIT);
+        let list = kv::ListResponse::from(listing);
+        assert!(list.keys.is_empty());
+        assert!(!list.list_complete);
+        assert!(list.cursor.is_some());
+    }
+
+    fn names(list: &kv::ListResponse) -> Vec<&str> {
+        list.keys.iter().map(|key| key.name.as_str()).collect()
+    }
+
+    #[test]
+    fn descending_listings_start_from_the_last_key() {
+        let mut list = page(vec![listed("a"), listed("b"), listed("c")]);
+        assert!(reverse_listing(&mut list, Some(2)));
+        assert_eq!(names(&list), ["c", "b"]);
+        assert!(!list.list_complete);
+    }
+
+    #[test]
+    fn descending_listings_that_fit_are_complete() {
+        let mut list = page(vec![listed("a"), listed("b")]);
+        assert!(!reverse_listing(&mut list, Some(2)));
+        assert_eq!(names(&list), ["b", "a"]);
+        assert!(list.list_complete);
+    }
+
+    #[test]
+    fn descending_listings_without_a_limit_keep_every_key() {
+        let mut list = page(vec![listed("a"), listed("b"), listed("c")]);
+        assert!(!reverse_listing(&mut list, None));
+        assert_eq!(names(&list), ["c", "b", "a"]);
+    }
+
+    #[test]
+    fn descending_listings_of_an_unfinished_walk_are_truncated() {
+        let mut list = kv::ListResponse {
+            list_complete: false,
+            cursor: Some("next".into()),
+            ..page(vec![listed("a"), listed("b")])
+        };
+        assert!(reverse_listing(&mut list, None));
+        assert_eq!(names(&list), ["b", "a"]);
+        assert!(!list.list_complete);
+        assert_eq!(list.cursor, None);
+    }
+
+    /// Turns a router path like `/:key/history/:version` into its OpenAPI form.
+    fn openapi_path(path: &str) -> String {
+        path.split('/')
+            .map(|segment| match segment.strip_prefix([':', '*']) {
+                Some(name) => format!("{{{}}}", name),
+                None => segment.into(),
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    #[test]
+    fn every_endpoint_is_in_the_openapi_description() {
+        let spec = spec::openapi();
+        let missing: Vec<String> = ENDPOINTS
+            .iter()
+            .filter(|endpoint| {
+                spec["paths"][openapi_path(endpoint.path)][endpoint.method.to_lowercase()].is_null()
+            })
+            .map(|endpoint| format!("{} {}", endpoint.method, endpoint.path))
+            .collect();
+        assert!(missing.is_empty(), "missing from the spec: {:?}", missing);
+    }
+
+    const INTEGRITY_KEY: &[u8] = b"integrity";
+
+    fn signed() -> ExampleMetadata {
+        let mut metadata: ExampleMetadata = serde_json::from_value(serde_json::json!({
+            "content_type": "text/plain",
+            "checksum": "abc",
+            "size": 3,
+        }))
+        .unwrap();
+        let message = signed_metadata_message(&metadata).unwrap();
+        metadata.signature = Some(utils::hmac_sha256_hex(INTEGRITY_KEY, &message));
+        metadata
+    }
+
+    #[test]
+    fn signed_metadata_passes() {
+        assert!(check_metadata_signature(INTEGRITY_KEY, Some(&signed())).is_ok());
+    }
+
+    #[test]
+    fn metadata_changed_after_signing_fails() {
+        let metadata = ExampleMetadata {
+            content_type: "text/html".into(),
+            ..signed()
+        };
+        let err = check_metadata_signature(INTEGRITY_KEY, Some(&metadata)).unwrap_err();
+        assert_eq!(status(err), 502);
+    }
+
+    #[test]
+    fn metadata_signed_with_another_key_fails() {
+        let err = check_metadata_signature(b"other", Some(&signed())).unwrap_err();
+        assert_eq!(status(err), 502);
+    }
+
+    #[test]
+    fn unsigned_metadata_fails() {
+        let mut metadata = signed();
+        metadata.signature = None;
+        let err = check_metadata_signature(INTEGRITY_KEY, Some(&metadata)).unwrap_err();
+        assert_eq!(status(err), 502);
+    }
+
+    #[test]
+    fn missing_metadata_fails() {
+        let err = check_metadata_signature(INTEGRITY_KEY, None).unwrap_err();
+        assert_eq!(status(err), 502);
+    }
+
+    const FORM_CONTENT_TYPE: &str = "multipart/form-data; boundary=\"boundary\"";
+
+    #[test]
+    fn the_file_is_taken_from_a_multipart_body() {
+        let body = b"--boundary\r\n\
+Content-Disposition: form-data; name=\"title\"\r\n\
+\r\n\
+holiday\r\n\
+--boundary\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+hello\r\n\
+--boundary--\r\n";
+
+        let file = multipart_file(FORM_CONTENT_TYPE, body).unwrap();
+        assert_eq!(file.filename.as_deref(), Some("notes.txt"));
+        assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+        assert_eq!(file.body, b"hello");
+    }
+
+    #[test]
+    fn a_multipart_body_without_a_file_is_rejected() {
+        let body = b"--boundary\r\n\
+Content-Disposition: form-data; name=\"title\"\r\n\
+\r\n\
+holiday\r\n\
+--boundary--\r\n";
+
+        let err = multipart_file(FORM_CONTENT_TYPE, body).unwrap_err();
+        assert_eq!(status(err), 400);
+    }
+
+    #[test]
+    fn a_multipart_body_without_a_boundary_is_rejected() {
+        let err = multipart_file("multipart/form-data", b"").unwrap_err();
+        assert_eq!(status(err), 400);
+    }
 }
@@ -0,0 +1,136 @@
+use std::{cell::RefCell, future::Future, rc::Rc, time::Duration};
+
+use worker::{
+    js_sys,
+    kv::KvError,
+    wasm_bindgen::{JsCast, JsValue},
+    Delay,
+};
+
+// How many times a KV operation is attempted before its error is handed back, and how long to wait
+// before the first retry. Each retry waits up to twice as long as the one before it.
+const MAX_ATTEMPTS: u32 = 3;
+const BASE_DELAY: Duration = Duration::from_millis(50);
+
+/// What retrying did over the course of a request.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Outcome {
+    // How many times an operation was attempted again after failing.
+    pub retries: u32,
+    // Whether an operation was still failing when it ran out of attempts.
+    pub exhausted: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    outcome: Outcome,
+    // Whether writes are safe to retry, which they are once the request is guarded by an
+    // idempotency key.
+    retry_puts: bool,
+}
+
+/// How retrying is going for a single request. Each request gets its own, which every store opened
+/// for the request shares, so requests handled at the same time by one isolate can neither see each
+/// other's retries nor let each other's writes be retried.
+#[derive(Debug, Default, Clone)]
+pub struct Retries(Rc<RefCell<State>>);
+
+impl Retries {
+    /// Hands back what retrying did for the request so far.
+    pub fn outcome(&self) -> Outcome {
+        self.0.borrow().outcome
+    }
+
+    /// Lets writes be retried for the rest of the request.
+    pub fn allow_puts(&self) {
+        self.0.borrow_mut().retry_puts = true;
+    }
+}
+
+/// Tells errors that might go away if we try again, like the store being briefly overloaded, from
+/// ones that won't. Values that can't be serialized and invalid arguments such as a key that's too
+/// long will fail the same way every time.
+pub fn is_retryable(err: &KvError) -> bool {
+    match err {
+        KvError::JavaScript(value) => !is_invalid_argument(value),
+        KvError::Serialization(_) | KvError::InvalidKvStore(_) => false,
+    }
+}
+
+/// Checks if the runtime turned the operation away for the arguments it was given, which it does by
+/// throwing a `TypeError` or a `RangeError`.
+fn is_invalid_argument(value: &JsValue) -> bool {
+    value.dyn_ref::<js_sys::TypeError>().is_some()
+        || value.dyn_ref::<js_sys::RangeError>().is_some()
+}
+
+/// Runs an operation that is safe to repeat, trying again with a jittered backoff while it fails
+/// with errors that might go away.
+pub async fn with_retries<T, F, Fut>(retries: &Retries, mut operation: F) -> Result<T, KvError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, KvError>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(err) if is_retryable(&err) && attempt < MAX_ATTEMPTS => {
+                // Let's wait a random part of the backoff so that requests which failed together
+                // don't all retry together too.
+                let backoff = BASE_DELAY * 2u32.pow(attempt - 1);
+                Delay::from(backoff.mul_f64(js_sys::Math::random())).await;
+                retries.0.borrow_mut().outcome.retries += 1;
+                attempt += 1;
+            }
+            Err(err) => {
+                if attempt > 1 {
+                    retries.0.borrow_mut().outcome.exhausted = true;
+                }
+                return Err(err);
+            }
+            Ok(value) => return Ok(value),
+        }
+    }
+}
+
+/// Runs a write, which is only retried once the request is guarded by an idempotency key. Otherwise
+/// a write that failed after reaching the store could be repeated over one that came after it.
+pub async fn put_with_retries<F, Fut>(retries: &Retries, mut operation: F) -> Result<(), KvError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), KvError>>,
+{
+    let retry_puts = retries.0.borrow().retry_puts;
+    if retry_puts {
+        with_retries(retries, operation).await
+    } else {
+        operation().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_only_allow_their_own_writes_to_be_retried() {
+        let guarded = Retries::default();
+        let unguarded = Retries::default();
+        guarded.allow_puts();
+
+        assert!(guarded.0.borrow().retry_puts);
+        assert!(!unguarded.0.borrow().retry_puts);
+    }
+
+    #[test]
+    fn stores_opened_for_a_request_share_its_retries() {
+        let retries = Retries::default();
+        let store = retries.clone();
+        store.allow_puts();
+        store.0.borrow_mut().outcome.retries += 2;
+
+        assert!(retries.0.borrow().retry_puts);
+        assert_eq!(retries.outcome().retries, 2);
+        assert_eq!(Retries::default().outcome().retries, 0);
+    }
+}
@@ -0,0 +1,1296 @@
+//! An [OpenAPI 3](https://spec.openapis.org/oas/v3.0.3) description of the API for clients that
+//! want to generate bindings. It's written by hand, so whenever a route is added to `main` it needs
+//! to be described here as well as in `ENDPOINTS`.
+
+use serde_json::{json, Value};
+
+/// The OpenAPI document describing every route we serve.
+pub fn openapi() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "A key-value store backed by Workers KV.",
+        },
+        "paths": paths(),
+        "components": components(),
+    })
+}
+
+fn paths() -> Value {
+    json!({
+        "/": {
+            "get": {
+                "summary": "Describes the API",
+                "responses": { "200": json_response("The routes we serve", schema_ref("Index")) },
+            },
+        },
+        "/openapi.json": {
+            "get": {
+                "summary": "Reads this document",
+                "responses": { "200": { "description": "An OpenAPI 3 document" } },
+            },
+        },
+        "/version": {
+            "get": {
+                "summary": "Describes the build that is deployed",
+                "responses": { "200": json_response("The build", schema_ref("VersionResponse")) },
+            },
+        },
+        "/list": {
+            "get": {
+                "summary": "Lists keys, optionally under a prefix",
+                "parameters": [
+                    query("limit", "The most keys to return, up to 1000", json!({ "type": "integer", "default": 100 })),
+                    query("prefix", "Only list keys starting with this prefix", string()),
+                    query("cursor", "Carry on from where a previous page left off", string()),
+                    query("format", "The format of the body", json!({ "type": "string", "enum": ["json", "csv"], "default": "json" })),
+                    query("content_type", "Only list keys with this content-type, can be repeated", string()),
+                    query("start", "Only list keys that sort at or after this one", string()),
+                    query("end", "Only list keys that sort before this one", string()),
+                    query("delimiter", "Collapse keys containing this after the prefix into `common_prefixes`, like directories", string()),
+                    query("metadata", "Set to `false` to leave each key's metadata out of the listing", string()),
+                    query("all", "Set to `1` to follow cursors and list up to 10,000 keys at once, ignoring `limit`", string()),
+                    query("sort", "Sort the page by this field, keys are only sorted within a page", json!({ "type": "string", "enum": ["key", "updated_at", "size"] })),
+                    query("order", "The order to sort the page in. Without `sort`, `desc` walks the whole prefix like `all=1` and returns the last keys first, without a cursor", json!({ "type": "string", "enum": ["asc", "desc"], "default": "asc" })),
+                    query("keys_only", "Set to `1` or `true` to return a bare array of key names, with the cursor in `X-Cursor`", string()),
+                    query("count", "Set to `true` to count the matching keys in `X-Total-Count`, up to 10,000 keys", boolean()),
+                    query("include_system", "Set to `1` to list the worker's own keys, requires the write token", string()),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "A page of keys, grouped into common prefixes when a delimiter is given",
+                        "headers": {
+                            "X-Cursor": { "description": "The cursor for the next page when `keys_only` is used", "schema": string() },
+                            "X-Total-Count": { "description": "How many keys match when `count=true` is used", "schema": integer() },
+                            "X-Total-Count-Partial": { "description": "Set to `true` when there were too many keys to count them all, in which case `X-Total-Count` is how many there are at least", "schema": string() },
+                        },
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "oneOf": [
+                                        schema_ref("ListResult"),
+                                        { "type": "array", "items": string() },
+                                    ],
+                                },
+                            },
+                            "text/csv": { "schema": string() },
+                        },
+                    },
+                    "400": error_response("An option is invalid"),
+                    "401": error_response("Reads are private or `include_system` was used, and the request doesn't have the write token"),
+                },
+            },
+        },
+        "/export": {
+            "get": {
+                "summary": "Exports keys and values as newline-delimited JSON",
+                "description": "The worker's own keys and soft deleted keys aren't exported. Each request exports up to 500 keys, ending with a line holding the cursor to carry on from.",
+                "security": write_token(),
+                "parameters": [
+                    query("prefix", "Only export keys starting with this prefix", string()),
+                    query("cursor", "Carry on from where a previous export left off", string()),
+                ],
+                "responses": {
+                    "200": {
+                        "description": "One `ExportEntry` per line followed by an `ExportTrailer`",
+                        "content": { "application/x-ndjson": { "schema": schema_ref("ExportEntry") } },
+                    },
+                    "401": error_response("The write token is missing or wrong"),
+                },
+            },
+        },
+        "/import": {
+            "post": {
+                "summary": "Imports newline-delimited JSON produced by /export",
+                "description": "Entries are written the same way `PUT /{key}` writes values, so their checksum and size are worked out again and their metadata is signed with this deployment's `INTEGRITY_KEY`. Encrypted entries have to be exported with the same `ENCRYPTION_KEY`.",
+                "security": write_token(),
+                "parameters": [
+                    query("dry_run", "Set to `1` to check the import without writing anything", string()),
+                    query("allow_empty", "Set to `1` to import empty values rather than reporting them as errors", string()),
+                    idempotency_key(),
+                ],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/x-ndjson": { "schema": schema_ref("ExportEntry") } },
+                },
+                "responses": {
+                    "200": json_response("How the import went", schema_ref("ImportResponse")),
+                    "401": error_response("The write token is missing or wrong"),
+                    "422": error_response("The `Idempotency-Key` was already used for a different request"),
+                },
+            },
+        },
+        "/{key}": key_routes(),
+        "/keys/{key}": key_routes(),
+        "/meta/{key}": {
+            "get": {
+                "summary": "Describes the value of a key as JSON without reading it",
+                "parameters": [
+                    key(),
+                    query("expires", "The expiry of a signed URL", integer()),
+                    query("signature", "The signature of a signed URL", string()),
+                ],
+                "responses": {
+                    "200": json_response("The key's metadata", schema_ref("MetaResponse")),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                    "403": error_response("The key is reserved or the signed URL is invalid or expired"),
+                    "404": error_response("The key doesn't exist"),
+                },
+            },
+            "patch": {
+                "summary": "Changes the metadata of a key without writing its value again",
+                "parameters": [key()],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "additionalProperties": false,
+                                "properties": {
+                                    "content_type": string(),
+                                    "filename": string(),
+                                    "tags": { "type": "array", "items": string() },
+                                },
+                            },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("The key's new metadata", schema_ref("MetaResponse")),
+                    "400": error_response("The body is invalid, changes a field managed by the worker, or makes the metadata too large"),
+                    "404": error_response("The key doesn't exist"),
+                },
+            },
+        },
+        "/multi/{keys}": {
+            "get": {
+                "summary": "Reads a comma-separated list of keys",
+                "parameters": [path("keys", "The keys to read, separated by commas")],
+                "responses": {
+                    "200": json_response("The values that were found", schema_ref("MultiGetResponse")),
+                    "400": error_response("Too many keys were asked for"),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                    "403": error_response("One of the keys is reserved for internal use"),
+                    "502": error_response("A value's metadata signature doesn't match"),
+                },
+            },
+        },
+        "/batch/exists": {
+            "post": {
+                "summary": "Checks which of a JSON array of keys exist",
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": { "type": "array", "items": string() } } },
+                },
+                "responses": {
+                    "200": json_response(
+                        "Whether each key exists",
+                        json!({ "type": "object", "additionalProperties": boolean() }),
+                    ),
+                    "400": error_response("The body isn't an array of keys or has too many of them"),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                    "403": error_response("One of the keys is reserved for internal use"),
+                },
+            },
+        },
+        "/bulk/put": {
+            "post": {
+                "summary": "Writes a JSON array of entries shaped like the lines of an export",
+                "description": "Entries are written the same way `POST /import` writes them, and each one is reported on its own so one bad entry doesn't stop the rest.",
+                "security": write_token(),
+                "parameters": [
+                    query("dry_run", "Set to `1` to check every entry without writing anything", string()),
+                    query("allow_empty", "Set to `1` to write empty values rather than reporting them as errors", string()),
+                    idempotency_key(),
+                ],
+                "requestBody": {
+                    "required": true,
+                    "content": { "application/json": { "schema": { "type": "array", "items": schema_ref("ExportEntry"), "maxItems": 100 } } },
+                },
+                "responses": {
+                    "200": json_response("How writing each entry went", schema_ref("BulkPutResponse")),
+                    "400": error_response("The body isn't an array of entries or has too many of them"),
+                    "401": error_response("The write token is missing or wrong"),
+                    "422": error_response("The `Idempotency-Key` was already used for a different request"),
+                },
+            },
+        },
+        "/{key}/previous": {
+            "get": {
+                "summary": "Reads the value a key had before it was last overwritten",
+                "parameters": [key()],
+                "responses": {
+                    "200": { "description": "The previous value" },
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                    "403": error_response("The key is reserved or the signed URL is invalid or expired"),
+                    "404": error_response("There is no previous version"),
+                    "502": error_response("The value's metadata signature doesn't match"),
+                },
+            },
+        },
+        "/{key}/history": {
+            "get": {
+                "summary": "Lists the older versions of a key kept in its history",
+                "description": "Only kept when `HISTORY_DEPTH` is set, for values written with `PUT /{key}`.",
+                "parameters": [key()],
+                "responses": {
+                    "200": json_response("The versions, oldest first", schema_ref("HistoryResponse")),
+                },
+            },
+        },
+        "/{key}/history/{version}": {
+            "get": {
+                "summary": "Reads a version of a key from its history",
+                "parameters": [key(), path("version", "The version, as listed by `GET /{key}/history`")],
+                "responses": {
+                    "200": { "description": "The value the key had at that version" },
+                    "400": error_response("The version isn't a number"),
+                    "404": error_response("There is no such version"),
+                    "502": error_response("The value's metadata signature doesn't match"),
+                },
+            },
+        },
+        "/{key}/text": {
+            "get": {
+                "summary": "Reads the value of a key as text",
+                "description": "Values are checked to be UTF-8 unless their content-type names another charset, in which case they're sent as they are with that charset.",
+                "parameters": [key()],
+                "responses": {
+                    "200": { "description": "The value", "content": { "text/plain": { "schema": string() } } },
+                    "404": error_response("The key doesn't exist"),
+                    "422": json_response("The value isn't valid UTF-8", json!({
+                        "type": "object",
+                        "properties": { "error": string(), "offset": integer() },
+                    })),
+                    "502": error_response("The value's metadata signature doesn't match"),
+                },
+            },
+        },
+        "/{key}/inspect": {
+            "get": {
+                "summary": "Shows what's stored in a key for debugging",
+                "parameters": [
+                    key(),
+                    query("bytes", "How many bytes from the start of the value to show, at most 4096", json!({ "type": "integer", "default": 256 })),
+                ],
+                "responses": {
+                    "200": json_response("The value's size, checksum and metadata, with its first bytes as a hex dump and as text", schema_ref("InspectResponse")),
+                    "404": error_response("The key doesn't exist"),
+                    "502": error_response("The value's metadata signature doesn't match"),
+                },
+            },
+        },
+        "/{key}/append": {
+            "post": {
+                "summary": "Adds the request body to the end of a key's value",
+                "description": "The key is created if it doesn't exist. KV can't update a key atomically, so two appends racing for the same key can lose one of them, and every append reads and writes the whole value. The value is written the way `PUT /{key}` writes it, keeping the value it replaces as the previous version and the key's expiration.",
+                "parameters": [
+                    key(),
+                    query("ttl", "How many seconds until a key this creates expires, at least 60. Defaults to `DEFAULT_TTL` when it's set", integer()),
+                ],
+                "requestBody": { "required": true, "content": { "*/*": { "schema": binary() } } },
+                "responses": {
+                    "200": json_response("The body was added to the value", schema_ref("AppendResponse")),
+                    "201": json_response("The key was created", schema_ref("AppendResponse")),
+                    "413": error_response("The value would be bigger than `MAX_VALUE_SIZE`"),
+                },
+            },
+        },
+        "/{key}/restore": {
+            "post": {
+                "summary": "Rolls a key back to its previous value",
+                "parameters": [
+                    key(),
+                    query("ttl", "How many seconds until the restored value expires, at least 60. Defaults to `DEFAULT_TTL` when it's set", integer()),
+                ],
+                "responses": {
+                    "200": text_response("The key was restored"),
+                    "400": error_response("The key or ttl is invalid"),
+                    "404": error_response("There is no previous version"),
+                },
+            },
+        },
+        "/{key}/rollback": {
+            "post": {
+                "summary": "Rolls a key back to a version from its history",
+                "description": "The value being replaced becomes the previous version and is added to the history, so the rollback can be undone.",
+                "parameters": [
+                    key(),
+                    query_required("version", "The version to roll back to, as listed by `GET /{key}/history`", integer()),
+                    query("ttl", "How many seconds until the rolled back value expires, at least 60. Defaults to `DEFAULT_TTL` when it's set", integer()),
+                ],
+                "responses": {
+                    "200": json_response("The key's new metadata", schema_ref("MetaResponse")),
+                    "400": error_response("The version is missing or isn't a number, or the key or ttl is invalid"),
+                    "404": error_response("There is no such version"),
+                },
+            },
+        },
+        "/{key}/touch": {
+            "post": {
+                "summary": "Extends a key's expiration",
+                "parameters": [
+                    key(),
+                    query_required("ttl", "How many seconds from now the key should expire, at least 60", integer()),
+                ],
+                "responses": {
+                    "200": json_response("The key's new expiration", schema_ref("TouchResponse")),
+                    "400": error_response("The ttl is missing or too short"),
+                    "404": error_response("The key doesn't exist"),
+                },
+            },
+        },
+        "/counter/{key}/increment": {
+            "post": {
+                "summary": "Adds to a counter, whose value can be read with GET /{key}",
+                "parameters": [
+                    key(),
+                    query("by", "How much to add, 1 by default", integer()),
+                    query("consistent", "Set to 1 to go through the COUNTER Durable Object, so increments can't be lost", integer()),
+                ],
+                "responses": {
+                    "200": json_response("The counter's new value", schema_ref("CounterResponse")),
+                    "400": error_response("The amount isn't a number"),
+                    "409": error_response("The key doesn't hold a counter or the counter would overflow"),
+                },
+            },
+        },
+        "/{key}/sign": {
+            "post": {
+                "summary": "Creates a URL that can read a key until it expires",
+                "security": write_token(),
+                "parameters": [
+                    key(),
+                    query_required("expires", "How many seconds the URL should work for", integer()),
+                ],
+                "responses": {
+                    "200": json_response("The signed URL", schema_ref("SignResponse")),
+                    "400": error_response("`expires` is missing or invalid"),
+                    "401": error_response("The write token is missing or wrong"),
+                    "501": error_response("No signing key is configured"),
+                },
+            },
+        },
+        "/{key}/with-meta": {
+            "put": {
+                "summary": "Stores a base64 encoded value along with arbitrary JSON metadata",
+                "description": "The metadata is handed back as `extra` by `GET /meta/{key}`. Otherwise the value is written the way `PUT /{key}` writes it, keeping the value it replaces as the previous version.",
+                "parameters": [
+                    key(),
+                    query("ttl", "How many seconds until the value expires, at least 60. Defaults to `DEFAULT_TTL` when it's set", integer()),
+                ],
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "object",
+                                "required": ["value_base64"],
+                                "properties": {
+                                    "value_base64": string(),
+                                    "content_type": string(),
+                                    "metadata": { "description": "Any JSON value" },
+                                },
+                            },
+                        },
+                    },
+                },
+                "responses": {
+                    "200": json_response("An existing key was overwritten", schema_ref("PutResponse")),
+                    "201": json_response("The key was created", schema_ref("PutResponse")),
+                    "400": error_response("The body is invalid or the metadata is too big"),
+                },
+            },
+        },
+        "/undelete/{key}": {
+            "post": {
+                "summary": "Restores a soft deleted key",
+                "description": "The key expires when it would have if it had never been deleted.",
+                "parameters": [key()],
+                "responses": {
+                    "200": text_response("The key was restored"),
+                    "401": error_response("The write token is missing or wrong"),
+                    "404": error_response("The key isn't soft deleted"),
+                },
+            },
+        },
+        "/structured/{key}": structured_routes(),
+        "/structured/keys/{key}": structured_routes(),
+        "/structured/search": {
+            "get": {
+                "summary": "Finds the JSON documents with a field set to a value",
+                "description": "This isn't indexed, so every key under the prefix is read, up to a limit after which the results are partial.",
+                "parameters": [
+                    query_required("field", "The field to compare", string()),
+                    query_required("value", "The value the field should have, compared as a number for numeric fields", string()),
+                    query("prefix", "Only search keys starting with this prefix", string()),
+                    query("cursor", "Carry on from where a partial search stopped", string()),
+                ],
+                "responses": {
+                    "200": json_response("The matching documents", schema_ref("SearchResponse")),
+                    "400": error_response("The field or value is missing"),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                },
+            },
+        },
+        "/structured/by/{field}/{value}": {
+            "get": {
+                "summary": "Lists the JSON documents with a top-level field set to a value using an index",
+                "parameters": [
+                    path("field", "The field to look up"),
+                    path("value", "The value the field should have, as text"),
+                    query("limit", "The most keys to return", json!({ "type": "integer", "default": 100 })),
+                    query("cursor", "Carry on from where a previous page left off", string()),
+                ],
+                "responses": {
+                    "200": json_response("A page of keys", schema_ref("ListResult")),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                },
+            },
+        },
+        "/structured/query": {
+            "get": {
+                "summary": "Finds the JSON documents with a field passing a comparison",
+                "description": "This isn't indexed, so every key under the prefix is read, up to a limit after which the results are partial and can be carried on from the cursor.",
+                "parameters": [
+                    query_required("field", "The field to compare", string()),
+                    query_required("op", "How to compare the field", json!({ "type": "string", "enum": ["eq", "ne", "gt", "lt", "contains"] })),
+                    query_required("value", "The value to compare the field against", string()),
+                    query("prefix", "Only query keys starting with this prefix", string()),
+                    query("cursor", "Carry on from where a partial query stopped", string()),
+                ],
+                "responses": {
+                    "200": json_response("The matching documents", schema_ref("SearchResponse")),
+                    "400": error_response("The field, op and value don't make sense together"),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                },
+            },
+        },
+        "/structured/{key}/query": {
+            "get": {
+                "summary": "Picks parts of a JSON document out with a JSONPath expression",
+                "parameters": [
+                    key(),
+                    query_required("path", "A JSONPath expression, such as `$.foo`", string()),
+                ],
+                "responses": {
+                    "200": json_response("Every part of the document that matched", json!({ "type": "array", "items": {} })),
+                    "400": error_response("The path is missing or invalid"),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                    "403": error_response("The key is reserved or the signed URL is invalid or expired"),
+                    "404": error_response("The key doesn't exist or isn't a document"),
+                },
+            },
+        },
+        "/by-content-type/{type}": {
+            "get": {
+                "summary": "Lists the keys with a content-type",
+                "parameters": [
+                    path("type", "The content-type, such as `image/png`, without encoding its slash"),
+                    query("limit", "The most keys to return", json!({ "type": "integer", "default": 100 })),
+                    query("cursor", "Carry on from where a previous page left off", string()),
+                ],
+                "responses": {
+                    "200": json_response("A page of keys", schema_ref("ListResult")),
+                    "400": error_response("The content-type is invalid"),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                },
+            },
+        },
+        "/stats/usage": {
+            "get": {
+                "summary": "Adds up the size of the values under a prefix",
+                "description": "Only 10,000 keys are looked at per request, after which the totals are partial and the rest can be added up by carrying on from the cursor.",
+                "parameters": [
+                    query("prefix", "Only count keys starting with this prefix", string()),
+                    query("cursor", "Carries on a partial count", string()),
+                ],
+                "responses": {
+                    "200": json_response("How much space the keys take", schema_ref("UsageResponse")),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                },
+            },
+        },
+        "/stats/store": {
+            "get": {
+                "summary": "Shows how often the store was unavailable",
+                "description": "Requests that fail because KV is rate limiting the worker or having trouble of its own get a 503 with a `Retry-After` header, and are counted here on a best-effort basis.",
+                "responses": { "200": json_response("How often the store was unavailable", schema_ref("StoreStats")) },
+            },
+        },
+        "/content-types": {
+            "get": {
+                "summary": "Counts the keys of each content-type under a prefix",
+                "description": "Keys without a content-type in their metadata aren't counted.",
+                "parameters": [
+                    query("prefix", "Only count keys starting with this prefix", string()),
+                    query("cursor", "Carries on a partial count", string()),
+                ],
+                "responses": {
+                    "200": json_response("How many keys there are of each content-type", schema_ref("ContentTypesResponse")),
+                    "401": error_response("Reads are private and the request isn't authorized"),
+                },
+            },
+        },
+        "/locks/{name}": {
+            "post": {
+                "summary": "Acquires a lock",
+                "description": "Checking the lock is free and taking it aren't atomic, so two clients racing for the same lock can both be told they hold it.",
+                "parameters": [
+                    path("name", "The name of the lock"),
+                    query("ttl", "How many seconds until the lock expires, at least 60", json!({ "type": "integer", "default": 60 })),
+                ],
+                "responses": {
+                    "200": json_response("The lock was acquired", schema_ref("LockResponse")),
+                    "400": error_response("The name or ttl is invalid"),
+                    "409": error_response("The lock is already held"),
+                },
+            },
+            "delete": {
+                "summary": "Releases a lock",
+                "parameters": [
+                    path("name", "The name of the lock"),
+                    query_required("token", "The token handed out when the lock was acquired", string()),
+                ],
+                "responses": {
+                    "200": text_response("The lock was released"),
+                    "400": error_response("The token is missing"),
+                    "404": error_response("The lock isn't held"),
+                    "409": error_response("The lock is held with a different token"),
+                },
+            },
+        },
+        "/admin/migrate": {
+            "post": {
+                "summary": "Adds default metadata to keys written without any",
+                "security": write_token(),
+                "description": "Migrated values are written again the way `PUT /{key}` writes them, so they're encrypted, deduplicated and signed as configured. Only 100 keys are looked at per request, the rest can be migrated by calling this again with the returned cursor.",
+                "parameters": [
+                    query("prefix", "Only migrate keys starting with this prefix", string()),
+                    query("cursor", "Carry on from where a previous migration left off", string()),
+                ],
+                "responses": {
+                    "200": json_response("How many keys were migrated", schema_ref("MigrateResponse")),
+                    "401": error_response("The write token is missing or wrong"),
+                },
+            },
+        },
+        "/tags/{tag}": {
+            "delete": {
+                "summary": "Deletes every key written with a tag",
+                "description": "Tags aren't indexed, so each request looks through up to 1,000 keys, stopping sooner once it has deleted 100. The rest can be gone through by calling this again with the returned cursor until it's complete.",
+                "security": write_token(),
+                "parameters": [
+                    path("tag", "The tag to delete the keys of"),
+                    query("cursor", "Carry on from where a previous request left off", string()),
+                ],
+                "responses": {
+                    "200": json_response("How many keys were deleted", schema_ref("DeleteTagResponse")),
+                    "401": error_response("The write token is missing or wrong"),
+                },
+            },
+        },
+        "/prefix-delete/{prefix}/prepare": {
+            "post": {
+                "summary": "Counts the keys under a prefix and hands out a token to delete them with",
+                "description": "Up to 10,000 keys are counted, `complete` is false when there are more than that.",
+                "security": write_token(),
+                "parameters": [path("prefix", "The prefix to delete the keys under")],
+                "responses": {
+                    "200": json_response("The token, valid for 60 seconds", schema_ref("PrepareDeleteResponse")),
+                    "401": error_response("The write token is missing or wrong"),
+                },
+            },
+        },
+        "/prefix/{prefix}": {
+            "delete": {
+                "summary": "Deletes every key under a prefix",
+                "description": "Only 100 keys are deleted per request, the rest can be deleted by calling this again with the returned cursor and the same token until it's complete. Each call keeps the token valid for another 60 seconds.",
+                "security": write_token(),
+                "parameters": [
+                    path("prefix", "The prefix to delete the keys under"),
+                    query("cursor", "Carry on from where a previous delete left off", string()),
+                    query("dry_run", "Set to `1` to check the token and list the keys without deleting them", string()),
+                    header("X-Confirm-Token", "The token from `POST /prefix-delete/{prefix}/prepare`"),
+                ],
+                "responses": {
+                    "200": json_response("How deleting each key went", schema_ref("PrefixDeleteResponse")),
+                    "400": error_response("The confirm token is missing"),
+                    "401": error_response("The write token is missing or wrong"),
+                    "409": error_response("The confirm token has expired or is for a different prefix"),
+                },
+            },
+        },
+        "/prefix/{prefix}/ttl": {
+            "post": {
+                "summary": "Gives every key under a prefix a new expiration, up to 100 keys at a time",
+                "security": write_token(),
+                "parameters": [
+                    path("prefix", "The prefix to update the keys under"),
+                    query_required("seconds", "How many seconds until the keys expire, at least 60", integer()),
+                    query("cursor", "Carry on from where a previous request left off", string()),
+                ],
+                "responses": {
+                    "200": json_response("How many keys were updated", schema_ref("PrefixTtlResponse")),
+                    "400": error_response("`seconds` is missing or below 60"),
+                    "401": error_response("The write token is missing or wrong"),
+                },
+            },
+        },
+        "/admin/flush": {
+            "delete": {
+                "summary": "Deletes every key, including the worker's own",
+                "description": "Only 100 keys are deleted per request, the rest can be deleted by calling this again with the returned cursor until it's complete.",
+                "security": [{ "adminToken": [] }],
+                "parameters": [
+                    query_required("confirm", "Must be `true`", string()),
+                    query("cursor", "Carry on from where a previous flush left off", string()),
+                ],
+                "responses": {
+                    "200": json_response("How many keys were deleted", schema_ref("FlushResponse")),
+                    "403": error_response("The admin token is missing or wrong, or the flush wasn't confirmed"),
+                },
+            },
+        },
+        "/admin/audit": {
+            "get": {
+                "summary": "Reads the log of requests that changed the store, oldest first",
+                "description": "Only available when an `AUDIT_LOG` KV namespace is bound. Every PUT, PATCH, DELETE and POST is recorded once it has been answered, on a best-effort basis.",
+                "security": [{ "adminToken": [] }],
+                "parameters": [
+                    query("since", "Only return entries from this unix timestamp in seconds on", integer()),
+                    query("limit", "The most entries to return, up to 1000", integer()),
+                    query("cursor", "Carries on from a previous page", string()),
+                ],
+                "responses": {
+                    "200": json_response("The entries", schema_ref("AuditResponse")),
+                    "400": error_response("A parameter isn't a number"),
+                    "401": error_response("The admin token is missing or wrong"),
+                    "501": error_response("No audit log is bound"),
+                },
+            },
+        },
+    })
+}
+
+/// The routes for reading and writing raw values, which are served both at `/{key}` and under
+/// `/keys/` for keys containing slashes.
+fn key_routes() -> Value {
+    json!({
+        "put": {
+            "summary": "Stores the request body under a key",
+            "parameters": [
+                key(),
+                query("fast", "Set to `true` to skip keeping the previous version", boolean()),
+                query("ttl", "How many seconds until the value expires, at least 60. Defaults to `DEFAULT_TTL` when it's set", integer()),
+                query("tag", "Tag the key so it can be deleted with `DELETE /tags/{tag}`, can be repeated", string()),
+                query("allow_empty", "Set to `1` to store an empty body rather than rejecting it", string()),
+                query("replace_metadata", "Set to `1` to start from fresh metadata rather than keeping the content-type, filename, tags and custom metadata of the value being overwritten", string()),
+                header("X-Checksum-Sha256", "The hex SHA-256 of the body, which is rejected if it doesn't match"),
+                header("X-Meta-*", "Custom metadata to store with the value, which is sent back as the same headers when it's read"),
+                idempotency_key(),
+            ],
+            "requestBody": {
+                "required": true,
+                "content": {
+                    "multipart/form-data": {
+                        "schema": {
+                            "type": "object",
+                            "description": "A form with exactly one file, which is stored along with its content-type and filename",
+                            "additionalProperties": binary(),
+                        },
+                    },
+                    "*/*": { "schema": binary() },
+                },
+            },
+            "responses": {
+                "200": json_response("An existing key was overwritten", schema_ref("PutResponse")),
+                "201": json_response("The key was created", schema_ref("PutResponse")),
+                "400": error_response("The key is invalid, the body is empty, the checksum doesn't match or the metadata is too big"),
+                "403": error_response("The key is reserved for internal use"),
+                "413": error_response("The body is bigger than `MAX_VALUE_SIZE`, which is at most the 25 MiB KV allows"),
+                "422": error_response("The `Idempotency-Key` was already used for a different request"),
+            },
+        },
+        "get": {
+            "summary": "Reads the value of a key",
+            "description": "Values of 1 MiB or more are streamed from the store, unless they have to be decrypted, compressed or cut down to a range. Their checksum is checked as they're streamed, and one that doesn't match cuts the response short.",
+            "parameters": [
+                key(),
+                query("strict", "Set to `true` to fail on values written without metadata", boolean()),
+                query("raw", "Set to `1` to never compress the value", string()),
+                query("default", "Returned with `X-Default: true` instead of a 404 when the key doesn't exist", string()),
+                query("expires", "The expiry of a signed URL", integer()),
+                query("signature", "The signature of a signed URL", string()),
+                header("Range", "A single byte range to read"),
+                header("If-Modified-Since", "Only read the value if it changed since this date"),
+            ],
+            "responses": {
+                "200": { "description": "The value, with any custom metadata in `X-Meta-*` headers", "content": { "*/*": { "schema": binary() } } },
+                "206": { "description": "Part of the value", "content": { "*/*": { "schema": binary() } } },
+                "304": { "description": "The value hasn't changed" },
+                "401": error_response("Reads are private and the request isn't authorized"),
+                "403": error_response("The key is reserved or the signed URL is invalid or expired"),
+                "404": error_response("The key doesn't exist and no default was given"),
+                "416": error_response("The range can't be satisfied"),
+                "502": error_response("The value's metadata signature doesn't match"),
+            },
+        },
+        "head": {
+            "summary": "Describes the value of a key without reading it",
+            "parameters": [
+                key(),
+                query("expires", "The expiry of a signed URL", integer()),
+                query("signature", "The signature of a signed URL", string()),
+            ],
+            "responses": {
+                "200": { "description": "The value's metadata, with its size in `Content-Length` and `X-Content-Length` and any custom metadata in `X-Meta-*` headers" },
+                "401": { "description": "Reads are private and the request isn't authorized" },
+                "403": { "description": "The key is reserved or the signed URL is invalid or expired" },
+                "404": { "description": "The key doesn't exist" },
+            },
+        },
+        "delete": {
+            "summary": "Deletes a key",
+            "description": "Removing a key for good removes its previous version and history too, so they can't be read or restored afterwards.",
+            "parameters": [
+                key(),
+                query("soft", "Set to `1` to leave a tombstone behind that can be undeleted", string()),
+                query("purge", "Set to `1` to always remove the key for good", string()),
+                header("If-Match", "Only delete the key if its `ETag` is one of these"),
+            ],
+            "responses": {
+                "200": text_response("The key was deleted"),
+                "404": error_response("`If-Match` was given for a key that doesn't exist"),
+                "412": error_response("The key's `ETag` doesn't match `If-Match`"),
+            },
+        },
+    })
+}
+
+/// The routes for reading and writing JSON documents, which are served both at `/structured/{key}`
+/// and under `/structured/keys/` for keys containing slashes.
+fn structured_routes() -> Value {
+    json!({
+        "put": {
+            "summary": "Stores a JSON document under a key",
+            "parameters": [
+                key(),
+                query("ttl", "How many seconds until the document expires, at least 60. Defaults to `DEFAULT_TTL` when it's set", integer()),
+                idempotency_key(),
+            ],
+            "requestBody": {
+                "required": true,
+                "content": {
+                    "application/json": { "schema": schema_ref("StructuredValue") },
+                    "application/x-www-form-urlencoded": { "schema": schema_ref("StructuredValue") },
+                    "application/cbor": { "schema": schema_ref("StructuredValue") },
+                    "application/yaml": { "schema": schema_ref("StructuredValue") },
+                },
+            },
+            "responses": {
+                "200": json_response("An existing key was overwritten", schema_ref("PutResponse")),
+                "201": json_response("The key was created", schema_ref("PutResponse")),
+                "400": error_response("The body isn't a valid document"),
+                "415": error_response("The body isn't JSON, form fields, CBOR or YAML"),
+                "422": json_response("The body doesn't match the configured schema, or the `Idempotency-Key` was already used for a different request", schema_ref("SchemaErrors")),
+            },
+        },
+        "get": {
+            "summary": "Reads a JSON document",
+            "parameters": [
+                key(),
+                query("pretty", "Set to `1` to indent the JSON", string()),
+                header("Accept", "`application/json`, `application/json; pretty`, `text/plain`, `application/cbor` or `application/yaml`"),
+            ],
+            "responses": {
+                "200": {
+                    "description": "The document",
+                    "content": {
+                        "application/json": { "schema": schema_ref("StructuredValue") },
+                        "text/plain": { "schema": string() },
+                        "application/cbor": { "schema": schema_ref("StructuredValue") },
+                        "application/yaml": { "schema": schema_ref("StructuredValue") },
+                    },
+                },
+                "401": error_response("Reads are private and the request isn't authorized"),
+                "403": error_response("The key is reserved or the signed URL is invalid or expired"),
+                "404": error_response("The key doesn't exist or isn't a document"),
+                "406": error_response("The document can't be rendered as any of the accepted types"),
+            },
+        },
+    })
+}
+
+fn components() -> Value {
+    json!({
+        "securitySchemes": {
+            "writeToken": {
+                "type": "http",
+                "scheme": "bearer",
+                "description": "The token from the `WRITE_TOKEN` secret",
+            },
+            "adminToken": {
+                "type": "http",
+                "scheme": "bearer",
+                "description": "The token from the `ADMIN_TOKEN` secret",
+            },
+        },
+        "schemas": {
+            "Error": {
+                "type": "string",
+                "description": "Errors are returned as a plain text message, unless the client prefers JSON",
+            },
+            "JsonError": {
+                "type": "object",
+                "description": "Errors for clients whose `Accept` header prefers `application/json`",
+                "properties": {
+                    "error": string(),
+                    "code": {
+                        "type": "string",
+                        "description": "A stable code for the kind of error, which is also sent in the `X-Error-Code` header",
+                        "enum": [
+                            "invalid_body", "invalid_request", "unauthorized", "forbidden", "key_not_found",
+                            "not_found", "not_acceptable", "conflict", "precondition_failed", "payload_too_large",
+                            "unsupported_media_type", "range_not_satisfiable", "idempotency_key_reused", "internal_error", "store_error",
+                            "not_implemented", "integrity_error", "store_unavailable", "timeout", "error",
+                        ],
+                    },
+                },
+            },
+            "NotFound": {
+                "type": "object",
+                "properties": {
+                    "error": string(),
+                    "endpoints": { "type": "array", "items": schema_ref("Endpoint") },
+                },
+            },
+            "Endpoint": {
+                "type": "object",
+                "properties": { "method": string(), "path": string(), "description": string() },
+            },
+            "Index": {
+                "type": "object",
+                "properties": {
+                    "name": string(),
+                    "endpoints": { "type": "array", "items": schema_ref("Endpoint") },
+                },
+            },
+            "StructuredValue": {
+                "type": "object",
+                "description": "The default document shape, which is replaced by the `STRUCTURED_SCHEMA` variable if it's set",
+                "required": ["foo", "bar"],
+                "properties": {
+                    "foo": string(),
+                    "bar": { "type": "integer", "format": "int32" },
+                },
+            },
+            "SchemaErrors": {
+                "type": "object",
+                "properties": { "errors": { "type": "array", "items": string() } },
+            },
+            "ListResult": {
+                "type": "object",
+                "required": ["keys", "cursor", "complete"],
+                "properties": {
+                    "keys": { "type": "array", "items": schema_ref("ListedKey") },
+                    "common_prefixes": { "type": "array", "items": string() },
+                    "cursor": { "type": "string", "nullable": true },
+                    "complete": boolean(),
+                    "truncated": {
+                        "type": "boolean",
+                        "description": "Set when `all=1` stopped before listing every key, the cursor carries on from there. With `order=desc` it's also set when there were more keys than `limit`, and there's no cursor",
+                    },
+                    "sort": {
+                        "type": "object",
+                        "description": "How the keys in this page were sorted, only the page is sorted rather than every key",
+                        "properties": {
+                            "field": { "type": "string", "enum": ["key", "updated_at", "size"] },
+                            "order": { "type": "string", "enum": ["asc", "desc"] },
+                            "scope": { "type": "string", "enum": ["page"] },
+                        },
+                    },
+                },
+            },
+            "HistoryResponse": {
+                "type": "object",
+                "required": ["key", "versions"],
+                "properties": {
+                    "key": string(),
+                    "versions": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "required": ["version"],
+                            "properties": {
+                                "version": integer(),
+                                "updated_at": integer(),
+                                "size": integer(),
+                                "content_type": string(),
+                                "checksum": string(),
+                            },
+                        },
+                    },
+                },
+            },
+            "ListedKey": {
+                "type": "object",
+                "required": ["name"],
+                "properties": {
+                    "name": string(),
+                    "expiration": {
+                        "type": "integer",
+                        "description": "When the key expires, as a unix timestamp in seconds. Missing for keys that don't expire",
+                    },
+                    "metadata": { "type": "object" },
+                },
+            },
+            "MultiGetResponse": {
+                "type": "object",
+                "properties": {
+                    "values": {
+                        "type": "object",
+                        "additionalProperties": {
+                            "type": "object",
+                            "properties": {
+                                "value": { "type": "string", "format": "byte" },
+                                "content_type": string(),
+                            },
+                        },
+                    },
+                    "missing": { "type": "array", "items": string() },
+                },
+            },
+            "ExportEntry": {
+                "type": "object",
+                "properties": {
+                    "key": string(),
+                    "value_base64": { "type": "string", "format": "byte" },
+                    "metadata": { "type": "object" },
+                    "expiration": integer(),
+                },
+            },
+            "ExportTrailer": {
+                "type": "object",
+                "properties": { "cursor": string(), "complete": boolean() },
+            },
+            "ImportResponse": {
+                "type": "object",
+                "properties": {
+                    "imported": integer(),
+                    "failed": integer(),
+                    "errors": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": { "line": integer(), "error": string() },
+                        },
+                    },
+                    "dry_run": boolean(),
+                },
+            },
+            "EntryResult": {
+                "type": "object",
+                "properties": { "key": string(), "ok": boolean(), "error": string() },
+            },
+            "BulkPutResponse": {
+                "type": "object",
+                "properties": {
+                    "written": integer(),
+                    "failed": integer(),
+                    "results": { "type": "array", "items": schema_ref("EntryResult") },
+                    "dry_run": boolean(),
+                },
+            },
+            "TouchResponse": {
+                "type": "object",
+                "properties": { "key": string(), "expiration": integer() },
+            },
+            "CounterResponse": {
+                "type": "object",
+                "required": ["key", "value", "consistent"],
+                "properties": {
+                    "key": string(),
+                    "value": integer(),
+                    "consistent": boolean(),
+                    "warning": { "type": "string", "description": "Why the increment couldn't be consistent, when it was asked to be" },
+                },
+            },
+            "SignResponse": {
+                "type": "object",
+                "properties": { "url": string(), "expires": integer() },
+            },
+            "VersionResponse": {
+                "type": "object",
+                "properties": {
+                    "version": string(),
+                    "commit": string(),
+                    "built_at": integer(),
+                    "colo": string(),
+                },
+            },
+            "SearchResponse": {
+                "type": "object",
+                "properties": {
+                    "matches": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "properties": { "key": string(), "value": schema_ref("StructuredValue") },
+                        },
+                    },
+                    "scanned": integer(),
+                    "partial": boolean(),
+                    "cursor": string(),
+                },
+            },
+            "ContentTypesResponse": {
+                "type": "object",
+                "properties": {
+                    "prefix": string(),
+                    "content_types": { "type": "object", "additionalProperties": integer() },
+                    "scanned": integer(),
+                    "partial": boolean(),
+                    "cursor": string(),
+                },
+            },
+            "AuditResponse": {
+                "type": "object",
+                "required": ["entries"],
+                "properties": {
+                    "entries": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "required": ["timestamp", "method", "path", "status", "request_id", "subject"],
+                            "properties": {
+                                "timestamp": { "type": "integer", "description": "When the request came in, as a unix timestamp in milliseconds" },
+                                "method": string(),
+                                "path": string(),
+                                "status": integer(),
+                                "ip": string(),
+                                "request_id": string(),
+                                "subject": { "type": "string", "enum": ["admin", "writer", "anonymous"] },
+                            },
+                        },
+                    },
+                    "cursor": string(),
+                },
+            },
+            "StoreStats": {
+                "type": "object",
+                "required": ["unavailable"],
+                "properties": {
+                    "unavailable": integer(),
+                    "last_unavailable_at": integer(),
+                },
+            },
+            "UsageResponse": {
+                "type": "object",
+                "properties": {
+                    "prefix": string(),
+                    "keys": integer(),
+                    "bytes": integer(),
+                    "unsized_keys": integer(),
+                    "scanned": integer(),
+                    "partial": boolean(),
+                    "cursor": string(),
+                },
+            },
+            "LockResponse": {
+                "type": "object",
+                "properties": { "token": string(), "expiration": integer() },
+            },
+            "MigrateResponse": {
+                "type": "object",
+                "properties": {
+                    "migrated": integer(),
+                    "cursor": { "type": "string", "nullable": true },
+                    "complete": boolean(),
+                },
+            },
+            "DeleteTagResponse": {
+                "type": "object",
+                "properties": {
+                    "deleted": integer(),
+                    "scanned": integer(),
+                    "cursor": { "type": "string", "nullable": true },
+                    "complete": boolean(),
+                },
+            },
+            "PrepareDeleteResponse": {
+                "type": "object",
+                "properties": {
+                    "token": string(),
+                    "prefix": string(),
+                    "keys": integer(),
+                    "complete": boolean(),
+                    "expiration": integer(),
+                },
+            },
+            "PrefixDeleteResponse": {
+                "type": "object",
+                "properties": {
+                    "deleted": integer(),
+                    "failed": integer(),
+                    "results": { "type": "array", "items": schema_ref("EntryResult") },
+                    "dry_run": boolean(),
+                    "cursor": { "type": "string", "nullable": true },
+                    "complete": boolean(),
+                },
+            },
+            "PrefixTtlResponse": {
+                "type": "object",
+                "properties": {
+                    "updated": integer(),
+                    "expiration": integer(),
+                    "cursor": { "type": "string", "nullable": true },
+                    "complete": boolean(),
+                },
+            },
+            "MetaResponse": {
+                "type": "object",
+                "properties": {
+                    "key": string(),
+                    "expiration": { "type": "integer", "nullable": true },
+                    "metadata": {
+                        "allOf": [schema_ref("Metadata")],
+                        "nullable": true,
+                        "description": "Missing for keys written before metadata was stored",
+                    },
+                },
+            },
+            "Metadata": {
+                "type": "object",
+                "properties": {
+                    "content_type": string(),
+                    "created_at": integer(),
+                    "updated_at": integer(),
+                    "checksum": string(),
+                    "expiration": integer(),
+                    "filename": string(),
+                    "size": integer(),
+                    "tags": { "type": "array", "items": string() },
+                    "custom": { "type": "object", "additionalProperties": string() },
+                    "extra": { "description": "The JSON metadata stored with `PUT /{key}/with-meta`" },
+                },
+            },
+            "PutResponse": {
+                "type": "object",
+                "properties": {
+                    "key": string(),
+                    "size": integer(),
+                    "content_type": string(),
+                    "etag": string(),
+                    "ttl": integer(),
+                    "expiration": integer(),
+                    "value": {
+                        "type": "object",
+                        "description": "The document as it was stored, only for structured writes",
+                    },
+                    "metadata": schema_ref("Metadata"),
+                },
+            },
+            "InspectResponse": {
+                "type": "object",
+                "properties": {
+                    "key": string(),
+                    "size": integer(),
+                    "sha256": string(),
+                    "expiration": { "type": "integer", "nullable": true },
+                    "metadata": { "allOf": [schema_ref("Metadata")], "nullable": true },
+                    "bytes": integer(),
+                    "hex": string(),
+                    "text": string(),
+                },
+            },
+            "AppendResponse": {
+                "type": "object",
+                "properties": { "key": string(), "size": integer() },
+            },
+            "FlushResponse": {
+                "type": "object",
+                "properties": {
+                    "deleted": integer(),
+                    "cursor": { "type": "string", "nullable": true },
+                    "complete": boolean(),
+                },
+            },
+        },
+    })
+}
+
+fn key() -> Value {
+    path(
+        "key",
+        "The key, with any `/` encoded as `%2F` unless it's under `/keys/`",
+    )
+}
+
+fn path(name: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "path", "required": true, "description": description, "schema": string() })
+}
+
+fn query(name: &str, description: &str, schema: Value) -> Value {
+    json!({ "name": name, "in": "query", "description": description, "schema": schema })
+}
+
+fn query_required(name: &str, description: &str, schema: Value) -> Value {
+    json!({ "name": name, "in": "query", "required": true, "description": description, "schema": schema })
+}
+
+fn header(name: &str, description: &str) -> Value {
+    json!({ "name": name, "in": "header", "description": description, "schema": string() })
+}
+
+fn idempotency_key() -> Value {
+    header(
+        "Idempotency-Key",
+        "Retries sent with the same key within a day get the response of the first request rather than writing again, marked with `X-Idempotent-Replayed: true`",
+    )
+}
+
+fn write_token() -> Value {
+    json!([{ "writeToken": [] }])
+}
+
+fn json_response(description: &str, schema: Value) -> Value {
+    json!({ "description": description, "content": { "application/json": { "schema": schema } } })
+}
+
+fn text_response(description: &str) -> Value {
+    json!({ "description": description, "content": { "text/plain": { "schema": string() } } })
+}
+
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": {
+            "text/plain": { "schema": schema_ref("Error") },
+            "application/json": { "schema": schema_ref("JsonError") },
+        },
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+fn integer() -> Value {
+    json!({ "type": "integer" })
+}
+
+fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+fn binary() -> Value {
+    json!({ "type": "string", "format": "binary" })
+}
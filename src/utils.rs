@@ -1,30 +1,1318 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow, cell::RefCell, convert::TryFrom, future::Future, io::Write, ops::Range,
+    str::FromStr, sync::Once,
+};
 
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
 use cfg_if::cfg_if;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use flate2::{write::GzEncoder, Compression};
+use futures::{Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use jsonschema::JSONSchema;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
 use worker::*;
 
+use crate::{error::ApiError, retry};
+
 cfg_if! {
     // https://github.com/rustwasm/console_error_panic_hook#readme
     if #[cfg(feature = "console_error_panic_hook")] {
         extern crate console_error_panic_hook;
-        pub use self::console_error_panic_hook::set_once as set_panic_hook;
+        use self::console_error_panic_hook::hook as console_panic_hook;
     } else {
         #[inline]
-        pub fn set_panic_hook() {}
+        fn console_panic_hook(_info: &std::panic::PanicInfo) {}
+    }
+}
+
+thread_local! {
+    // The IDs of the requests being handled right now. An isolate handles several requests at once,
+    // so there can be more than one.
+    static REQUESTS_IN_FLIGHT: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+static PANIC_HOOK: Once = Once::new();
+
+/// Installs a panic hook that logs the panic along with the requests in flight when it happened, so
+/// a panic in the logs can be tied back to the request IDs the clients got. The hook is only
+/// installed the first time this is called.
+///
+/// Panics abort on `wasm32-unknown-unknown` rather than unwind, which takes down every request the
+/// isolate is handling and leaves the runtime to answer them with its own error. We can't tell which
+/// of them panicked, so all of them are logged. With the `console_error_panic_hook` feature the
+/// panic's stack trace is written to the console too.
+pub fn set_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        std::panic::set_hook(Box::new(|info| {
+            let requests = requests_in_flight()
+                .map_or_else(|| "unknown requests".into(), |requests| requests.join(", "));
+            console_error!("[{}] panicked: {}", requests, info);
+            console_panic_hook(info);
+        }));
+    });
+}
+
+/// Keeps a request in the list of requests in flight the panic hook logs, until it's dropped.
+pub struct RequestInFlight(String);
+
+/// Adds a request to the list of requests in flight for as long as the returned guard is kept.
+pub fn track_request(request_id: &str) -> RequestInFlight {
+    REQUESTS_IN_FLIGHT.with(|requests| requests.borrow_mut().push(request_id.to_string()));
+    RequestInFlight(request_id.to_string())
+}
+
+impl Drop for RequestInFlight {
+    fn drop(&mut self) {
+        REQUESTS_IN_FLIGHT.with(|requests| {
+            let mut requests = requests.borrow_mut();
+            if let Some(index) = requests.iter().position(|request| *request == self.0) {
+                requests.remove(index);
+            }
+        });
     }
 }
 
+/// The IDs of the requests in flight, oldest first. A panic can happen while the list is being
+/// changed, in which case we can't look at it.
+fn requests_in_flight() -> Option<Vec<String>> {
+    REQUESTS_IN_FLIGHT
+        .try_with(|requests| requests.try_borrow().ok().map(|requests| requests.clone()))
+        .ok()
+        .flatten()
+}
+
 pub fn param_from<'url>(url: &'url Url, target_name: &'static str) -> Option<Cow<'url, str>> {
     url.query_pairs()
         .find_map(|(name, value)| (name == target_name).then_some(value))
 }
 
-pub fn log_request(req: &Request) {
+/// Collects every value of a query parameter that can be repeated, like `?tag=a&tag=b`.
+pub fn params_from(url: &Url, target_name: &'static str) -> Vec<String> {
+    url.query_pairs()
+        .filter(|(name, _)| name == target_name)
+        .map(|(_, value)| value.into_owned())
+        .collect()
+}
+
+/// Copies `url`, replacing any existing values of the query parameter `name` with `value`.
+pub fn with_param(url: &Url, name: &str, value: &str) -> Url {
+    let pairs: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(existing, _)| existing != name)
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+
+    let mut url = url.clone();
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(pairs)
+        .append_pair(name, value);
+    url
+}
+
+// The characters that need to be encoded for a string to be used as a single segment of a URL path.
+const PATH_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+/// Percent-encodes a string so it can be used as a single segment of a URL path.
+pub fn percent_encode(segment: &str) -> String {
+    utf8_percent_encode(segment, PATH_SEGMENT).to_string()
+}
+
+/// Percent-decodes part of a URL, failing with a 400 if it doesn't decode to valid UTF-8.
+pub fn percent_decode(encoded: &str) -> Result<String> {
+    percent_decode_str(encoded)
+        .decode_utf8()
+        .map(Cow::into_owned)
+        .map_err(|_| Error::from(ApiError::InvalidRequest("key is not valid UTF-8".into())))
+}
+
+/// Parses the query parameter `target_name` into a `T`, failing with a 400 when it's present but
+/// can't be parsed.
+pub fn param_parse<T: FromStr>(url: &Url, target_name: &'static str) -> Result<Option<T>> {
+    param_from(url, target_name)
+        .map(|value| {
+            value.parse().map_err(|_| {
+                Error::from(ApiError::InvalidRequest(format!("invalid {}", target_name)))
+            })
+        })
+        .transpose()
+}
+
+/// Like [param_parse], but also fails with a 400 when the parameter is missing.
+pub fn param_required<T: FromStr>(url: &Url, target_name: &'static str) -> Result<T> {
+    param_parse(url, target_name)?
+        .ok_or_else(|| Error::from(ApiError::InvalidRequest(format!("missing {}", target_name))))
+}
+
+/// Puts a key in the namespace given by `prefix`, which leaves it untouched when there's no namespace.
+pub fn scope_key(prefix: &str, key: &str) -> String {
+    format!("{}{}", prefix, key)
+}
+
+/// Takes a key stored in the namespace given by `prefix` back out of it.
+pub fn unscope_key<'key>(prefix: &str, key: &'key str) -> &'key str {
+    key.strip_prefix(prefix).unwrap_or(key)
+}
+
+/// Reads an environment variable, returning `None` when it isn't set.
+pub fn var<D>(ctx: &RouteContext<D>, name: &str) -> Option<String> {
+    ctx.var(name).ok().map(|var| var.to_string())
+}
+
+/// The current time as a unix timestamp in seconds.
+pub fn now() -> u64 {
+    Date::now().as_millis() / 1000
+}
+
+/// Runs `future` to completion, returning its output along with how long it took in milliseconds.
+pub async fn timed<T>(future: impl Future<Output = T>) -> (T, f64) {
+    let start = Date::now().as_millis();
+    let output = future.await;
+    let duration = Date::now().as_millis().saturating_sub(start);
+
+    (output, duration as f64)
+}
+
+/// Formats a single metric for a `Server-Timing` header.
+pub fn server_timing(name: &str, duration: f64) -> String {
+    format!("{};dur={}", name, duration)
+}
+
+/// Formats a unix timestamp in seconds as an HTTP-date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn http_date(timestamp: u64) -> String {
+    let time = NaiveDateTime::from_timestamp(timestamp as i64, 0);
+    DateTime::<Utc>::from_utc(time, Utc)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+/// Parses an HTTP-date into a unix timestamp in seconds.
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    let time = DateTime::parse_from_rfc2822(date).ok()?;
+    u64::try_from(time.timestamp()).ok()
+}
+
+/// The part of a value that a `Range` header asked for.
+pub enum ByteRange {
+    /// The header should be ignored and the whole value sent.
+    Full,
+    /// Only the bytes in this range should be sent.
+    Partial(Range<usize>),
+    /// None of the requested bytes exist in the value.
+    Unsatisfiable,
+}
+
+/// Works out which part of a value of `len` bytes a `Range` header is asking for. Only a single
+/// range is supported, so requests for multiple ranges are treated as unsatisfiable.
+pub fn byte_range(header: &str, len: usize) -> ByteRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) => spec,
+        // We don't know about any other range units, so the header is ignored.
+        None => return ByteRange::Full,
+    };
+
+    if spec.contains(',') {
+        return ByteRange::Unsatisfiable;
+    }
+
+    let (start, end) = match spec.split_once('-') {
+        Some((start, end)) => (start.trim(), end.trim()),
+        None => return ByteRange::Full,
+    };
+
+    let range = match (start.parse::<usize>(), end.parse::<usize>()) {
+        // `bytes=start-end`, where the end is inclusive and allowed to run past the value.
+        (Ok(start), Ok(end)) if start <= end => start..len.min(end + 1),
+        // `bytes=start-`, meaning everything from the start onwards.
+        (Ok(start), Err(_)) if end.is_empty() => start..len,
+        // `bytes=-suffix`, meaning the last `suffix` bytes.
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => len.saturating_sub(suffix)..len,
+        (Err(_), Ok(_)) if start.is_empty() => return ByteRange::Unsatisfiable,
+        // Anything else is malformed, which means the header should be ignored.
+        _ => return ByteRange::Full,
+    };
+
+    if range.start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(range)
+    }
+}
+
+/// A content-coding we can compress responses with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    /// The name of the encoding for a `Content-Encoding` header, which is omitted for identity.
+    pub fn name(self) -> Option<&'static str> {
+        match self {
+            Encoding::Brotli => Some("br"),
+            Encoding::Gzip => Some("gzip"),
+            Encoding::Identity => None,
+        }
+    }
+
+    /// Compresses `bytes` with this encoding.
+    pub fn encode(self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => brotli(&bytes),
+            Encoding::Gzip => gzip(&bytes),
+            Encoding::Identity => Ok(bytes),
+        }
+    }
+}
+
+/// Picks the best encoding we support from the request's `Accept-Encoding` header, going by the
+/// client's q-values and preferring brotli over gzip when the client likes them equally.
+pub fn negotiate_encoding(req: &Request) -> Encoding {
+    let header = match req.headers().get("accept-encoding").ok().flatten() {
+        Some(header) => header,
+        None => return Encoding::Identity,
+    };
+
+    let mut preferences = Vec::new();
+    for entry in header.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let q = parts
+            .find_map(|param| param.strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.parse::<f32>().ok());
+
+        if let Some(q) = q {
+            preferences.push((name, q));
+        }
+    }
+
+    // An encoding that isn't listed gets whatever weight the wildcard has, if there is one.
+    let weight = |encoding: &str| {
+        preferences
+            .iter()
+            .find(|(name, _)| name == encoding)
+            .or_else(|| preferences.iter().find(|(name, _)| name == "*"))
+            .map_or(0.0, |(_, q)| *q)
+    };
+
+    [Encoding::Brotli, Encoding::Gzip]
+        .iter()
+        .copied()
+        .map(|encoding| (encoding, weight(encoding.name().unwrap_or_default())))
+        .filter(|(_, q)| *q > 0.0)
+        .fold(
+            None,
+            |best: Option<(Encoding, f32)>, (encoding, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((encoding, q)),
+            },
+        )
+        .map_or(Encoding::Identity, |(encoding, _)| encoding)
+}
+
+/// A media range from an `Accept` header, such as `text/*` or `application/json; pretty`.
+#[derive(Debug)]
+pub struct MediaRange {
+    essence: String,
+    params: Vec<String>,
+    q: f32,
+}
+
+impl MediaRange {
+    /// Checks if the range covers the given content-type, taking wildcards into account.
+    pub fn matches(&self, content_type: &str) -> bool {
+        let (kind, _) = content_type.split_once('/').unwrap_or((content_type, ""));
+        match self.essence.split_once('/') {
+            Some(("*", "*")) => true,
+            Some((range_kind, "*")) => range_kind == kind,
+            _ => self.essence == content_type,
+        }
+    }
+
+    /// Checks if the range has a parameter with the given name, with or without a value.
+    pub fn has_param(&self, name: &str) -> bool {
+        self.params.iter().any(|param| {
+            let param_name = param.split('=').next().unwrap_or_default();
+            param_name.trim().eq_ignore_ascii_case(name)
+        })
+    }
+}
+
+/// Reads the media ranges the client will take from the request's `Accept` header, most preferred
+/// first. Clients that don't send one will take anything.
+pub fn accepted_media_ranges(req: &Request) -> Vec<MediaRange> {
+    let header = match req.headers().get("accept").ok().flatten() {
+        Some(header) if !header.trim().is_empty() => header,
+        _ => "*/*".into(),
+    };
+
+    let mut ranges = Vec::new();
+    for entry in header.split(',') {
+        let mut parts = entry.split(';').map(str::trim);
+        let essence = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let mut params = Vec::new();
+        let mut q = Some(1.0);
+        for param in parts {
+            match param.strip_prefix("q=") {
+                Some(weight) => q = weight.parse::<f32>().ok(),
+                None => params.push(param.to_string()),
+            }
+        }
+
+        if let Some(q) = q.filter(|q| *q > 0.0) {
+            ranges.push(MediaRange { essence, params, q });
+        }
+    }
+
+    // The sort is stable, so ranges the client likes equally stay in the order they were sent.
+    ranges.sort_by(|a, b| b.q.partial_cmp(&a.q).unwrap_or(std::cmp::Ordering::Equal));
+    ranges
+}
+
+/// Checks if the client would rather have errors as JSON than as plain text, going by its `Accept`
+/// header. Clients that don't say which they prefer get plain text.
+pub fn prefers_json_errors(req: &Request) -> bool {
+    for range in accepted_media_ranges(req) {
+        if range.essence == "application/json" || range.essence == "application/*" {
+            return true;
+        }
+        if range.matches("text/plain") {
+            return false;
+        }
+    }
+
+    false
+}
+
+/// Builds an error response, as `{ "error": message, "code": code }` for clients that prefer JSON or
+/// as the plain message otherwise. The code is always sent in the `X-Error-Code` header too.
+pub fn error_response(error: &ApiError, json: bool) -> Result<Response> {
+    let mut response = if json {
+        Response::from_json(&serde_json::json!({
+            "error": error.to_string(),
+            "code": error.code(),
+        }))?
+        .with_status(error.status())
+    } else {
+        error.response()?
+    };
+
+    for (name, value) in error_headers(error) {
+        response.headers_mut().set(name, &value)?;
+    }
+    Ok(response)
+}
+
+/// The headers every error response is sent with.
+fn error_headers(error: &ApiError) -> Vec<(&'static str, String)> {
+    let mut headers = vec![
+        ("vary", "accept".into()),
+        ("x-error-code", error.code().into()),
+    ];
+    if let Some(retry_after) = error.retry_after() {
+        headers.push(("retry-after", retry_after.to_string()));
+    }
+    headers
+}
+
+/// Renders the plain message errors handlers build with [ApiError::response] in the format the client
+/// prefers, keeping any other headers they set. Errors that already have a content-type, such as
+/// those with a JSON body of their own, are left as they are, but every error is marked as varying
+/// with the `Accept` header.
+pub fn negotiate_error(mut response: Response, json: bool) -> Result<Response> {
+    if response.status_code() < 400 {
+        return Ok(response);
+    }
+
+    let plain = !response.headers().has("content-type")?
+        && matches!(response.body(), ResponseBody::Body(_));
+    if !plain {
+        response.headers_mut().append("vary", "accept")?;
+        return Ok(response);
+    }
+
+    let message = match response.body() {
+        ResponseBody::Body(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        _ => String::new(),
+    };
+    let error = ApiError::from_status(response.status_code(), &message);
+    let mut negotiated = error_response(&error, json)?;
+    for (name, value) in response.headers().entries() {
+        negotiated.headers_mut().append(&name, &value)?;
+    }
+
+    Ok(negotiated)
+}
+
+/// Guesses the content-type of a value from its leading bytes, for the handful of formats that are
+/// easy to recognize.
+pub fn sniff_content_type(bytes: &[u8]) -> Option<String> {
+    let content_type = if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        "image/png"
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        "image/jpeg"
+    } else if bytes.starts_with(b"%PDF-") {
+        "application/pdf"
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        "image/gif"
+    } else if bytes.starts_with(&[0x1f, 0x8b]) {
+        "application/gzip"
+    } else if looks_like_json(bytes) {
+        "application/json"
+    } else if looks_like_text(bytes) {
+        "text/plain; charset=utf-8"
+    } else {
+        return None;
+    };
+
+    Some(content_type.into())
+}
+
+/// Whether a value is a JSON object or array. Plenty of plain text happens to be valid JSON, such as
+/// a lone number, so that doesn't count.
+fn looks_like_json(bytes: &[u8]) -> bool {
+    matches!(
+        bytes.iter().find(|byte| !byte.is_ascii_whitespace()),
+        Some(b'{') | Some(b'[')
+    ) && serde_json::from_slice::<serde::de::IgnoredAny>(bytes).is_ok()
+}
+
+/// Whether a value is UTF-8 text without any control characters other than whitespace, which binary
+/// data is all but certain to have.
+fn looks_like_text(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => {
+            !text.is_empty()
+                && !text
+                    .chars()
+                    .any(|c| c.is_control() && !c.is_ascii_whitespace())
+        }
+        Err(_) => false,
+    }
+}
+
+/// Whether a content-type looks like `type/subtype`, optionally followed by parameters.
+pub fn is_media_type(content_type: &str) -> bool {
+    let is_token = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+
+    match media_type_essence(content_type).split_once('/') {
+        Some((kind, subtype)) => is_token(kind) && is_token(subtype),
+        None => false,
+    }
+}
+
+/// Strips the parameters from a content-type, leaving just the lowercased `type/subtype`.
+pub fn media_type_essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Checks an `If-Match` header against the current entity tag of a value. `*` matches any value,
+/// and weak tags are compared like strong ones.
+pub fn if_match(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|tag| {
+        let tag = tag.strip_prefix("W/").unwrap_or(tag);
+        let tag = tag
+            .strip_prefix('"')
+            .and_then(|tag| tag.strip_suffix('"'))
+            .unwrap_or(tag);
+        tag == "*" || (!tag.is_empty() && tag.eq_ignore_ascii_case(etag))
+    })
+}
+
+/// Gets a parameter from a header value made up of `;` separated parameters, such as the `boundary`
+/// of a content-type or the `filename` of a content-disposition, with any quotes removed.
+pub fn header_param(header: &str, name: &str) -> Option<String> {
+    header.split(';').skip(1).find_map(|param| {
+        let (param_name, value) = param.split_once('=')?;
+        if !param_name.trim().eq_ignore_ascii_case(name) {
+            return None;
+        }
+
+        let value = value.trim();
+        let value = value
+            .strip_prefix('"')
+            .and_then(|value| value.strip_suffix('"'))
+            .unwrap_or(value);
+        Some(value.to_string())
+    })
+}
+
+/// A single part of a `multipart/form-data` body.
+#[derive(Debug)]
+pub struct MultipartPart {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// Finds where `needle` first appears in `haystack`, starting the search at `from`.
+fn find_bytes(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|position| position + from)
+}
+
+/// Splits a `multipart/form-data` body into its parts, failing with a 400 if it's malformed.
+pub fn parse_multipart(body: &[u8], boundary: &str) -> Result<Vec<MultipartPart>> {
+    let malformed = || Error::from(ApiError::InvalidRequest("malformed multipart body".into()));
+    let delimiter = format!("--{}", boundary).into_bytes();
+    // Every delimiter after the first one ends the previous part's body along with the line break
+    // before it.
+    let separator = [b"\r\n".as_ref(), &delimiter].concat();
+
+    let mut parts = Vec::new();
+    let mut position = find_bytes(body, &delimiter, 0).ok_or_else(malformed)? + delimiter.len();
+    loop {
+        // The last delimiter is followed by `--` rather than the next part.
+        if body[position..].starts_with(b"--") {
+            return Ok(parts);
+        }
+
+        let headers_start = find_bytes(body, b"\r\n", position).ok_or_else(malformed)? + 2;
+        let headers_end = find_bytes(body, b"\r\n\r\n", headers_start - 2).ok_or_else(malformed)?;
+        let body_start = headers_end + 4;
+        let body_end = find_bytes(body, &separator, body_start).ok_or_else(malformed)?;
+
+        // A part without any headers has its blank line straight after the delimiter.
+        let headers = body.get(headers_start..headers_end).unwrap_or_default();
+        let headers = String::from_utf8_lossy(headers);
+        let mut part = MultipartPart {
+            name: None,
+            filename: None,
+            content_type: None,
+            body: body[body_start..body_end].to_vec(),
+        };
+        for header in headers.split("\r\n") {
+            let (name, value) = match header.split_once(':') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => continue,
+            };
+
+            if name.eq_ignore_ascii_case("content-disposition") {
+                part.name = header_param(value, "name");
+                part.filename = header_param(value, "filename");
+            } else if name.eq_ignore_ascii_case("content-type") {
+                part.content_type = Some(value.to_string());
+            }
+        }
+
+        parts.push(part);
+        position = body_end + separator.len();
+    }
+}
+
+/// Checks if values with the given content-type are likely to get smaller when compressed. Most
+/// binary formats such as images and archives are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    let essence = media_type_essence(content_type);
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || matches!(
+            essence.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/x-ndjson"
+                | "image/svg+xml"
+        )
+}
+
+/// Compresses `bytes` with brotli.
+pub fn brotli(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+    encoder
+        .write_all(bytes)
+        .map_err(|err| Error::RustError(format!("failed to compress: {}", err)))?;
+    Ok(encoder.into_inner())
+}
+
+/// Compresses `bytes` with gzip.
+pub fn gzip(bytes: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(bytes)
+        .and_then(|_| encoder.finish())
+        .map_err(|err| Error::RustError(format!("failed to compress: {}", err)))
+}
+
+/// Computes the SHA-256 digest of `bytes` as a lowercase hex string.
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Passes a stream of chunks on while checking them against a SHA-256 checksum. The last chunk is
+/// held back until the stream ends, so a value that doesn't match ends with an error instead of its
+/// last chunk and the client is left with a broken response rather than a complete, wrong one.
+pub fn verify_checksum<S>(stream: S, checksum: String) -> impl Stream<Item = Result<Vec<u8>>>
+where
+    S: Stream<Item = Result<Vec<u8>>> + 'static,
+{
+    struct State<S> {
+        stream: std::pin::Pin<Box<S>>,
+        hasher: Sha256,
+        held: Option<Vec<u8>>,
+        checksum: String,
+        done: bool,
+    }
+
+    let state = State {
+        stream: Box::pin(stream),
+        hasher: Sha256::new(),
+        held: None,
+        checksum,
+        done: false,
+    };
+    futures::stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+
+        loop {
+            match state.stream.next().await {
+                Some(Ok(chunk)) => {
+                    state.hasher.update(&chunk);
+                    if let Some(held) = state.held.replace(chunk) {
+                        return Some((Ok(held), state));
+                    }
+                }
+                Some(Err(err)) => {
+                    state.done = true;
+                    return Some((Err(err), state));
+                }
+                None => {
+                    state.done = true;
+                    let actual: String = state
+                        .hasher
+                        .clone()
+                        .finalize()
+                        .iter()
+                        .map(|byte| format!("{:02x}", byte))
+                        .collect();
+                    if !actual.eq_ignore_ascii_case(&state.checksum) {
+                        let err = ApiError::Internal("checksum mismatch".into());
+                        return Some((Err(err.into()), state));
+                    }
+                    return state.held.take().map(|held| (Ok(held), state));
+                }
+            }
+        }
+    })
+}
+
+/// A request body read by [read_body], with the SHA-256 digest it was given as it was read.
+pub struct Body {
+    pub bytes: Vec<u8>,
+    // The lowercase hex SHA-256 digest of the bytes.
+    pub checksum: String,
+}
+
+/// Reads a request body a chunk at a time, hashing it as it arrives. Bodies bigger than `limit`
+/// bytes fail with a 413 as soon as we know, without reading any further.
+pub async fn read_body(req: &mut Request, limit: usize) -> Result<Body> {
+    // Most clients tell us how big the body is up front, which saves reading any of it.
+    let length = req
+        .headers()
+        .get("content-length")?
+        .and_then(|length| length.parse::<usize>().ok());
+
+    // A request without a body doesn't have a stream either.
+    read_chunks(req.stream().ok(), length, limit).await
+}
+
+/// Reads a body from a stream of chunks, given the length the client said it would be, if any.
+async fn read_chunks<S>(stream: Option<S>, length: Option<usize>, limit: usize) -> Result<Body>
+where
+    S: Stream<Item = Result<Vec<u8>>> + Unpin,
+{
+    let too_large = || {
+        Error::from(ApiError::PayloadTooLarge(format!(
+            "body is larger than the limit of {} bytes",
+            limit
+        )))
+    };
+
+    if length.is_some_and(|length| length > limit) {
+        return Err(too_large());
+    }
+
+    let mut bytes = Vec::with_capacity(length.unwrap_or_default());
+    let mut hasher = Sha256::new();
+    if let Some(mut stream) = stream {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            if bytes.len() + chunk.len() > limit {
+                return Err(too_large());
+            }
+            hasher.update(&chunk);
+            bytes.extend_from_slice(&chunk);
+        }
+    }
+
+    let checksum = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Ok(Body { bytes, checksum })
+}
+
+/// Starts an HMAC-SHA256 of a message.
+fn hmac_sha256(key: &[u8], message: &str) -> Hmac<Sha256> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message.as_bytes());
+    mac
+}
+
+/// Signs a message with HMAC-SHA256, returning the signature as hex.
+pub fn hmac_sha256_hex(key: &[u8], message: &str) -> String {
+    hmac_sha256(key, message)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Checks a hex encoded HMAC-SHA256 signature of a message. The comparison takes the same time no
+/// matter how much of the signature is right, so it can't be guessed a byte at a time.
+pub fn verify_hmac_sha256(key: &[u8], message: &str, signature: &str) -> bool {
+    let signature = signature
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>();
+    let signature = match signature {
+        Some(signature) => signature,
+        None => return false,
+    };
+
+    hmac_sha256(key, message).verify_slice(&signature).is_ok()
+}
+
+/// Decodes a base64 encoded AES-256 key.
+pub fn parse_encryption_key(key: &str) -> Result<Vec<u8>> {
+    match base64::decode(key.trim()) {
+        Ok(key) if key.len() == 32 => Ok(key),
+        _ => Err(Error::RustError(
+            "encryption key must be 32 base64 encoded bytes".into(),
+        )),
+    }
+}
+
+/// Encrypts `plaintext` with AES-256-GCM under a fresh random nonce, returning the ciphertext and
+/// the base64 encoded nonce needed to decrypt it.
+pub fn encrypt(key: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, String)> {
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|_| Error::RustError("invalid encryption key".into()))?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, plaintext)
+        .map_err(|_| Error::RustError("failed to encrypt value".into()))?;
+
+    Ok((ciphertext, base64::encode(nonce)))
+}
+
+/// Decrypts a value produced by [encrypt].
+pub fn decrypt(key: &[u8], nonce: &str, ciphertext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|_| Error::RustError("invalid encryption key".into()))?;
+    let nonce = match base64::decode(nonce) {
+        Ok(nonce) if nonce.len() == 12 => nonce,
+        _ => return Err(Error::RustError("invalid encryption nonce".into())),
+    };
+
+    cipher
+        .decrypt(Nonce::from_slice(&nonce), ciphertext)
+        .map_err(|_| Error::RustError("failed to decrypt value".into()))
+}
+
+/// Validates `instance` against the JSON Schema in `schema`, returning a description of every
+/// validation error. An empty list means the instance is valid.
+pub fn schema_errors(schema: &str, instance: &serde_json::Value) -> Result<Vec<String>> {
+    let schema = serde_json::from_str(schema)?;
+    let compiled = JSONSchema::compile(&schema)
+        .map_err(|err| Error::RustError(format!("invalid schema: {}", err)))?;
+
+    let errors = match compiled.validate(instance) {
+        Ok(()) => Vec::new(),
+        Err(errors) => errors
+            .map(|err| format!("{}: {}", err.instance_path, err))
+            .collect(),
+    };
+
+    Ok(errors)
+}
+
+/// Gets the token from a `Authorization: Bearer <token>` header, if there is one.
+pub fn bearer_token(req: &Request) -> Result<Option<String>> {
+    Ok(req.headers().get("authorization")?.and_then(|header| {
+        header
+            .strip_prefix("Bearer ")
+            .map(|token| token.trim().into())
+    }))
+}
+
+/// Escapes a field for a CSV row, quoting it if it contains anything that would otherwise break the
+/// row apart.
+pub fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Renders bytes the way `hexdump -C` does, sixteen to a line with their offset and the printable
+/// ones alongside.
+pub fn hex_dump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (line, chunk) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let printable: String = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+
+        dump.push_str(&format!(
+            "{:08x}  {:<47}  |{}|\n",
+            line * 16,
+            hex.join(" "),
+            printable
+        ));
+    }
+
+    dump
+}
+
+/// Serializes `value` as a single line of newline-delimited JSON.
+pub fn ndjson_line<T: serde::Serialize>(value: &T) -> Result<Vec<u8>> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    Ok(line)
+}
+
+/// Gets an ID for the request, using the `CF-Ray` header Cloudflare attaches when it's available
+/// so our logs can be matched up with Cloudflare's.
+pub fn request_id(req: &Request) -> String {
+    req.headers()
+        .get("cf-ray")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| Uuid::new_v4().to_string())
+}
+
+pub fn log_request(req: &Request, request_id: &str) {
     console_log!(
-        "{} - [{}], located at: {:?}, within: {}",
+        "{} - [{}] {}, located at: {:?}, within: {}",
         Date::now().to_string(),
         req.path(),
+        request_id,
         req.cf().coordinates().unwrap_or_default(),
         req.cf().region().unwrap_or_else(|| "unknown region".into())
     );
 }
+
+/// Logs how many times KV operations were retried while handling a request and whether they
+/// eventually went through.
+pub fn log_retries(request_id: &str, outcome: retry::Outcome) {
+    console_log!(
+        "{} - {} kv retries: {}, outcome: {}",
+        Date::now().to_string(),
+        request_id,
+        outcome.retries,
+        if outcome.exhausted {
+            "gave up"
+        } else {
+            "succeeded"
+        }
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(chunks: &[&str]) -> impl Stream<Item = Result<Vec<u8>>> {
+        let chunks: Vec<Result<Vec<u8>>> =
+            chunks.iter().map(|c| Ok(c.as_bytes().to_vec())).collect();
+        futures::stream::iter(chunks)
+    }
+
+    fn collect(stream: impl Stream<Item = Result<Vec<u8>>>) -> Vec<Result<Vec<u8>>> {
+        futures::executor::block_on(stream.collect())
+    }
+
+    fn read(
+        stream: impl Stream<Item = Result<Vec<u8>>> + Unpin,
+        length: Option<usize>,
+    ) -> Result<Body> {
+        futures::executor::block_on(read_chunks(Some(stream), length, 10))
+    }
+
+    fn too_large(result: Result<Body>) -> bool {
+        matches!(result, Err(Error::Json((_, 413))))
+    }
+
+    #[test]
+    fn bodies_within_the_limit_are_read_and_hashed() {
+        let body = read(chunks(&["hello", "world"]), None).unwrap();
+        assert_eq!(body.bytes, b"helloworld");
+        assert_eq!(body.checksum, sha256_hex(b"helloworld"));
+    }
+
+    #[test]
+    fn bodies_over_the_limit_are_rejected_as_they_are_read() {
+        assert!(too_large(read(chunks(&["hello", "world", "!"]), None)));
+    }
+
+    #[test]
+    fn bodies_said_to_be_over_the_limit_are_rejected_without_reading_them() {
+        let unread = futures::stream::poll_fn(|_| -> std::task::Poll<Option<Result<Vec<u8>>>> {
+            panic!("the body shouldn't be read")
+        });
+        assert!(too_large(read(unread, Some(11))));
+    }
+
+    #[test]
+    fn requests_without_a_body_are_empty() {
+        let body = futures::executor::block_on(read_chunks(
+            None::<futures::stream::Empty<Result<Vec<u8>>>>,
+            None,
+            10,
+        ))
+        .unwrap();
+        assert!(body.bytes.is_empty());
+        assert_eq!(body.checksum, sha256_hex(b""));
+    }
+
+    #[test]
+    fn streams_matching_their_checksum_are_passed_on() {
+        let checksum = sha256_hex(b"hello world");
+        let stream = verify_checksum(chunks(&["hello", " ", "world"]), checksum);
+        let chunks: Vec<Vec<u8>> = collect(stream).into_iter().map(|c| c.unwrap()).collect();
+        assert_eq!(chunks.concat(), b"hello world");
+    }
+
+    #[test]
+    fn streams_not_matching_their_checksum_end_with_an_error() {
+        let checksum = sha256_hex(b"hello world");
+        let results = collect(verify_checksum(chunks(&["hello", " ", "there"]), checksum));
+
+        // The last chunk is swapped for the error, so the changed value never gets through whole.
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), b"hello");
+        assert_eq!(results[1].as_ref().unwrap(), b" ");
+        assert!(results[2].is_err());
+    }
+
+    /// A few MB of bytes that don't repeat too neatly, split into chunks the way a body is streamed.
+    fn large_value() -> (Vec<u8>, Vec<Result<Vec<u8>>>) {
+        let value: Vec<u8> = (0..3 * 1024 * 1024 + 7)
+            .map(|i: u32| (i.wrapping_mul(31) ^ (i >> 8)) as u8)
+            .collect();
+        let chunks = value
+            .chunks(64 * 1024 + 3)
+            .map(|c| Ok(c.to_vec()))
+            .collect();
+        (value, chunks)
+    }
+
+    #[test]
+    fn large_streams_round_trip_through_the_checksum() {
+        let (value, chunks) = large_value();
+        let count = chunks.len();
+        let stream = verify_checksum(futures::stream::iter(chunks), sha256_hex(&value));
+
+        let results = collect(stream);
+        assert_eq!(results.len(), count);
+        let streamed: Vec<Vec<u8>> = results.into_iter().map(|c| c.unwrap()).collect();
+        assert_eq!(streamed.concat(), value);
+    }
+
+    #[test]
+    fn large_streams_changed_anywhere_fail_the_checksum() {
+        let (value, mut chunks) = large_value();
+        if let Ok(chunk) = &mut chunks[10] {
+            chunk[100] ^= 1;
+        }
+        let results = collect(verify_checksum(
+            futures::stream::iter(chunks),
+            sha256_hex(&value),
+        ));
+        assert!(results.last().unwrap().is_err());
+        assert!(results[..results.len() - 1].iter().all(Result::is_ok));
+    }
+
+    #[test]
+    fn empty_streams_are_checked_too() {
+        assert!(collect(verify_checksum(chunks(&[]), sha256_hex(b""))).is_empty());
+
+        let results = collect(verify_checksum(chunks(&[]), sha256_hex(b"hello")));
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+
+    const KEY: [u8; 32] = [7; 32];
+
+    #[test]
+    fn unavailable_errors_tell_clients_when_to_retry() {
+        let headers = error_headers(&ApiError::Unavailable("too many requests".into()));
+        assert!(headers.contains(&("retry-after", "5".into())));
+        assert!(headers.contains(&("x-error-code", "store_unavailable".into())));
+    }
+
+    #[test]
+    fn other_errors_dont_ask_to_be_retried() {
+        let headers = error_headers(&ApiError::KeyNotFound);
+        assert!(headers.iter().all(|(name, _)| *name != "retry-after"));
+        assert!(headers.contains(&("vary", "accept".into())));
+        assert!(headers.contains(&("x-error-code", "key_not_found".into())));
+    }
+
+    #[test]
+    fn plain_csv_fields_are_left_alone() {
+        assert!(matches!(csv_field("notes/a"), Cow::Borrowed("notes/a")));
+        assert_eq!(csv_field(""), "");
+    }
+
+    #[test]
+    fn csv_fields_that_would_break_the_row_are_quoted() {
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("line\nbreak"), "\"line\nbreak\"");
+        assert_eq!(csv_field("line\r\nbreak"), "\"line\r\nbreak\"");
+    }
+
+    #[test]
+    fn if_match_compares_entity_tags() {
+        assert!(if_match("\"abc\"", "abc"));
+        assert!(if_match("W/\"ABC\"", "abc"));
+        assert!(if_match("\"xyz\", \"abc\"", "abc"));
+        assert!(if_match("*", "abc"));
+        assert!(!if_match("\"xyz\"", "abc"));
+        assert!(!if_match("\"\"", ""));
+    }
+
+    #[test]
+    fn keys_round_trip_through_their_namespace() {
+        let scoped = scope_key("prod/", "users/1");
+        assert_eq!(scoped, "prod/users/1");
+        assert_eq!(unscope_key("prod/", &scoped), "users/1");
+    }
+
+    #[test]
+    fn keys_are_untouched_without_a_namespace() {
+        assert_eq!(scope_key("", "users/1"), "users/1");
+        assert_eq!(unscope_key("", "users/1"), "users/1");
+        // A key that isn't in the namespace is left as it is rather than mangled.
+        assert_eq!(unscope_key("prod/", "dev/users/1"), "dev/users/1");
+    }
+
+    #[test]
+    fn requests_are_in_flight_until_their_guard_is_dropped() {
+        let first = track_request("first");
+        let second = track_request("second");
+        assert_eq!(requests_in_flight().unwrap(), ["first", "second"]);
+
+        drop(first);
+        assert_eq!(requests_in_flight().unwrap(), ["second"]);
+        drop(second);
+        assert!(requests_in_flight().unwrap().is_empty());
+    }
+
+    fn url(query: &str) -> Url {
+        Url::parse(&format!("https://example.com/list?{}", query)).unwrap()
+    }
+
+    #[test]
+    fn repeated_params_are_collected_in_order() {
+        let url = url("tag=a&prefix=x&tag=b&tag=c%2Fd");
+        assert_eq!(params_from(&url, "tag"), ["a", "b", "c/d"]);
+        assert_eq!(params_from(&url, "prefix"), ["x"]);
+    }
+
+    #[test]
+    fn a_single_param_is_collected_on_its_own() {
+        let url = url("tag=a");
+        assert_eq!(params_from(&url, "tag"), ["a"]);
+        assert_eq!(param_from(&url, "tag").as_deref(), Some("a"));
+        assert!(params_from(&url, "missing").is_empty());
+    }
+
+    #[test]
+    fn keys_round_trip_through_percent_encoding() {
+        for key in [
+            "notes",
+            "my notes",
+            "a/b/c",
+            "a%2Fb",
+            "café/日本",
+            "a+b",
+            "100%",
+        ] {
+            let encoded = percent_encode(key);
+            assert!(!encoded.contains('/'), "{}", encoded);
+            assert_eq!(percent_decode(&encoded).unwrap(), key);
+        }
+    }
+
+    #[test]
+    fn percent_decoding_leaves_plain_characters_alone() {
+        assert_eq!(percent_decode("my%20notes").unwrap(), "my notes");
+        assert_eq!(percent_decode("a%2Fb").unwrap(), "a/b");
+        assert_eq!(percent_decode("a/b").unwrap(), "a/b");
+        assert_eq!(percent_decode("a+b").unwrap(), "a+b");
+        assert_eq!(percent_decode("caf%C3%A9").unwrap(), "café");
+    }
+
+    #[test]
+    fn keys_that_arent_utf8_are_rejected() {
+        assert!(matches!(percent_decode("%FF"), Err(Error::Json((_, 400)))));
+    }
+
+    #[test]
+    fn common_formats_are_recognized_by_their_leading_bytes() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", "image/png"),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", "image/jpeg"),
+            (b"GIF87a\x01\0", "image/gif"),
+            (b"GIF89a\x01\0", "image/gif"),
+            (b"%PDF-1.7\n", "application/pdf"),
+            (b"\x1f\x8b\x08\0", "application/gzip"),
+            (b" {\"a\": [1, 2]}\n", "application/json"),
+            (b"[1, 2, 3]", "application/json"),
+            (b"hello world\n", "text/plain; charset=utf-8"),
+            ("café\tnotes\r\n".as_bytes(), "text/plain; charset=utf-8"),
+        ];
+        for (bytes, content_type) in cases {
+            assert_eq!(sniff_content_type(bytes).as_deref(), Some(*content_type));
+        }
+    }
+
+    #[test]
+    fn json_that_could_be_text_is_text() {
+        assert_eq!(
+            sniff_content_type(b"42").as_deref(),
+            Some("text/plain; charset=utf-8")
+        );
+        assert_eq!(
+            sniff_content_type(b"{not json").as_deref(),
+            Some("text/plain; charset=utf-8")
+        );
+    }
+
+    #[test]
+    fn unknown_binary_isnt_guessed() {
+        assert_eq!(sniff_content_type(&[0, 1, 2, 3]), None);
+        assert_eq!(sniff_content_type(&[0xfe, 0xed]), None);
+        assert_eq!(sniff_content_type(b""), None);
+    }
+
+    #[test]
+    fn encrypted_values_round_trip() {
+        let (ciphertext, nonce) = encrypt(&KEY, b"hello world").unwrap();
+        assert_ne!(ciphertext, b"hello world");
+        assert_eq!(decrypt(&KEY, &nonce, &ciphertext).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn every_value_gets_its_own_nonce() {
+        let (first, first_nonce) = encrypt(&KEY, b"hello world").unwrap();
+        let (second, second_nonce) = encrypt(&KEY, b"hello world").unwrap();
+        assert_ne!(first_nonce, second_nonce);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn decrypting_with_the_wrong_key_fails() {
+        let (ciphertext, nonce) = encrypt(&KEY, b"hello world").unwrap();
+        assert!(decrypt(&[8; 32], &nonce, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn tampered_values_fail_to_decrypt() {
+        let (mut ciphertext, nonce) = encrypt(&KEY, b"hello world").unwrap();
+        ciphertext[0] ^= 1;
+        assert!(decrypt(&KEY, &nonce, &ciphertext).is_err());
+        assert!(decrypt(&KEY, "not a nonce", &ciphertext).is_err());
+    }
+
+    #[test]
+    fn encryption_keys_must_be_32_bytes() {
+        assert_eq!(parse_encryption_key(&base64::encode(KEY)).unwrap(), KEY);
+        assert!(parse_encryption_key(&base64::encode([7; 16])).is_err());
+        assert!(parse_encryption_key("not base64!").is_err());
+    }
+
+    const FORM: &[u8] = b"--boundary\r\n\
+Content-Disposition: form-data; name=\"title\"\r\n\
+\r\n\
+holiday\r\n\
+--boundary\r\n\
+Content-Disposition: form-data; name=\"file\"; filename=\"beach.png\"\r\n\
+Content-Type: image/png\r\n\
+\r\n\
+\x89PNG\r\n--not-the-boundary\r\n\
+--boundary--\r\n";
+
+    #[test]
+    fn multipart_bodies_are_split_into_parts() {
+        let parts = parse_multipart(FORM, "boundary").unwrap();
+        assert_eq!(parts.len(), 2);
+
+        assert_eq!(parts[0].name.as_deref(), Some("title"));
+        assert_eq!(parts[0].filename, None);
+        assert_eq!(parts[0].body, b"holiday");
+
+        assert_eq!(parts[1].name.as_deref(), Some("file"));
+        assert_eq!(parts[1].filename.as_deref(), Some("beach.png"));
+        assert_eq!(parts[1].content_type.as_deref(), Some("image/png"));
+        assert_eq!(parts[1].body, b"\x89PNG\r\n--not-the-boundary");
+    }
+
+    #[test]
+    fn malformed_multipart_bodies_are_rejected() {
+        assert!(parse_multipart(FORM, "other").is_err());
+        assert!(parse_multipart(b"--boundary\r\nno blank line", "boundary").is_err());
+    }
+}